@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gen_rtp::error::Error;
+use gen_rtp::mapping::Mapping;
+use gen_rtp::output::{Output, Overwrite};
+use gen_rtp::utils;
+
+pub enum ApplyError {
+    /// Invalid command line.
+    Usage(String),
+    Failed(Error),
+}
+
+impl From<Error> for ApplyError {
+    fn from(e: Error) -> Self {
+        ApplyError::Failed(e)
+    }
+}
+
+// gen-rtp apply-map [-o DIR] [--resname RES] [--resid N] [--dry-run] [--overwrite MODE] <MAP> <FILE>...
+pub fn run(args: &[String]) -> Result<(), ApplyError> {
+    let mut out_dir: Option<String> = None;
+    let mut overwrite = Overwrite::default();
+    let mut dry_run = false;
+    let mut resname: Option<String> = None;
+    let mut resid: Option<i32> = None;
+    let mut files: Vec<String> = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        let (opt, inline) = match args[i].split_once('=') {
+            Some((o, v)) if args[i].starts_with("--") => (o.to_string(), Some(v.to_string())),
+            _ => (args[i].to_string(), None),
+        };
+        let mut value = || -> Result<String, ApplyError> {
+            if let Some(v) = &inline {
+                return Ok(v.to_string());
+            }
+            i += 1;
+            args.get(i).cloned().ok_or(ApplyError::Usage(format!("Option `{}` requires a value.", opt)))
+        };
+        match opt.as_str() {
+            "-o" | "--out-dir" => out_dir = Some(value()?),
+            "--overwrite" => overwrite = value()?.parse().map_err(ApplyError::Usage)?,
+            "--resname" => resname = Some(value()?),
+            "--resid" => {
+                let v = value()?;
+                resid = Some(v.parse().map_err(|_| ApplyError::Usage(format!("Invalid value `{}` for option `--resid`.", v)))?);
+            },
+            "--dry-run" => dry_run = true,
+            s if s.starts_with('-') && s.len() > 1 => return Err(ApplyError::Usage(format!("Unknown option `{}`.", s))),
+            _ => files.push(args[i].to_string()),
+        }
+        i += 1;
+    }
+    if files.len() < 2 {
+        return Err(ApplyError::Usage("`apply-map` requires a mapping file and at least one file to rename.".to_string()));
+    }
+
+    let mut mapping = Mapping::from(&files[0])?;
+    println!("Read {} atom names from {}", mapping.atoms.len(), files[0]);
+    if resname.is_some() {
+        mapping.resname = resname;
+    }
+    match &mapping.resname {
+        Some(r) => println!("Renaming the atoms of residue {}{}", r, resid.map_or(String::new(), |id| format!(" {}", id))),
+        None => println!("Warning: the mapping has no residue name, atoms of all residues{} are renamed, see --resname",
+            resid.map_or(String::new(), |id| format!(" numbered {}", id))),
+    }
+    let extension = |file: &str| Path::new(file).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    // ndx 中的组按同时给出的 PDB 判断是否属于该残基
+    let pdb = files[1..].iter().find(|f| matches!(extension(f).as_str(), "pdb" | "ent"));
+    let pdb_atoms = match pdb {
+        Some(pdb) => Some(mapping.pdb_atoms(&fs::read_to_string(pdb).map_err(|e| Error::io(pdb, e))?, resid)),
+        None => None,
+    };
+    let mut output = Output::new(overwrite, dry_run);
+    for file in &files {
+        output.protect(file);
+    }
+    for file in &files[1..] {
+        let ext = extension(file);
+        let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let renamed = match ext.as_str() {
+            "pdb" | "ent" => mapping.apply_pdb(&content, resid),
+            "ndx" => {
+                if pdb_atoms.is_none() {
+                    println!("Warning: no PDB file given to tell the atoms of the residue, the groups of {} are renamed by name only", file);
+                }
+                mapping.apply_ndx(&content, pdb_atoms.as_deref())
+            },
+            "itp" | "top" => mapping.apply_itp(&content),
+            _ => return Err(Error::Invalid(format!("{}: unknown file type, only support pdb, ndx and itp", file)).into()),
+        };
+        // 输出为 <stem>_renamed.<ext>
+        let dir = match &out_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => utils::get_parent_path(file),
+        };
+        let out = dir.join(format!("{}_renamed.{}", utils::get_stemname(file), ext));
+        let out = out.to_str().unwrap();
+        output.write(out, &renamed)?;
+        if !dry_run {
+            println!("Finished renaming {} to {}", file, out);
+        }
+    }
+    Ok(())
+}
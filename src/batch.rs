@@ -0,0 +1,328 @@
+use std::fs;
+use std::path::Path;
+
+use gen_rtp::bonded::Ffbonded;
+use gen_rtp::error::{Error, Location};
+use gen_rtp::hdb::Hdb;
+use gen_rtp::install::Package;
+use gen_rtp::nonbonded::{conflicts, Nonbonded};
+use gen_rtp::output::{Output, Overwrite};
+use gen_rtp::rtp::Rtp;
+use gen_rtp::specbond::Specbond;
+use gen_rtp::utils;
+
+use crate::cli::Args;
+use crate::convert::{convert, Converted};
+
+// 任务文件, TOML 子集:
+//   ff = "amber"                # 所有残基共用的力场, 决定唯一的 [ bondedtypes ]
+//   rtp = "residues.rtp"        # 合并输出, 默认与任务文件同名
+//   hdb = "residues.hdb"
+//   specbond = "residues_specbond.dat"   # 有 link 时写出
+//   ffnonbonded = "residues_ffnonbonded.itp"   # 合并的 atomtypes
+//   atp = "residues_atomtypes.atp"
+//   target-ff = "amber99sb.ff"  # 可选, 所有残基的 atomtypes 按其 [ defaults ] 检查与转换
+//   bonded-types = true         # 可选, 键参数移入合并的 ffbonded 类型表
+//   ffbonded = "residues_ffbonded.itp"
+//   install = "amber99sb.ff"    # 可选, 合并结果安装到力场目录, 清单为 gen-rtp_<任务名>.manifest
+//   install-rtp = "aminoacids"
+//   [[residue]]
+//   mol2 = "TYS.mol2"           # 其余键与命令行长选项同名, 如 itp, prev, next-adj, naming
+//   prev = "1-6"
+pub struct Job {
+    pub ff: String,
+    pub rtp: String,
+    pub hdb: String,
+    pub specbond: String,
+    pub nonbonded: String,
+    pub atp: String,
+    pub target_ff: Option<String>,
+    pub bonded_types: bool,
+    pub ffbonded: String,
+    pub install: Option<String>,
+    pub install_rtp: String,
+    pub residues: Vec<Vec<String>>,
+}
+
+impl Job {
+    pub fn from(file: &str) -> Result<Job, Error> {
+        let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let job_dir = utils::get_parent_path(file);
+        let stem = utils::get_stemname(file);
+        let mut job = Job {
+            ff: "amber".to_string(),
+            rtp: job_dir.join(stem.to_string() + ".rtp").to_str().unwrap().to_string(),
+            hdb: job_dir.join(stem.to_string() + ".hdb").to_str().unwrap().to_string(),
+            specbond: job_dir.join(stem.to_string() + "_specbond.dat").to_str().unwrap().to_string(),
+            nonbonded: job_dir.join(stem.to_string() + "_ffnonbonded.itp").to_str().unwrap().to_string(),
+            atp: job_dir.join(stem.to_string() + "_atomtypes.atp").to_str().unwrap().to_string(),
+            target_ff: None,
+            bonded_types: false,
+            ffbonded: job_dir.join(stem.to_string() + "_ffbonded.itp").to_str().unwrap().to_string(),
+            install: None,
+            install_rtp: "aminoacids".to_string(),
+            residues: vec![],
+        };
+        for (ln, line) in content.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            let section = match job.residues.is_empty() {
+                true => "job header",
+                false => "[[residue]]",
+            };
+            let loc = Location { file, line: ln + 1, section };
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[residue]]" {
+                job.residues.push(vec![]);
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| loc.error(line, "expected `key = value`"))?;
+            let key = key.trim().replace('_', "-");
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(v) => v.to_string(),
+                None => value.to_string(),
+            };
+            // 相对路径以任务文件所在目录为准
+            let value = match key.as_str() {
+                "mol2" | "itp" | "include" | "rtp" | "hdb" | "specbond" | "ffnonbonded" | "atp" | "ffbonded" | "target-ff" | "install" if Path::new(&value).is_relative() => {
+                    job_dir.join(&value).to_str().unwrap().to_string()
+                },
+                _ => value,
+            };
+            match job.residues.last_mut() {
+                None => match key.as_str() {
+                    "ff" => job.ff = value,
+                    "rtp" => job.rtp = value,
+                    "hdb" => job.hdb = value,
+                    "specbond" => job.specbond = value,
+                    "ffnonbonded" => job.nonbonded = value,
+                    "atp" => job.atp = value,
+                    "target-ff" => job.target_ff = Some(value),
+                    "bonded-types" => job.bonded_types = match value.as_str() {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(loc.error(&value, "expected true or false")),
+                    },
+                    "ffbonded" => job.ffbonded = value,
+                    "install" => job.install = Some(value),
+                    "install-rtp" => job.install_rtp = value,
+                    _ => return Err(loc.error(&key, "unknown job key")),
+                },
+                Some(residue) => match (key.as_str(), value.as_str()) {
+                    ("rename", "true") => residue.push("--rename".to_string()),
+                    ("rename", "false") => residue.push("--no-rename".to_string()),
+                    ("detect", "true") => residue.push("--detect=amide".to_string()),
+                    ("detect", "false") => residue.push("--no-detect".to_string()),
+                    _ => residue.push(format!("--{}={}", key, value)),
+                },
+            }
+        }
+        if job.ff != "amber" && job.ff != "gromos" {
+            return Err(Error::Invalid(format!("invalid forcefield `{}` in {}, only support amber and gromos.", job.ff, file)));
+        }
+        Ok(job)
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+// 转换一个残基, 连同其选项返回, 改名后的 mol2 和名字映射在通过合并检查后写出
+fn run_residue(job: &Job, options: &[String], known_bonded: &Ffbonded, output: &Output) -> Result<(Converted, Args), Error> {
+    let mut argv = vec!["--non-interactive".to_string(), format!("--overwrite={}", output.overwrite)];
+    if output.dry_run {
+        argv.push("--dry-run".to_string());
+    }
+    argv.extend(options.iter().cloned());
+    let mut args = Args::parse(&argv).map_err(Error::Invalid)?;
+    match &args.ff {
+        Some(ff) if ff != &job.ff => {
+            return Err(Error::Invalid(format!("forcefield `{}` differs from `{}` of the merged rtp", ff, job.ff)));
+        },
+        _ => args.ff = Some(job.ff.to_string()),
+    }
+    if args.target_ff.is_none() {
+        args.target_ff = job.target_ff.clone().or(job.install.clone());
+    }
+    args.bonded_types |= job.bonded_types;
+    args.known_bonded = known_bonded.clone();
+    let mol2_file = args.mol2.clone().ok_or_else(|| Error::Invalid("no `mol2` file given".to_string()))?;
+    let converted = convert(&args, &mol2_file)?;
+    Ok((converted, args))
+}
+
+// gen-rtp batch [--dry-run] [--overwrite MODE] <JOB>, 其余选项写在任务文件中
+pub fn parse_args(args: &[String]) -> Result<(String, Overwrite, bool), String> {
+    let mut overwrite = Overwrite::default();
+    let mut dry_run = false;
+    let mut files: Vec<String> = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        let (opt, inline) = match args[i].split_once('=') {
+            Some((o, v)) if args[i].starts_with("--") => (o.to_string(), Some(v.to_string())),
+            _ => (args[i].to_string(), None),
+        };
+        match opt.as_str() {
+            "--overwrite" => {
+                let value = match inline {
+                    Some(v) => v,
+                    None => {
+                        i += 1;
+                        args.get(i).cloned().ok_or("Option `--overwrite` requires a value.")?
+                    },
+                };
+                overwrite = value.parse()?;
+            },
+            "--dry-run" => dry_run = true,
+            s if s.starts_with('-') && s.len() > 1 =>
+                return Err(format!("Option `{}` is not accepted by `batch`, give it per residue in the job file.", s)),
+            _ => files.push(args[i].to_string()),
+        }
+        i += 1;
+    }
+    match <[String; 1]>::try_from(files) {
+        Ok([job_file]) => Ok((job_file, overwrite, dry_run)),
+        Err(_) => Err("`batch` requires exactly one job file.".to_string()),
+    }
+}
+
+// 依次处理任务文件中的所有残基, 成功的合并写入同一个 rtp 和 hdb, 返回是否全部成功
+pub fn run(job_file: &str, overwrite: Overwrite, dry_run: bool) -> Result<bool, Error> {
+    let job = Job::from(job_file)?;
+    let mut output = Output::new(overwrite, dry_run);
+    println!("Processing {} residues from {}", job.residues.len(), job_file);
+
+    let mut results = vec![];
+    // 前面残基的键型参数作为已知, 参数不同的项保留在 rtp 中
+    let mut bonded: Option<Ffbonded> = None;
+    for (i, options) in job.residues.iter().enumerate() {
+        println!("\n===== Residue {} of {} =====", i + 1, job.residues.len());
+        let mut result = run_residue(&job, options, bonded.as_ref().unwrap_or(&Ffbonded::default()), &output);
+        if let Ok((converted, _)) = &result {
+            let name = &converted.residue.rtp.name;
+            let seen = results.iter().any(|(_, r): &(usize, Result<(Converted, Args), Error>)| {
+                matches!(r, Ok((r, _)) if &r.residue.rtp.name == name)
+            });
+            if seen {
+                result = Err(Error::Invalid(format!("residue `{}` is defined more than once", name)));
+            }
+        }
+        // 合并的 ffnonbonded.itp 中同名的 atomtype 只能有一组参数
+        if let Ok((converted, _)) = &result {
+            let earlier: Vec<&Converted> = results.iter().filter_map(|(_, r)| r.as_ref().ok().map(|(c, _)| c)).collect();
+            let clashes: Vec<String> = earlier.iter().flat_map(|c| {
+                conflicts(&converted.residue.topol.atomtypes, &c.residue.topol.atomtypes).into_iter()
+                    .map(|(at, known)| format!("{} (sigma {}, epsilon {}) differs from {} of {} (sigma {}, epsilon {})",
+                        at.name, at.sigma, at.epsilon, known.name, c.residue.rtp.name, known.sigma, known.epsilon))
+            }).collect();
+            if !clashes.is_empty() {
+                result = Err(Error::Invalid(format!("atomtypes clash with those of earlier residues: {}; rename them in the itp",
+                    clashes.join(", "))));
+            }
+        }
+        if let Some(b) = result.as_ref().ok().and_then(|(c, _)| c.residue.bonded.clone()) {
+            bonded.get_or_insert_with(Ffbonded::default).merge(b);
+        }
+        results.push((i, result));
+    }
+
+    // 合并输出
+    let done: Vec<&Converted> = results.iter().filter_map(|(_, r)| r.as_ref().ok().map(|(c, _)| c)).collect();
+    if !done.is_empty() {
+        for c in &done {
+            output.protect(&c.mol2_file);
+            output.protect(&c.itp_file);
+        }
+        for (converted, args) in results.iter().filter_map(|(_, r)| r.as_ref().ok()) {
+            let output = converted.output(args);
+            converted.write_mol2(args, &output)?;
+            converted.write_mapping(args, &output)?;
+        }
+        let rtp = Rtp::new(&job.ff, done.iter().flat_map(|c| c.residue.rtps()).collect());
+        output.write(&job.rtp, &rtp.to_string())?;
+        // 各残基的 atomtypes 已按目标力场转换, 取第一个残基的 [ defaults ]
+        let defaults = done.first().and_then(|c| c.residue.topol.defaults.clone());
+        let nonbonded = Nonbonded::new(done.iter().flat_map(|c| c.residue.topol.new_atomtypes().cloned()), defaults)?;
+        output.write(&job.nonbonded, &nonbonded.to_string())?;
+        output.write(&job.atp, &nonbonded.atp().to_string())?;
+        if let Some(bonded) = &bonded {
+            output.write(&job.ffbonded, &bonded.to_string())?;
+        }
+        let hdb = Hdb { residues: done.iter().flat_map(|c| c.residue.hdbs()).collect() };
+        output.write(&job.hdb, &hdb.to_string())?;
+        let specbond = Specbond { bonds: done.iter().flat_map(|c| c.residue.specbonds.iter().cloned()).collect() };
+        if !specbond.bonds.is_empty() {
+            output.write(&job.specbond, &specbond.to_string())?;
+        }
+        if !output.dry_run {
+            println!("\nFinished writing rtp file to {}", job.rtp);
+            println!("Finished writing hdb file to {}", job.hdb);
+            println!("Finished writing atomtypes to {} and {}", job.nonbonded, job.atp);
+            if bonded.is_some() {
+                println!("Finished writing bonded types to {}", job.ffbonded);
+            }
+            if !specbond.bonds.is_empty() {
+                println!("Finished writing specbond.dat entries to {}", job.specbond);
+            }
+        }
+        if let Some(dir) = &job.install {
+            let package = Package {
+                ff: job.ff.to_string(),
+                rtps: rtp.residues,
+                hdbs: hdb.residues,
+                nonbonded,
+                bonded,
+                restypes: done.iter()
+                    .flat_map(|c| c.residue.rtps().into_iter().map(|r| (r.name, c.options.kind.residue_type().to_string())))
+                    .collect(),
+            };
+            let manifest = Path::new(dir).join(format!("gen-rtp_{}.manifest", utils::get_stemname(job_file)));
+            println!();
+            package.install(dir, &job.install_rtp)?.write(&output, manifest.to_str().unwrap())?;
+        }
+    }
+
+    // 逐个报告
+    println!("\nSummary:");
+    for (i, result) in &results {
+        let mol2 = job.residues[*i].iter()
+            .find_map(|o| o.strip_prefix("--mol2="))
+            .unwrap_or("?");
+        match result {
+            Ok((c, _)) => println!("  [ OK ] #{} {} ({})", i + 1, c.residue.rtp.name, mol2),
+            Err(e) => println!("  [FAIL] #{} {}: {}", i + 1, mol2, e),
+        }
+    }
+    println!("{} of {} residues succeeded.", done.len(), results.len());
+    Ok(done.len() == results.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn batch_accepts_only_output_options() {
+        let (job, overwrite, dry_run) = parse_args(&args("--overwrite force job.toml --dry-run")).unwrap();
+        assert_eq!((job.as_str(), overwrite, dry_run), ("job.toml", Overwrite::Force, true));
+        assert!(parse_args(&args("job.toml --ff gromos")).is_err());
+        assert!(parse_args(&args("--naming=amino job.toml")).is_err());
+        assert!(parse_args(&args("a.toml b.toml")).is_err());
+    }
+}
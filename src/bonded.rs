@@ -0,0 +1,234 @@
+use std::fmt::{self, Display};
+
+use regex::Regex;
+
+use crate::error::{Location, Result};
+use crate::itp::forcefield_file;
+use crate::preprocess::{Preprocessor, SourceLine};
+
+/// A line of `[ bondtypes ]`, `[ angletypes ]` or `[ dihedraltypes ]`: the atomtypes (`X` matches any
+/// type in the dihedrals of a force field), the function type and its parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BondedType {
+    pub types: Vec<String>,
+    pub funct: i32,
+    pub params: Vec<f64>,
+}
+
+/// Bonded type tables as in `ffbonded.itp`.
+#[derive(Clone, Debug, Default)]
+pub struct Ffbonded {
+    pub bondtypes: Vec<BondedType>,
+    pub angletypes: Vec<BondedType>,
+    /// Proper dihedrals.
+    pub dihedraltypes: Vec<BondedType>,
+    /// Improper dihedrals, function types 2 and 4.
+    pub impropertypes: Vec<BondedType>,
+}
+
+// 1 与 9 同为周期性二面角, 查找参数时互通
+pub(crate) fn class(funct: i32) -> i32 {
+    match funct {
+        9 => 1,
+        f => f,
+    }
+}
+
+impl BondedType {
+    // 正向或反向匹配 types 时通配符 X 的个数, 不匹配为 None
+    fn wildcards(&self, types: &[&str]) -> Option<usize> {
+        if self.types.len() != types.len() {
+            return None;
+        }
+        let matches = |order: &mut dyn Iterator<Item = &str>| {
+            self.types.iter().zip(order).all(|(t, q)| t == "X" || t == q)
+        };
+        match matches(&mut types.iter().copied()) || matches(&mut types.iter().rev().copied()) {
+            true => Some(self.types.iter().filter(|t| *t == "X").count()),
+            false => None,
+        }
+    }
+
+    /// The table line, with the parameters formatted like those of the rtp.
+    pub fn line(&self) -> String {
+        let types: String = self.types.iter().map(|t| format!(" {:>7}", t)).collect();
+        format!("{}{:>6}{}", types, self.funct, self.params_text())
+    }
+
+    /// The parameters as written after the atom names of an rtp line.
+    pub fn params_text(&self) -> String {
+        let mut out = String::new();
+        let formats: Vec<fn(f64) -> String> = match self.types.len() {
+            2 => vec![|p| format!("{:13.6}", p), |p| format!("{:13.6e}", p)],
+            _ => vec![|p| format!("{:10.2}", p), |p| format!("{:9.2}", p), |p| format!("{:8}", p)],
+        };
+        for (i, p) in self.params.iter().enumerate() {
+            out.push_str(&formats.get(i).map_or_else(|| format!("{:13.6}", p), |f| f(*p)));
+        }
+        out
+    }
+}
+
+/// Whether the parameter lines `a` and `b` agree to 0.1%.
+pub fn same_params(a: &[&BondedType], b: &[&[f64]]) -> bool {
+    let same = |x: f64, y: f64| (x - y).abs() <= 1e-3 * x.abs().max(y.abs());
+    a.len() == b.len() && a.iter().zip(b).all(|(t, p)| {
+        t.params.len() == p.len() && t.params.iter().zip(p.iter()).all(|(x, y)| same(*x, *y))
+    })
+}
+
+/// The entries of `table` giving the parameters of a term of function type `funct` between atoms of
+/// `types`, like grompp: the most specific match, forward or backward. Several entries are the terms
+/// of a multiple dihedral.
+pub fn find<'a>(table: &'a [BondedType], types: &[&str], funct: i32) -> Vec<&'a BondedType> {
+    let candidates: Vec<(&BondedType, usize)> = table.iter()
+        .filter(|t| class(t.funct) == class(funct))
+        .filter_map(|t| t.wildcards(types).map(|n| (t, n)))
+        .collect();
+    let best = match candidates.iter().map(|(_, n)| *n).min() {
+        Some(best) => best,
+        None => return vec![],
+    };
+    let first = candidates.iter().find(|(_, n)| *n == best).unwrap().0;
+    candidates.iter().filter(|(t, _)| t.types == first.types).map(|(t, _)| *t).collect()
+}
+
+impl Ffbonded {
+    /// Reads the bonded type sections of a file, or of a whole force field from its `.ff` directory or
+    /// `forcefield.itp` with the files it `#include`s.
+    pub fn from(path: &str) -> Result<Ffbonded> {
+        let mut ffbonded = Ffbonded::default();
+        ffbonded.read(&Preprocessor::default().process(&forcefield_file(path))?)?;
+        Ok(ffbonded)
+    }
+
+    /// Reads the `[ bondtypes ]`, `[ angletypes ]` and `[ dihedraltypes ]` lines of `lines`.
+    pub fn read(&mut self, lines: &[SourceLine]) -> Result<()> {
+        let re = Regex::new(r"^\[\s*(\w+)\s*\]").unwrap();
+        let mut section = String::new();
+        for l in lines {
+            let text = l.text.split(';').next().unwrap().trim();
+            if let Some(caps) = re.captures(text) {
+                section = caps[1].to_string();
+                continue;
+            }
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = text.split_whitespace().collect();
+            // 旧格式的二面角只给出两个原子类型: proper 为中间两个, improper 为两端
+            let n = match section.as_str() {
+                "bondtypes" => 2,
+                "angletypes" => 3,
+                "dihedraltypes" if fields.get(2).is_some_and(|f| f.parse::<i32>().is_ok()) => 2,
+                "dihedraltypes" => 4,
+                _ => continue,
+            };
+            let label = format!("[ {} ]", section);
+            let loc = Location { file: &l.file, line: l.line, section: &label };
+            let funct: i32 = loc.field(&fields, n, "funct")?;
+            let mut types: Vec<String> = fields[..n].iter().map(|t| t.to_string()).collect();
+            let improper = funct == 2 || funct == 4;
+            if section == "dihedraltypes" && n == 2 {
+                types = match improper {
+                    true => vec![types[0].to_string(), "X".to_string(), "X".to_string(), types[1].to_string()],
+                    false => vec!["X".to_string(), types[0].to_string(), types[1].to_string(), "X".to_string()],
+                };
+            }
+            // 未展开的宏等无法解析的参数只保留类型
+            let params = fields[n + 1..].iter().map(|p| p.parse().ok()).collect::<Option<Vec<f64>>>().unwrap_or_default();
+            let entry = BondedType { types, funct, params };
+            match section.as_str() {
+                "bondtypes" => self.bondtypes.push(entry),
+                "angletypes" => self.angletypes.push(entry),
+                _ if improper => self.impropertypes.push(entry),
+                _ => self.dihedraltypes.push(entry),
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of entries in all tables.
+    pub fn len(&self) -> usize {
+        self.bondtypes.len() + self.angletypes.len() + self.dihedraltypes.len() + self.impropertypes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds the entries of `other` whose types are not in the tables yet, warning about those tabulated
+    /// with other parameters.
+    pub fn merge(&mut self, other: Ffbonded) {
+        for (table, entries) in [(&mut self.bondtypes, other.bondtypes), (&mut self.angletypes, other.angletypes),
+                                 (&mut self.dihedraltypes, other.dihedraltypes), (&mut self.impropertypes, other.impropertypes)] {
+            let mut added: Vec<BondedType> = vec![];
+            for e in entries {
+                let types: Vec<&str> = e.types.iter().map(|t| t.as_str()).collect();
+                let known = find(table, &types, e.funct);
+                if known.is_empty() {
+                    added.push(e);
+                } else if !known.iter().any(|k| k.params == e.params) {
+                    println!("Warning: bonded type {} is tabulated with other parameters, kept the first", types.join("-"));
+                }
+            }
+            table.extend(added);
+        }
+    }
+
+    /// The entries whose types are not in `known`, with the names of the types that are, with the same
+    /// parameters and with other ones.
+    pub fn missing(&self, known: &Ffbonded) -> (Ffbonded, Vec<String>, Vec<String>) {
+        let mut missing = Ffbonded::default();
+        let mut same = vec![];
+        let mut other = vec![];
+        for (table, ours, theirs) in [(&mut missing.bondtypes, &self.bondtypes, &known.bondtypes),
+                                      (&mut missing.angletypes, &self.angletypes, &known.angletypes),
+                                      (&mut missing.dihedraltypes, &self.dihedraltypes, &known.dihedraltypes),
+                                      (&mut missing.impropertypes, &self.impropertypes, &known.impropertypes)] {
+            for (i, e) in ours.iter().enumerate() {
+                // 多重二面角按一组处理
+                if ours[..i].iter().any(|o| o.types == e.types) {
+                    continue;
+                }
+                let group: Vec<&BondedType> = ours[i..].iter().filter(|o| o.types == e.types).collect();
+                let types: Vec<&str> = e.types.iter().map(|t| t.as_str()).collect();
+                let found = find(theirs, &types, e.funct);
+                let params: Vec<&[f64]> = group.iter().map(|g| g.params.as_slice()).collect();
+                match found.is_empty() {
+                    true => table.extend(group.into_iter().cloned()),
+                    false if same_params(&found, &params) => same.push(types.join("-")),
+                    false => other.push(types.join("-")),
+                }
+            }
+        }
+        (missing, same, other)
+    }
+
+    /// The sections, without the file header.
+    pub fn sections(&self) -> String {
+        let mut out = String::new();
+        for (title, columns, table) in [
+            ("[ bondtypes ]", ";      i       j func           b0           kb", &self.bondtypes),
+            ("[ angletypes ]", ";      i       j       k func       th0      cth", &self.angletypes),
+            ("[ dihedraltypes ]\n; propers", ";      i       j       k       l func     phase       kd      pn", &self.dihedraltypes),
+            ("[ dihedraltypes ]\n; impropers", ";      i       j       k       l func     phase       kd      pn", &self.impropertypes),
+        ] {
+            if table.is_empty() {
+                continue;
+            }
+            out.push_str(&format!("\n{}\n{}\n", title, columns));
+            for t in table {
+                out.push_str(&format!("{}\n", t.line()));
+            }
+        }
+        out
+    }
+}
+
+impl Display for Ffbonded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "; Created by gen-rtp (https://github.com/supernova4869/gen-rtp)")?;
+        write!(f, "{}", self.sections())
+    }
+}
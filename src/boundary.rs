@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::hdb::{get_adj_atoms_id, get_adj_h_id};
+use crate::mol2::MOL2;
+use crate::residue::{Kind, Neighbour, ResidueOptions};
+
+/// Where the previous and next residues of a capped molecule are taken from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Detect {
+    /// The backbone bonds to the caps (amide or phosphodiester), see [`detect_neighbours`].
+    #[default]
+    Amide,
+    /// The mol2 substructure of each atom, see [`neighbours_from_residues`].
+    Subst,
+    /// The `resnr` column of the itp `[ atoms ]`.
+    Resnr,
+    /// Nothing is proposed.
+    None,
+}
+
+impl FromStr for Detect {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Detect, String> {
+        match s {
+            "amide" => Ok(Detect::Amide),
+            "subst" | "substructure" => Ok(Detect::Subst),
+            "resnr" => Ok(Detect::Resnr),
+            "none" => Ok(Detect::None),
+            _ => Err(format!("Invalid detection `{}`, only support amide, subst, resnr and none.", s)),
+        }
+    }
+}
+
+impl Display for Detect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Detect::Amide => write!(f, "amide"),
+            Detect::Subst => write!(f, "subst"),
+            Detect::Resnr => write!(f, "resnr"),
+            Detect::None => write!(f, "none"),
+        }
+    }
+}
+
+/// A backbone bond that is not part of a ring, splitting the molecule in two: C(=O)-N for proteins,
+/// O3'-P for nucleic acids.
+struct Linkage {
+    /// The atom ending a residue, carbonyl C or O3'.
+    head: usize,
+    /// The atom starting the next residue, N or P.
+    tail: usize,
+    /// Atoms on the `head` side, including `head`.
+    head_side: Vec<usize>,
+    /// Atoms on the `tail` side, including `tail`.
+    tail_side: Vec<usize>,
+}
+
+/// Proposes the previous and next residues of a capped molecule (ACE-X-NME, or any fragment with a
+/// backbone bond on each side) from its amide bonds, or phosphodiester bonds for nucleic acids, with
+/// the standard connection names of `kind`. The smaller side of a backbone bond is taken as the cap:
+/// the carbonyl (O3') side is the previous residue and the amine (P) side the next one.
+pub fn detect_neighbours(mol2: &MOL2, kind: Kind) -> (Option<Neighbour>, Option<Neighbour>) {
+    let linkages = match kind {
+        Kind::Protein => amide_bonds(mol2),
+        Kind::Nucleic => phosphodiester_bonds(mol2),
+        // 聚合物的连接键没有固定的模式
+        Kind::Polymer => vec![],
+    };
+    // 不含碳的封端 (如侧链酰胺的 NH2) 排在最后
+    let no_carbon = |atoms: &[usize]| !atoms.iter().any(|&a| mol2.atoms[a - 1].element == "C");
+    let prev = linkages.iter()
+        .filter(|l| l.head_side.len() < l.tail_side.len())
+        .min_by_key(|l| (no_carbon(&l.head_side), l.head_side.len()));
+    // 有前一残基时, 优先选离其连接原子最近的键, 即主链而非侧链酰胺
+    let next = linkages.iter()
+        .filter(|l| l.tail_side.len() < l.head_side.len())
+        .filter(|l| prev.is_none_or(|p| !l.tail_side.iter().any(|x| p.head_side.contains(x)) && l.head != p.tail))
+        .min_by_key(|l| (no_carbon(&l.tail_side), prev.map_or(0, |p| distance(mol2, p.tail, l.head)), l.tail_side.len()));
+    let [prev_con, prev_adj, next_con, next_adj] = kind.link_names();
+    let prev = prev.map(|l| Neighbour {
+        atoms: sorted(&l.head_side), con_atom: l.head, con_name: prev_con.to_string(), adj_atom: l.tail, adj_name: prev_adj.to_string()
+    });
+    let next = next.map(|l| Neighbour {
+        atoms: sorted(&l.tail_side), con_atom: l.tail, con_name: next_con.to_string(), adj_atom: l.head, adj_name: next_adj.to_string()
+    });
+    (prev, next)
+}
+
+/// Splits a molecule by the residue of each atom, given as (atom id, residue number, residue name), e.g.
+/// the mol2 substructures or the itp `resnr` column. The current residue is the one named `resname`
+/// (the largest one if none is); residues numbered before it form the previous residue and those after
+/// it the next one. The connection atoms are those of the bonds between them and the current residue.
+pub fn neighbours_from_residues(mol2: &MOL2, residues: &[(usize, i32, String)], resname: &str, kind: Kind)
+    -> Result<(Option<Neighbour>, Option<Neighbour>)> {
+    let [prev_con, prev_adj, next_con, next_adj] = kind.link_names();
+    let mut counts: Vec<(i32, &str, usize)> = vec![];
+    for (_, resnr, name) in residues {
+        match counts.iter_mut().find(|(r, _, _)| r == resnr) {
+            Some(c) => c.2 += 1,
+            None => counts.push((*resnr, name, 1)),
+        }
+    }
+    let current = counts.iter().find(|(_, name, _)| name.eq_ignore_ascii_case(resname))
+        .or_else(|| counts.iter().rev().max_by_key(|(_, _, n)| *n))
+        .map(|c| c.0)
+        .ok_or_else(|| Error::Invalid("no residue found to split the molecule".to_string()))?;
+    let side = |before: bool| -> Vec<usize> {
+        let mut atoms: Vec<usize> = residues.iter()
+            .filter(|(_, r, _)| match before { true => *r < current, false => *r > current })
+            .map(|(id, _, _)| *id)
+            .collect();
+        atoms.sort_unstable();
+        atoms
+    };
+    let link = |atoms: &[usize], side: &str| -> Result<(usize, usize)> {
+        find_link(mol2, atoms).ok_or_else(|| Error::Invalid(format!(
+            "atoms of the {} residue are not bonded to the current residue {}", side, current)))
+    };
+    let prev = match side(true) {
+        atoms if atoms.is_empty() => None,
+        atoms => {
+            let (con_atom, adj_atom) = link(&atoms, "previous")?;
+            Some(Neighbour { atoms, con_atom, con_name: prev_con.to_string(), adj_atom, adj_name: prev_adj.to_string() })
+        },
+    };
+    let next = match side(false) {
+        atoms if atoms.is_empty() => None,
+        atoms => {
+            let (con_atom, adj_atom) = link(&atoms, "next")?;
+            Some(Neighbour { atoms, con_atom, con_name: next_con.to_string(), adj_atom, adj_name: next_adj.to_string() })
+        },
+    };
+    Ok((prev, next))
+}
+
+/// The residue of each atom from the mol2 substructures, as (atom id, subst_id, subst_name).
+pub fn mol2_residues(mol2: &MOL2) -> Result<Vec<(usize, i32, String)>> {
+    mol2.atoms.iter().map(|a| match (a.subst_id, &a.subst_name) {
+        (Some(id), Some(name)) => Ok((a.atom_id, id as i32, name.to_string())),
+        _ => Err(Error::Invalid(format!("atom {} has no substructure in the mol2 file", a.atom_id))),
+    }).collect()
+}
+
+/// The bond between `atoms` (a neighbouring residue) and the rest of the molecule, as
+/// (atom of the neighbour, atom of the current residue).
+pub fn find_link(mol2: &MOL2, atoms: &[usize]) -> Option<(usize, usize)> {
+    mol2.bonds.iter().find_map(|b| match (atoms.contains(&b.a1), atoms.contains(&b.a2)) {
+        (true, false) => Some((b.a1, b.a2)),
+        (false, true) => Some((b.a2, b.a1)),
+        _ => None,
+    })
+}
+
+/// Names in the reference residue (`--neighbour-ref`, ALA by default) of the cap atoms of a protein
+/// residue, found from the bonds to the connection atoms: the carbonyl O of the previous residue is `O`,
+/// the hydrogen on the N of the next one `H`, and an sp3 carbon bonded to either connection atom `CA`
+/// with its hydrogens `HA`. The atoms are named as in the rtp, e.g. `-O2`.
+pub fn neighbour_roles(mol2: &MOL2, options: &ResidueOptions) -> Vec<(String, String)> {
+    let mut roles = vec![];
+    if options.kind != Kind::Protein {
+        return roles;
+    }
+    for (n, sign) in [(&options.prev, "-"), (&options.next, "+")] {
+        let Some(n) = n else { continue };
+        let name = |id: usize| format!("{}{}", sign, mol2.atoms[id - 1].atom_name.trim_start_matches(sign));
+        for id in get_adj_atoms_id(mol2, n.con_atom).into_iter().filter(|id| n.atoms.contains(id)) {
+            let atom = &mol2.atoms[id - 1];
+            match (atom.element.as_str(), atom.atom_type.as_str(), sign) {
+                ("O", _, "-") => roles.push((name(id), "O".to_string())),
+                ("H", _, "+") => roles.push((name(id), "H".to_string())),
+                ("C", "C.3", _) => {
+                    roles.push((name(id), "CA".to_string()));
+                    roles.extend(get_adj_h_id(mol2, id).into_iter().map(|h| (name(h), "HA".to_string())));
+                },
+                _ => {},
+            }
+        }
+    }
+    roles
+}
+
+fn amide_bonds(mol2: &MOL2) -> Vec<Linkage> {
+    let element = |id: usize| mol2.atoms[id - 1].element.as_str();
+    // 羰基C: 连有只连一个原子的O
+    let carbonyl = |c: usize| get_adj_atoms_id(mol2, c).into_iter()
+        .any(|o| element(o) == "O" && get_adj_atoms_id(mol2, o).len() == 1);
+    linkages(mol2, |a, b| match (element(a), element(b)) {
+        ("C", "N") if carbonyl(a) => Some((a, b)),
+        ("N", "C") if carbonyl(b) => Some((b, a)),
+        _ => None,
+    })
+}
+
+fn phosphodiester_bonds(mol2: &MOL2) -> Vec<Linkage> {
+    let element = |id: usize| mol2.atoms[id - 1].element.as_str();
+    // O3' 连在糖环C上, O5' 连在环外的 C5' 上
+    let in_ring = |c: usize| get_adj_atoms_id(mol2, c).into_iter().any(|x| side(mol2, c, x).contains(&x));
+    let o3 = |o: usize| get_adj_atoms_id(mol2, o).into_iter().any(|c| element(c) == "C" && in_ring(c));
+    linkages(mol2, |a, b| match (element(a), element(b)) {
+        ("O", "P") if o3(a) => Some((a, b)),
+        ("P", "O") if o3(b) => Some((b, a)),
+        _ => None,
+    })
+}
+
+// 由 (head, tail) 判定函数找出所有不成环的主链键
+fn linkages(mol2: &MOL2, is_linkage: impl Fn(usize, usize) -> Option<(usize, usize)>) -> Vec<Linkage> {
+    let mut linkages = vec![];
+    for b in &mol2.bonds {
+        let (head, tail) = match is_linkage(b.a1, b.a2) {
+            Some(l) => l,
+            None => continue,
+        };
+        let head_side = side(mol2, head, tail);
+        // 成环的键 (如内酰胺) 不能切开
+        if head_side.contains(&tail) {
+            continue;
+        }
+        linkages.push(Linkage { head, tail, head_side, tail_side: side(mol2, tail, head) });
+    }
+    linkages
+}
+
+// 从 start 出发不经过 start-cut 键能到达的原子
+fn side(mol2: &MOL2, start: usize, cut: usize) -> Vec<usize> {
+    let mut found = vec![start];
+    let mut queue = VecDeque::from([start]);
+    while let Some(a) = queue.pop_front() {
+        for b in get_adj_atoms_id(mol2, a) {
+            if (a == start && b == cut) || found.contains(&b) {
+                continue;
+            }
+            found.push(b);
+            queue.push_back(b);
+        }
+    }
+    found
+}
+
+// 两原子间最少的键数, 不连通时为 usize::MAX
+fn distance(mol2: &MOL2, from: usize, to: usize) -> usize {
+    let mut dist = vec![usize::MAX; mol2.atoms.len() + 1];
+    dist[from] = 0;
+    let mut queue = VecDeque::from([from]);
+    while let Some(a) = queue.pop_front() {
+        for b in get_adj_atoms_id(mol2, a) {
+            if dist[b] == usize::MAX {
+                dist[b] = dist[a] + 1;
+                queue.push_back(b);
+            }
+        }
+    }
+    dist[to]
+}
+
+fn sorted(atoms: &[usize]) -> Vec<usize> {
+    let mut atoms = atoms.to_vec();
+    atoms.sort_unstable();
+    atoms
+}
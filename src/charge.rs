@@ -0,0 +1,222 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::hdb::{get_adj_h_id, get_adj_heavy_id};
+use crate::mol2::MOL2;
+use crate::residue::ResidueOptions;
+use crate::rtp::{RtpAtom, RtpResidue};
+
+/// How the residual charge of a residue is removed once the atoms of the neighbours are excluded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChargeFix {
+    /// Only report the residual.
+    #[default]
+    None,
+    /// Spread the residual evenly over all atoms.
+    Even,
+    /// Spread the residual over the connection atoms of the current residue.
+    Connection,
+    /// Spread the residual weighted by |q|.
+    Weighted,
+    /// Add the charge of each excluded neighbour to the atom bonded to it, then spread what is left evenly.
+    Fold,
+}
+
+impl FromStr for ChargeFix {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ChargeFix, String> {
+        match s {
+            "none" => Ok(ChargeFix::None),
+            "even" => Ok(ChargeFix::Even),
+            "connection" => Ok(ChargeFix::Connection),
+            "weighted" => Ok(ChargeFix::Weighted),
+            "fold" => Ok(ChargeFix::Fold),
+            _ => Err(format!("Invalid charge correction `{}`, only support none, even, connection, weighted and fold.", s)),
+        }
+    }
+}
+
+impl Display for ChargeFix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChargeFix::None => write!(f, "none"),
+            ChargeFix::Even => write!(f, "even"),
+            ChargeFix::Connection => write!(f, "connection"),
+            ChargeFix::Weighted => write!(f, "weighted"),
+            ChargeFix::Fold => write!(f, "fold"),
+        }
+    }
+}
+
+/// How the charge groups (`cgnr`) of the rtp are made.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChargeGroups {
+    /// The groups of the itp, renumbered from 1.
+    #[default]
+    Keep,
+    /// Small near-neutral groups from the bond graph, see [`charge_groups`].
+    Auto,
+}
+
+impl FromStr for ChargeGroups {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ChargeGroups, String> {
+        match s {
+            "keep" => Ok(ChargeGroups::Keep),
+            "auto" => Ok(ChargeGroups::Auto),
+            _ => Err(format!("Invalid charge groups `{}`, only support keep and auto.", s)),
+        }
+    }
+}
+
+impl Display for ChargeGroups {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChargeGroups::Keep => write!(f, "keep"),
+            ChargeGroups::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+// rtp 中电荷保留6位小数
+const PRECISION: f64 = 1e6;
+
+/// Reports the net charge of `rtp` without its excluded atoms and, unless `options.charge_fix` is
+/// [`ChargeFix::None`], corrects the charges to `options.net_charge` (the nearest integer if not given).
+/// `ids` are the atom ids of `rtp.atoms`. The target is recorded in `rtp.net_charge`.
+pub fn fix_charge(rtp: &mut RtpResidue, ids: &[usize], options: &ResidueOptions) {
+    let total = |rtp: &RtpResidue| -> f64 { rtp.atoms.iter().filter(|a| a.excluded.is_none()).map(|a| a.charge).sum() };
+    let net = total(rtp);
+    let target = options.net_charge.map_or(net.round(), |q| q as f64);
+    println!("Net charge of {} without the neighbour atoms: {:.6} (residual {:+.6} to {})", rtp.name, net, net - target, target as i32);
+    let strategy = options.charge_fix;
+    if strategy == ChargeFix::None {
+        return;
+    }
+
+    let current: Vec<usize> = (0..rtp.atoms.len()).filter(|&i| rtp.atoms[i].excluded.is_none()).collect();
+    let index = |id: usize| ids.iter().position(|&i| i == id).filter(|i| current.contains(i));
+    // 封端电荷并入与之相连的原子
+    if strategy == ChargeFix::Fold {
+        let sides = options.prev.iter().map(|n| (&n.atoms, n.adj_atom))
+            .chain(options.next.iter().map(|n| (&n.atoms, n.adj_atom)))
+            .chain(options.links.iter().map(|l| (&l.atoms, l.adj_atom)));
+        for (atoms, adj) in sides {
+            let cap: f64 = ids.iter().zip(&rtp.atoms).filter(|(id, _)| atoms.contains(id)).map(|(_, a)| a.charge).sum();
+            if let Some(i) = index(adj) {
+                rtp.atoms[i].charge += cap;
+            }
+        }
+    }
+    let target = options.net_charge.map_or(total(rtp).round(), |q| q as f64);
+    let residual = target - total(rtp);
+
+    // 各原子分摊的权重
+    let mut weights: Vec<(usize, f64)> = match strategy {
+        ChargeFix::Connection => options.prev.iter().map(|n| n.adj_atom)
+            .chain(options.next.iter().map(|n| n.adj_atom))
+            .chain(options.links.iter().map(|l| l.adj_atom))
+            .filter_map(index)
+            .map(|i| (i, 1.0))
+            .collect(),
+        ChargeFix::Weighted => current.iter().map(|&i| (i, rtp.atoms[i].charge.abs())).collect(),
+        _ => current.iter().map(|&i| (i, 1.0)).collect(),
+    };
+    if weights.iter().map(|(_, w)| w).sum::<f64>() == 0.0 {
+        println!("Note: no atom to take the residual by `{}`, spreading it evenly", strategy);
+        weights = current.iter().map(|&i| (i, 1.0)).collect();
+    }
+    let sum: f64 = weights.iter().map(|(_, w)| w).sum();
+    for &(i, w) in &weights {
+        rtp.atoms[i].charge += residual * w / sum;
+    }
+    // 按输出精度取整, 舍入误差放到电荷绝对值最大的原子上
+    for &i in &current {
+        rtp.atoms[i].charge = (rtp.atoms[i].charge * PRECISION).round() / PRECISION;
+    }
+    if let Some(&i) = current.iter().max_by(|&&a, &&b| rtp.atoms[a].charge.abs().total_cmp(&rtp.atoms[b].charge.abs())) {
+        rtp.atoms[i].charge += ((target - total(rtp)) * PRECISION).round() / PRECISION;
+    }
+    rtp.net_charge = Some(target as i32);
+    println!("Corrected the charges of {} to {} by `{}`", rtp.name, target as i32, strategy);
+}
+
+/// Renumbers the charge groups of the atoms kept in the residue from 1 in order of appearance, keeping
+/// the atoms of a group together. Excluded atoms keep their numbers.
+pub fn renumber_cgnr(atoms: &mut [RtpAtom]) {
+    let mut numbers: Vec<i32> = vec![];
+    for a in atoms.iter_mut().filter(|a| a.excluded.is_none()) {
+        let pos = match numbers.iter().position(|&n| n == a.cgnr) {
+            Some(pos) => pos,
+            None => {
+                numbers.push(a.cgnr);
+                numbers.len() - 1
+            },
+        };
+        a.cgnr = pos as i32 + 1;
+    }
+}
+
+// 电荷组总电荷与整数的最大偏差
+const GROUP_TOLERANCE: f64 = 0.05;
+
+/// Assigns small near-neutral charge groups, as GROMOS-family force fields expect: each heavy atom with
+/// its hydrogens is a unit, and bonded units are merged, lowest id first, until the group charge is
+/// within 0.05 of an integer. `ids` are the atom ids of `rtp.atoms`.
+pub fn charge_groups(rtp: &mut RtpResidue, ids: &[usize], mol2: &MOL2) {
+    let index = |id: usize| ids.iter().position(|&i| i == id).filter(|&i| rtp.atoms[i].excluded.is_none());
+    let heavy: Vec<usize> = ids.iter().copied()
+        .filter(|&id| index(id).is_some() && mol2.atoms[id - 1].element != "H")
+        .collect();
+    let unit = |id: usize| -> Vec<usize> {
+        [id].into_iter().chain(get_adj_h_id(mol2, id)).filter_map(index).collect()
+    };
+    let charge = |atoms: &[usize]| -> f64 { atoms.iter().map(|&i| rtp.atoms[i].charge).sum() };
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut done: Vec<usize> = vec![];
+    while let Some(&seed) = heavy.iter().find(|id| !done.contains(id)) {
+        let mut members = vec![seed];
+        let mut atoms = unit(seed);
+        done.push(seed);
+        // 逐个并入与组内重原子相连的重原子, 直到组电荷接近整数
+        while (charge(&atoms) - charge(&atoms).round()).abs() > GROUP_TOLERANCE {
+            let next = members.iter()
+                .flat_map(|&m| get_adj_heavy_id(mol2, m))
+                .filter(|id| heavy.contains(id) && !done.contains(id))
+                .min();
+            match next {
+                Some(id) => {
+                    members.push(id);
+                    atoms.extend(unit(id));
+                    done.push(id);
+                },
+                None => break,
+            }
+        }
+        groups.push(atoms);
+    }
+    let off: Vec<String> = groups.iter().enumerate()
+        .filter(|(_, g)| (charge(g) - charge(g).round()).abs() > GROUP_TOLERANCE)
+        .map(|(n, g)| format!("{} ({:+.4})", n + 1, charge(g)))
+        .collect();
+    for (n, atoms) in groups.iter().enumerate() {
+        for &i in atoms {
+            rtp.atoms[i].cgnr = n as i32 + 1;
+        }
+    }
+    // 孤立的 H (未连重原子) 单独成组
+    let mut n = groups.len() as i32;
+    for i in 0..rtp.atoms.len() {
+        if rtp.atoms[i].excluded.is_none() && !groups.iter().any(|g| g.contains(&i)) {
+            n += 1;
+            rtp.atoms[i].cgnr = n;
+        }
+    }
+    println!("Assigned {} charge groups to {}", n, rtp.name);
+    if !off.is_empty() {
+        println!("Note: charge groups {} of {} are not near-integer", off.join(", "), rtp.name);
+    }
+}
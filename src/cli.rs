@@ -0,0 +1,472 @@
+use std::str::FromStr;
+
+use gen_rtp::bonded::Ffbonded;
+use gen_rtp::boundary::{detect_neighbours, find_link, mol2_residues, neighbours_from_residues, Detect};
+use gen_rtp::charge::{ChargeFix, ChargeGroups};
+use gen_rtp::error::Error;
+use gen_rtp::itp::{read_atom_residues, read_molecules, select_molecule};
+use gen_rtp::mol2::MOL2;
+use gen_rtp::output::Overwrite;
+use gen_rtp::preprocess::Preprocessor;
+use gen_rtp::naming::{heavy_names, NameTable, Naming};
+use gen_rtp::residue::{Kind, Link, Neighbour, ResidueOptions};
+use gen_rtp::utils::{ask, atlist2atrange, atrange2atlist};
+
+pub const USAGE: &str = "\
+Usage: gen-rtp [OPTIONS] [MOL2]
+       gen-rtp batch [--dry-run] [--overwrite <MODE>] <JOB>
+       gen-rtp apply-map [-o <DIR>] [--resname <RES>] [--resid <N>] [--dry-run] [--overwrite <MODE>] <MAP> <FILE>...
+       gen-rtp uninstall [--dry-run] <MANIFEST>
+
+Every option left out is asked interactively, unless `--non-interactive` is given,
+in which case the default value is used. The previous and next residues (e.g. ACE and
+NME caps) are proposed from the amide bonds of the molecule, or by `--detect`.
+
+`batch` converts all residues listed in a job file and merges them into one
+`rtp` and `hdb`. Residue keys in the job file are the long options below.
+
+`apply-map` renames the atoms of PDB, `.ndx` and itp (e.g. restraint) files with a
+name mapping written by a conversion, saving each as <stem>_renamed.<ext>. Only the
+residue named in the mapping (or `--resname`) is renamed, numbered `--resid` if given.
+
+`uninstall` reverts the force field edits of `--install` recorded in a manifest.
+
+Options:
+  -m, --mol2 <FILE>             input `mol2` file (same as the positional MOL2)
+  -i, --itp <FILE>              `itp` file generated by Sobtop or acpype (default: <mol2 stem>.itp)
+  -I, --include <DIR>           directory searched for #include files of the itp or top (after the
+                                directory of the including file, before $GMXLIB); may be repeated
+  -D, --define <NAME[=VALUE]>   define a macro for #ifdef and substitution, e.g., -DPOSRES; may be repeated
+      --molecule <NAME>         [ moleculetype ] of the itp or top to convert, by name or number
+                                (default: the only one, or the one with as many atoms as the mol2)
+  -f, --ff <amber|gromos>       force field family of the rtp (default: amber)
+      --target-ff <DIR>         `.ff` directory (or its forcefield.itp) the residue goes into: its
+                                [ defaults ] are compared with the itp, converting the atomtypes
+                                between sigma/epsilon and C6/C12 if needed (default: the
+                                --install directory); its atomtypes clashing by name (ignoring
+                                case) with other parameters are renamed, see --type-prefix
+      --type-prefix <PREFIX>    prefix of renamed clashing atomtypes (default: gaff_, e.g., gaff_c)
+      --neighbour-ref <RES>     residue of the target force field rtp whose atomtypes stand for the
+                                neighbour atoms (-C, +N...) when looking up the parameters of the
+                                terms across the link (default: ALA, DA for nucleic; the unit
+                                itself for polymer)
+      --neighbour-map <MAP>     names in that residue of neighbour atoms, e.g., -O2=O,-C3=CA; by
+                                default the carbonyl O of the previous residue is O, the sp3
+                                carbon bonded to a connection atom CA (its hydrogens HA) and the
+                                hydrogen of the next N is H
+      --bonded-types            move the bonded parameters of the residue into [ bondtypes ],
+                                [ angletypes ] and [ dihedraltypes ] for ffbonded.itp, keyed by
+                                atomtype, leaving only the connectivity in the rtp; types already
+                                in the target force field are not repeated
+  -k, --kind <KIND>             protein (-C/N, +N/C links, default), nucleic (-O3'/P, +P/O3'
+                                links, with 5'/3' terminal variants) or polymer (repeat unit
+                                with start and end units, links named after the head/tail atoms)
+      --naming <SCHEME>         heavy atom names: global (C1, O2, C3..., default),
+                                element (C1, O1, C2...), keep, or amino (N, CA, CB, CG...)
+      --name-map <FILE>         take atom names from a table of `id-or-old-name,new-name` lines
+      --rename                  same as --naming global
+      --no-rename               same as --naming keep
+  -p, --prev <RANGE>            atoms id of the previous residue, e.g., 1-3,5 (empty or none: first residue)
+      --prev-con <ID>           connection atom id of the previous residue (default: from the bonds)
+      --prev-con-name <NAME>    new name of the previous connection atom (default: -C or -O3')
+      --prev-adj <ID>           connection atom id of the current residue to previous
+      --prev-adj-name <NAME>    new name of that atom (default: N or P)
+  -n, --next <RANGE>            atoms id of the next residue, e.g., 31-36 (empty or none: last residue)
+      --next-con <ID>           connection atom id of the next residue (default: from the bonds)
+      --next-con-name <NAME>    new name of the next connection atom (default: +N or +P)
+      --next-adj <ID>           connection atom id of the current residue to next
+      --next-adj-name <NAME>    new name of that atom (default: C or O3')
+      --link <SPEC>             extra link to another residue (crosslink, branch, ligand), dropped
+                                from the rtp and written to specbond.dat: RANGE:RES:ATOM[:CON:ADJ],
+                                e.g., 37-45:LYS:NZ (atoms, residue and atom name of the linked
+                                residue, connection atoms from the bonds); may be repeated
+      --charge-fix <HOW>        correct the net charge left after excluding the neighbour atoms:
+                                none (only report it, default), even (over all atoms), connection
+                                (over the connection atoms), weighted (by |q|) or fold (add each
+                                cap's charge to the atom bonded to it, then spread the rest evenly)
+      --net-charge <Q>          integer net charge to correct to (default: the nearest integer)
+      --charge-groups <HOW>     keep (the itp groups renumbered from 1, default) or auto (each heavy
+                                atom with its H, bonded ones merged until near-integer; for gromos)
+      --detect <FROM>           propose the previous and next residues from: amide (bonds to
+                                the caps, default), subst (mol2 substructures), resnr (itp
+                                `[ atoms ]` residue numbers) or none
+      --no-detect               same as --detect none
+      --head <ID>               polymer: atom of the repeat unit bonded to the previous unit (= --prev-adj)
+      --tail <ID>               polymer: atom of the repeat unit bonded to the next unit (= --next-adj)
+  -o, --out-dir <DIR>           directory of the output files (default: next to the mol2 and itp)
+      --mol2-out <TEMPLATE>     renamed mol2 file name (default: {mol2}_renamed.mol2)
+      --rtp-out <TEMPLATE>      rtp file name (default: {itp}.rtp)
+      --hdb-out <TEMPLATE>      hdb file name (default: {itp}.hdb)
+      --nonbonded-out <TEMPLATE>
+                                [ atomtypes ] for ffnonbonded.itp (default: {itp}_ffnonbonded.itp)
+      --atp-out <TEMPLATE>      lines for atomtypes.atp (default: {itp}_atomtypes.atp)
+      --ffbonded-out <TEMPLATE> bonded types for ffbonded.itp, with --bonded-types
+                                (default: {itp}_ffbonded.itp)
+      --map-out <TEMPLATE>      old/new atom name mapping, CSV or .json (default: {mol2}_names.csv)
+      --specbond-out <TEMPLATE> specbond.dat entries of the links (default: {itp}_specbond.dat)
+      --install <DIR>           also install the residue into a `.ff` directory: rtp and hdb entries
+                                (replacing those of the same name), new atomtypes in ffnonbonded.itp
+                                and atomtypes.atp, new bonded types in ffbonded.itp, and
+                                residuetypes.dat; the edits are recorded in
+                                <DIR>/gen-rtp_<res>.manifest for `uninstall`
+      --install-rtp <NAME>      rtp and hdb of the force field to install into (default: aminoacids)
+      --overwrite <MODE>        existing output files: backup (to .bak, default), refuse or force
+      --dry-run                 show what would be written without writing anything
+  -y, --non-interactive         never prompt, use defaults for options not given
+  -h, --help                    print this help
+
+Templates may use {mol2} and {itp} (stems of the input files) and {res} (residue name).
+The input mol2 and itp are never overwritten.
+
+Exit status: 0 on success, 1 on failure, 2 on invalid command line.";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub mol2: Option<String>,
+    pub itp: Option<String>,
+    pub preprocessor: Preprocessor,
+    pub molecule: Option<String>,
+    pub ff: Option<String>,
+    pub target_ff: Option<String>,
+    pub type_prefix: Option<String>,
+    pub neighbour_ref: Option<String>,
+    pub neighbour_map: Vec<(String, String)>,
+    pub bonded_types: bool,
+    pub naming: Option<Naming>,
+    pub kind: Option<Kind>,
+    pub name_map: Option<String>,
+    pub prev_atoms: Option<String>,
+    pub prev_con_atom: Option<usize>,
+    pub prev_con_atom_name: Option<String>,
+    pub prev_adj_atom: Option<usize>,
+    pub prev_adj_atom_name: Option<String>,
+    pub next_atoms: Option<String>,
+    pub next_con_atom: Option<usize>,
+    pub next_con_atom_name: Option<String>,
+    pub next_adj_atom: Option<usize>,
+    pub next_adj_atom_name: Option<String>,
+    pub links: Vec<String>,
+    pub charge_fix: Option<ChargeFix>,
+    pub net_charge: Option<i32>,
+    pub charge_groups: Option<ChargeGroups>,
+    pub detect: Detect,
+    pub out_dir: Option<String>,
+    pub mol2_out: Option<String>,
+    pub rtp_out: Option<String>,
+    pub hdb_out: Option<String>,
+    pub map_out: Option<String>,
+    pub nonbonded_out: Option<String>,
+    pub atp_out: Option<String>,
+    pub ffbonded_out: Option<String>,
+    /// Bonded types tabulated by the residues converted before, in a batch.
+    pub known_bonded: Ffbonded,
+    pub specbond_out: Option<String>,
+    pub install: Option<String>,
+    pub install_rtp: Option<String>,
+    pub overwrite: Overwrite,
+    pub dry_run: bool,
+    pub interactive: bool,
+    pub help: bool,
+}
+
+impl Args {
+    pub fn parse(args: &[String]) -> Result<Args, String> {
+        let mut parsed = Args { interactive: true, ..Default::default() };
+        let mut i = 0;
+        while i < args.len() {
+            // 支持 --opt value 与 --opt=value 两种写法
+            let (opt, inline) = match args[i].split_once('=') {
+                Some((o, v)) if args[i].starts_with("--") => (o.to_string(), Some(v.to_string())),
+                _ => (args[i].to_string(), None),
+            };
+            let mut value = || -> Result<String, String> {
+                if let Some(v) = &inline {
+                    return Ok(v.to_string());
+                }
+                i += 1;
+                args.get(i).cloned().ok_or(format!("Option `{}` requires a value.", opt))
+            };
+            match opt.as_str() {
+                "-m" | "--mol2" => parsed.mol2 = Some(value()?),
+                "-i" | "--itp" => parsed.itp = Some(value()?),
+                "--molecule" => parsed.molecule = Some(value()?),
+                "-I" | "--include" => parsed.preprocessor.include_dirs.push(value()?),
+                "-D" | "--define" => parsed.preprocessor.define(&value()?),
+                "-f" | "--ff" => {
+                    let ff = value()?;
+                    if ff != "amber" && ff != "gromos" {
+                        return Err(format!("Invalid forcefield `{}`, only support amber and gromos.", ff));
+                    }
+                    parsed.ff = Some(ff);
+                },
+                "--target-ff" => parsed.target_ff = Some(value()?),
+                "--type-prefix" => parsed.type_prefix = Some(value()?),
+                "--neighbour-ref" => parsed.neighbour_ref = Some(value()?),
+                "--neighbour-map" => parsed.neighbour_map.extend(parse_neighbour_map(&value()?)?),
+                "--bonded-types" => parsed.bonded_types = true,
+                "-k" | "--kind" => parsed.kind = Some(value()?.parse()?),
+                "--naming" => parsed.naming = Some(value()?.parse()?),
+                "--name-map" => parsed.name_map = Some(value()?),
+                "--rename" => parsed.naming = Some(Naming::Global),
+                "--no-rename" => parsed.naming = Some(Naming::Keep),
+                "-p" | "--prev" => parsed.prev_atoms = Some(value()?),
+                "--prev-con" => parsed.prev_con_atom = Some(parse_value(&opt, &value()?)?),
+                "--prev-con-name" => parsed.prev_con_atom_name = Some(value()?),
+                "--prev-adj" => parsed.prev_adj_atom = Some(parse_value(&opt, &value()?)?),
+                "--prev-adj-name" => parsed.prev_adj_atom_name = Some(value()?),
+                "-n" | "--next" => parsed.next_atoms = Some(value()?),
+                "--next-con" => parsed.next_con_atom = Some(parse_value(&opt, &value()?)?),
+                "--next-con-name" => parsed.next_con_atom_name = Some(value()?),
+                "--head" => parsed.prev_adj_atom = Some(parse_value(&opt, &value()?)?),
+                "--tail" => parsed.next_adj_atom = Some(parse_value(&opt, &value()?)?),
+                "--next-adj" => parsed.next_adj_atom = Some(parse_value(&opt, &value()?)?),
+                "--next-adj-name" => parsed.next_adj_atom_name = Some(value()?),
+                "--link" => parsed.links.push(value()?),
+                "--charge-fix" => parsed.charge_fix = Some(value()?.parse()?),
+                "--net-charge" => parsed.net_charge = Some(parse_value(&opt, &value()?)?),
+                "--charge-groups" => parsed.charge_groups = Some(value()?.parse()?),
+                "--detect" => parsed.detect = value()?.parse()?,
+                "--no-detect" => parsed.detect = Detect::None,
+                "-o" | "--out-dir" => parsed.out_dir = Some(value()?),
+                "--mol2-out" => parsed.mol2_out = Some(value()?),
+                "--rtp-out" => parsed.rtp_out = Some(value()?),
+                "--hdb-out" => parsed.hdb_out = Some(value()?),
+                "--map-out" => parsed.map_out = Some(value()?),
+                "--nonbonded-out" => parsed.nonbonded_out = Some(value()?),
+                "--atp-out" => parsed.atp_out = Some(value()?),
+                "--ffbonded-out" => parsed.ffbonded_out = Some(value()?),
+                "--specbond-out" => parsed.specbond_out = Some(value()?),
+                "--install" => parsed.install = Some(value()?),
+                "--install-rtp" => parsed.install_rtp = Some(value()?),
+                "--overwrite" => parsed.overwrite = value()?.parse()?,
+                "--dry-run" => parsed.dry_run = true,
+                "-y" | "--non-interactive" => parsed.interactive = false,
+                "-h" | "--help" => parsed.help = true,
+                // 与 cpp 相同, 也可写作 -DPOSRES, -I../top
+                s if s.starts_with("-D") => parsed.preprocessor.define(&args[i][2..]),
+                s if s.starts_with("-I") => parsed.preprocessor.include_dirs.push(args[i][2..].to_string()),
+                s if s.starts_with('-') && s.len() > 1 => return Err(format!("Unknown option `{}`.", s)),
+                _ => match parsed.mol2 {
+                    None => parsed.mol2 = Some(args[i].to_string()),
+                    Some(_) => return Err("Too more arguments.".to_string()),
+                },
+            }
+            i += 1;
+        }
+        Ok(parsed)
+    }
+}
+
+// 如 -O2=O,-C3=CA
+fn parse_neighbour_map(value: &str) -> Result<Vec<(String, String)>, String> {
+    value.split(',').filter(|p| !p.trim().is_empty()).map(|pair| match pair.split_once('=') {
+        Some((atom, reference)) if !atom.trim().is_empty() && !reference.trim().is_empty() =>
+            Ok((atom.trim().to_string(), reference.trim().to_string())),
+        _ => Err(format!("Invalid neighbour mapping `{}`, expected ATOM=REF, e.g., -O2=O.", pair)),
+    }).collect()
+}
+
+fn parse_value<T: FromStr>(opt: &str, value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("Invalid value `{}` for option `{}`.", value, opt))
+}
+
+// 命令行未给出的残基边界信息逐项询问, 默认值来自酰胺键检测或相邻残基与当前残基间的键
+pub fn residue_options(args: &Args, mol2: &MOL2, itp_file: &str) -> Result<ResidueOptions, Error> {
+    let molecule = select(args, mol2, itp_file)?;
+    let naming = match &args.name_map {
+        Some(file) => Naming::Map(NameTable::from(file)?),
+        None => ask("Naming scheme of heavy atoms: global (C1, O2, C3...), element (C1, O1, C2...), keep or amino (N, CA, CB...) (default: global):",
+            args.naming.clone(), Naming::Global, args.interactive),
+    };
+    let kind = ask("Residue kind, protein, nucleic or polymer (default: protein):", args.kind, Kind::Protein, args.interactive);
+    let [prev_con_name, prev_adj_name, next_con_name, next_adj_name] = kind.link_names();
+    let (detected_prev, detected_next) = match args.detect {
+        Detect::Amide => detect_neighbours(mol2, kind),
+        Detect::Subst => neighbours_from_residues(mol2, &mol2_residues(mol2)?, &mol2.resname, kind)?,
+        Detect::Resnr => neighbours_from_residues(mol2, &read_atom_residues(itp_file, &args.preprocessor, Some(&molecule))?, &mol2.resname, kind)?,
+        Detect::None => (None, None),
+    };
+    for (side, n) in [("previous", &detected_prev), ("next", &detected_next)] {
+        if let Some(n) = n {
+            println!("Detected {} residue: atoms {}, bonded by atom {} to atom {} of the current residue",
+                side, atlist2atrange(&n.atoms), n.con_atom, n.adj_atom);
+        }
+    }
+    let default = detected_prev.as_ref().map_or(String::new(), |n| atlist2atrange(&n.atoms));
+    let prev_atoms = ask(&format!("Input atoms id of the previous residue, e.g., 1-3, 5 (default: {}, input `none` if it is the first residue): ", show(&default)),
+        args.prev_atoms.clone(), default, args.interactive);
+    let prev_atoms = atrange2atlist(none_to_empty(&prev_atoms))?;
+    let prev = match prev_atoms.is_empty() {
+        false => {
+            let (default_con, default_adj) = match &detected_prev {
+                Some(n) if n.atoms == prev_atoms => (n.con_atom, n.adj_atom),
+                _ => find_link(mol2, &prev_atoms).unwrap_or((prev_atoms[0], prev_atoms[prev_atoms.len() - 1] + 1)),
+            };
+            let con_atom = ask(&format!("Connection atom id of the previous residue (default: {}): ", default_con),
+                args.prev_con_atom, default_con, args.interactive);
+            let con_name = match kind {
+                Kind::Polymer => args.prev_con_atom_name.clone().unwrap_or_default(),
+                _ => ask(&format!("Rename connection atom name to (default: {}): \n(IMPORTANT: please ensure the new atom name is correct)", prev_con_name),
+                    args.prev_con_atom_name.clone(), prev_con_name.to_string(), args.interactive),
+            };
+            let adj_atom = ask(&format!("Connection atom id of the current residue to previous (default: {}): ", default_adj),
+                args.prev_adj_atom, default_adj, args.interactive);
+            let adj_name = match kind {
+                Kind::Polymer => args.prev_adj_atom_name.clone().unwrap_or_default(),
+                _ => ask(&format!("Rename connection atom name to (default: {}): \n(IMPORTANT: please ensure the new atom name is correct)", prev_adj_name),
+                    args.prev_adj_atom_name.clone(), prev_adj_name.to_string(), args.interactive),
+            };
+            Some(Neighbour { atoms: prev_atoms, con_atom, con_name, adj_atom, adj_name })
+        },
+        true => None
+    };
+    let default = detected_next.as_ref().map_or(String::new(), |n| atlist2atrange(&n.atoms));
+    let next_atoms = ask(&format!("Input atoms id of the next residue, e.g., 1-3, 5 (default: {}, input `none` if it is the last residue): ", show(&default)),
+        args.next_atoms.clone(), default, args.interactive);
+    let next_atoms = atrange2atlist(none_to_empty(&next_atoms))?;
+    let next = match next_atoms.is_empty() {
+        false => {
+            let (default_con, default_adj) = match &detected_next {
+                Some(n) if n.atoms == next_atoms => (n.con_atom, n.adj_atom),
+                _ => find_link(mol2, &next_atoms).unwrap_or((next_atoms[0], next_atoms[0].saturating_sub(1))),
+            };
+            let con_atom = ask(&format!("Connection atom id of the next residue (default: {}): ", default_con),
+                args.next_con_atom, default_con, args.interactive);
+            let con_name = match kind {
+                Kind::Polymer => args.next_con_atom_name.clone().unwrap_or_default(),
+                _ => ask(&format!("Rename connection atom to (default: {}): \n(IMPORTANT: please ensure the new atom name is correct)", next_con_name),
+                    args.next_con_atom_name.clone(), next_con_name.to_string(), args.interactive),
+            };
+            let adj_atom = ask(&format!("Connection atom id of the current residue to next (default: {}): ", default_adj),
+                args.next_adj_atom, default_adj, args.interactive);
+            let adj_name = match kind {
+                Kind::Polymer => args.next_adj_atom_name.clone().unwrap_or_default(),
+                _ => ask(&format!("Rename connection atom to (default: {}): \n(IMPORTANT: please ensure the new atom name is correct)", next_adj_name),
+                    args.next_adj_atom_name.clone(), next_adj_name.to_string(), args.interactive),
+            };
+            Some(Neighbour { atoms: next_atoms, con_atom, con_name, adj_atom, adj_name })
+        },
+        true => None
+    };
+    let preset = match args.links.is_empty() {
+        true => None,
+        false => Some(args.links.join(" ")),
+    };
+    let links = ask("Input extra links to other residues as RANGE:RES:ATOM[:CON:ADJ], separated by spaces, e.g., 37-45:LYS:NZ (default: none):",
+        preset, "".to_string(), args.interactive);
+    let links = links.split_whitespace().map(|spec| parse_link(spec, mol2)).collect::<Result<_, _>>()?;
+    let charge_fix = ask("Charge correction after excluding the neighbour atoms: none, even, connection, weighted or fold (default: none):",
+        args.charge_fix, ChargeFix::None, args.interactive);
+    let charge_groups = ask("Charge groups: keep (from the itp) or auto (near-neutral groups from the bonds) (default: keep):",
+        args.charge_groups, ChargeGroups::Keep, args.interactive);
+    let mut options = ResidueOptions {
+        naming, kind, prev, next, links, charge_fix, net_charge: args.net_charge, charge_groups,
+        bonded_types: args.bonded_types, neighbour_map: args.neighbour_map.clone(), preprocessor: args.preprocessor.clone(), molecule: Some(molecule), ..Default::default()
+    };
+    if kind == Kind::Polymer {
+        polymer_link_names(&mut options, mol2)?;
+    }
+    Ok(options)
+}
+
+// 列出 itp 或 top 中的分子, 只有一个或原子数与 mol2 相同的作为默认值
+fn select(args: &Args, mol2: &MOL2, itp_file: &str) -> Result<String, Error> {
+    let molecules = read_molecules(itp_file, &args.preprocessor)?;
+    if molecules.len() > 1 {
+        println!("Molecule types in {}:", itp_file);
+        for (i, m) in molecules.iter().enumerate() {
+            println!("{:>4}  {} ({} atoms)", i + 1, m.name, m.natoms);
+        }
+    }
+    let wanted = match molecules.len() > 1 && args.interactive {
+        true => {
+            let default = select_molecule(&molecules, None, Some(mol2.atoms.len())).unwrap_or(0);
+            let default = molecules[default].name.to_string();
+            Some(ask(&format!("Molecule type to convert, name or number (default: {}):", default), args.molecule.clone(), default, true))
+        },
+        false => args.molecule.clone(),
+    };
+    let index = select_molecule(&molecules, wanted.as_deref(), Some(mol2.atoms.len()))?;
+    Ok(molecules[index].name.to_string())
+}
+
+// 聚合物重复单元的首原子连到前一单元的尾原子: 未指定的连接原子名取首尾原子的新名字,
+// 如首原子 C1, 尾原子 O4 时为 -O4/C1 与 +C1/O4
+fn polymer_link_names(options: &mut ResidueOptions, mol2: &MOL2) -> Result<(), Error> {
+    // 首尾原子由命令行给出, 取名前先检查范围
+    options.check(mol2.atoms.len())?;
+    let names = heavy_names(mol2, &ResidueOptions { naming: options.naming.clone(), ..Default::default() })?;
+    let (prev, next) = match (&mut options.prev, &mut options.next) {
+        (Some(prev), Some(next)) => (prev, next),
+        _ => return Err(Error::Invalid("the polymer mode needs both the previous and the next unit of the repeat unit".to_string())),
+    };
+    let name = |id: usize| names[id - 1].clone().unwrap_or_else(|| mol2.atoms[id - 1].atom_name.to_string());
+    if prev.adj_name.is_empty() {
+        prev.adj_name = name(prev.adj_atom);
+    }
+    if next.adj_name.is_empty() {
+        next.adj_name = name(next.adj_atom);
+    }
+    if prev.con_name.is_empty() {
+        prev.con_name = format!("-{}", next.adj_name);
+    }
+    if next.con_name.is_empty() {
+        next.con_name = format!("+{}", prev.adj_name);
+    }
+    println!("Polymer links: {}-{} (head atom {}) and {}-{} (tail atom {})",
+        prev.con_name, prev.adj_name, prev.adj_atom, next.adj_name, next.con_name, next.adj_atom);
+    Ok(())
+}
+
+// RANGE:RES:ATOM[:CON:ADJ], 未给出连接原子时由键确定
+fn parse_link(spec: &str, mol2: &MOL2) -> Result<Link, Error> {
+    let invalid = || Error::Invalid(format!("invalid link `{}`, expected RANGE:RES:ATOM[:CON:ADJ]", spec));
+    let fields: Vec<&str> = spec.split(':').collect();
+    if fields.len() != 3 && fields.len() != 5 {
+        return Err(invalid());
+    }
+    let atoms = atrange2atlist(fields[0])?;
+    if atoms.is_empty() || fields[1].is_empty() || fields[2].is_empty() {
+        return Err(invalid());
+    }
+    let (con_atom, adj_atom) = match fields.len() {
+        5 => (fields[3].parse().map_err(|_| invalid())?, fields[4].parse().map_err(|_| invalid())?),
+        _ => find_link(mol2, &atoms).ok_or_else(|| Error::Invalid(format!("atoms {} of link `{}` are not bonded to the rest of the molecule",
+            fields[0], spec)))?,
+    };
+    Ok(Link { atoms, con_atom, con_resname: fields[1].to_string(), con_name: fields[2].to_string(), adj_atom })
+}
+
+fn show(range: &str) -> &str {
+    match range.is_empty() {
+        true => "none",
+        false => range,
+    }
+}
+
+// 输入 none 表示没有相邻残基, 以覆盖检测结果
+fn none_to_empty(range: &str) -> &str {
+    match range.trim().eq_ignore_ascii_case("none") {
+        true => "",
+        false => range,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polymer_head_and_tail_out_of_range_are_errors() {
+        let mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        for (head, tail) in [(0, 9), (7, 37)] {
+            let mut options = ResidueOptions {
+                kind: Kind::Polymer,
+                prev: Some(Neighbour { atoms: (1..=6).collect(), con_atom: 1, con_name: String::new(), adj_atom: head, adj_name: String::new() }),
+                next: Some(Neighbour { atoms: (31..=36).collect(), con_atom: 31, con_name: String::new(), adj_atom: tail, adj_name: String::new() }),
+                ..Default::default()
+            };
+            assert!(matches!(polymer_link_names(&mut options, &mol2), Err(Error::Invalid(_))));
+        }
+    }
+}
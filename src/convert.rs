@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use gen_rtp::bonded::Ffbonded;
+use gen_rtp::error::Error;
+use gen_rtp::install::Package;
+use gen_rtp::itp::{forcefield_atomtypes, forcefield_defaults};
+use gen_rtp::mapping::Mapping;
+use gen_rtp::mol2::MOL2;
+use gen_rtp::output::{expand_template, Output};
+use gen_rtp::residue::{self, fix_atom_names, Kind, Residue, ResidueOptions};
+use gen_rtp::rtp::read_atom_types;
+use gen_rtp::utils::{self, ask};
+
+use crate::cli::{residue_options, Args};
+
+pub struct Converted {
+    pub mol2_file: String,
+    pub itp_file: String,
+    /// All molecules of the mol2, the first one renamed.
+    pub mols: Vec<MOL2>,
+    /// Old and new atom names of the renamed molecule.
+    pub mapping: Mapping,
+    pub options: ResidueOptions,
+    pub residue: Residue,
+}
+
+impl Converted {
+    /// Output with the input files protected.
+    pub fn output(&self, args: &Args) -> Output {
+        let mut output = Output::new(args.overwrite, args.dry_run);
+        output.protect(&self.mol2_file);
+        output.protect(&self.itp_file);
+        output
+    }
+
+    // 模板展开后放到 --out-dir, 未给出时放在 near 所在目录
+    fn out_path(&self, args: &Args, template: &Option<String>, default: &str, near: &str) -> String {
+        let name = expand_template(template.as_deref().unwrap_or(default), &utils::get_stemname(&self.mol2_file),
+            &utils::get_stemname(&self.itp_file), &self.residue.rtp.name);
+        let dir = match &args.out_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => utils::get_parent_path(near),
+        };
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    pub fn write_mol2(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        let out = self.out_path(args, &args.mol2_out, "{mol2}_renamed.mol2", &self.mol2_file);
+        output.write(&out, &MOL2::to_string_all(&self.mols))?;
+        if !output.dry_run {
+            println!("Finished writing renamed mol2 file to {}", out);
+        }
+        Ok(())
+    }
+
+    pub fn write_mapping(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        let out = self.out_path(args, &args.map_out, "{mol2}_names.csv", &self.mol2_file);
+        let content = match out.to_lowercase().ends_with(".json") {
+            true => self.mapping.to_json(),
+            false => self.mapping.to_csv(),
+        };
+        output.write(&out, &content)?;
+        if !output.dry_run {
+            println!("Finished writing atom name mapping to {}", out);
+        }
+        Ok(())
+    }
+
+    pub fn write_rtp(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        let out = self.out_path(args, &args.rtp_out, "{itp}.rtp", &self.itp_file);
+        output.write(&out, &self.residue.rtp_file(&self.options.ff).to_string())?;
+        if !output.dry_run {
+            println!("Finished writing rtp file to {}", out);
+        }
+        Ok(())
+    }
+
+    /// Writes the atomtypes as `ffnonbonded.itp` and `atomtypes.atp` snippets.
+    pub fn write_nonbonded(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        let nonbonded = self.residue.nonbonded_file()?;
+        let out = self.out_path(args, &args.nonbonded_out, "{itp}_ffnonbonded.itp", &self.itp_file);
+        output.write(&out, &nonbonded.to_string())?;
+        let atp = self.out_path(args, &args.atp_out, "{itp}_atomtypes.atp", &self.itp_file);
+        output.write(&atp, &nonbonded.atp().to_string())?;
+        if !output.dry_run {
+            println!("Finished writing atomtypes to {} and {}", out, atp);
+        }
+        Ok(())
+    }
+
+    /// Writes the bonded types moved out of the rtp as an `ffbonded.itp` snippet, with `--bonded-types`.
+    pub fn write_ffbonded(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        let bonded = match &self.residue.bonded {
+            Some(bonded) => bonded,
+            None => return Ok(()),
+        };
+        let out = self.out_path(args, &args.ffbonded_out, "{itp}_ffbonded.itp", &self.itp_file);
+        output.write(&out, &bonded.to_string())?;
+        if !output.dry_run {
+            println!("Finished writing bonded types to {}", out);
+        }
+        Ok(())
+    }
+
+    /// Writes the `specbond.dat` entries of the extra links, if there are any.
+    pub fn write_specbond(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        if self.residue.specbonds.is_empty() {
+            return Ok(());
+        }
+        let out = self.out_path(args, &args.specbond_out, "{itp}_specbond.dat", &self.itp_file);
+        output.write(&out, &self.residue.specbond_file().to_string())?;
+        if !output.dry_run {
+            println!("Finished writing specbond.dat entries to {}", out);
+        }
+        Ok(())
+    }
+
+    /// Installs the residue into the `--install` force field directory, if given.
+    pub fn install(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        let dir = match &args.install {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let package = Package {
+            ff: self.options.ff.to_string(),
+            rtps: self.residue.rtps(),
+            hdbs: self.residue.hdbs(),
+            nonbonded: self.residue.nonbonded_file()?,
+            bonded: self.residue.bonded.clone(),
+            restypes: self.residue.rtps().iter().map(|r| (r.name.to_string(), self.options.kind.residue_type().to_string())).collect(),
+        };
+        let manifest = Path::new(dir).join(format!("gen-rtp_{}.manifest", self.residue.rtp.name));
+        package.install(dir, args.install_rtp.as_deref().unwrap_or("aminoacids"))?
+            .write(output, manifest.to_str().unwrap())
+    }
+
+    pub fn write_hdb(&self, args: &Args, output: &Output) -> Result<(), Error> {
+        let out = self.out_path(args, &args.hdb_out, "{itp}.hdb", &self.itp_file);
+        output.write(&out, &self.residue.hdb_file().to_string())?;
+        if !output.dry_run {
+            println!("Finished writing hdb file to {}", out);
+        }
+        Ok(())
+    }
+}
+
+// 从一个 mol2 和 itp 生成单个残基的 rtp 和 hdb 条目, 未给出的选项交互询问
+pub fn convert(args: &Args, mol2_file: &str) -> Result<Converted, Error> {
+    println!("Reading mol2 file: {}", mol2_file);
+    let mut mols = MOL2::read_all(mol2_file)?;
+    if mols.len() > 1 {
+        println!("Found {} molecules in {}, using the first one.", mols.len(), mol2_file);
+    }
+    let mol2_stem = utils::get_stemname(mol2_file);
+    let parent_path = utils::get_parent_path(mol2_file);
+
+    let itp_file = parent_path.join(mol2_stem.to_string() + ".itp");
+    let inp = ask(&format!("Input the `itp` file name generated by Sobtop (default: {}):", itp_file.to_str().unwrap()),
+        args.itp.clone(), itp_file.to_str().unwrap().to_string(), args.interactive);
+    let itp_file = match inp.is_empty() {
+        true => utils::read_file(),
+        false => inp
+    };
+    // 输入排除列表
+    let mut options = residue_options(args, &mols[0], &itp_file)?;
+
+    println!("Fixing atom names...");
+    let original = mols[0].clone();
+    fix_atom_names(&mut mols[0], &options)?;
+    let mapping = Mapping::new(&original, &mols[0], &options);
+    println!("Finished fixing atom names.");
+
+    options.ff = ask("Force field of the rtp, amber or gromos (default: amber):", args.ff.clone(), "amber".to_string(), args.interactive);
+    if options.ff != "amber" && options.ff != "gromos" {
+        return Err(Error::Invalid(format!("invalid forcefield `{}`, only support amber and gromos.", options.ff)));
+    }
+    options.target_bonded = args.known_bonded.clone();
+    if let Some(dir) = args.target_ff.as_ref().or(args.install.as_ref()) {
+        options.target_defaults = Some(forcefield_defaults(dir)?);
+        options.target_atomtypes = forcefield_atomtypes(dir)?;
+        println!("Read {} atomtypes of the target force field {}", options.target_atomtypes.len(), dir);
+        if let Some(prefix) = &args.type_prefix {
+            options.type_prefix = prefix.to_string();
+        }
+        let target = Ffbonded::from(dir)?;
+        println!("Read {} bonded types of the target force field", target.len());
+        options.target_bonded.merge(target);
+        // 跨残基键项中的相邻原子取目标力场中代表残基的原子类型
+        options.junction_params = true;
+        if options.kind != Kind::Polymer {
+            let default = match options.kind {
+                Kind::Nucleic => "DA",
+                _ => "ALA",
+            };
+            let neighbour = args.neighbour_ref.as_deref().unwrap_or(default);
+            match read_atom_types(dir, neighbour)? {
+                Some(types) => options.neighbour_types = types,
+                None => println!("Warning: no residue {} in the rtp files of {}, the neighbour atoms of the junction terms have no atomtypes, see --neighbour-ref",
+                    neighbour, dir),
+            }
+        }
+    }
+    let residue = residue::convert(&mols[0], &itp_file, &options)?;
+    Ok(Converted { mol2_file: mol2_file.to_string(), itp_file, mols, mapping, options, residue })
+}
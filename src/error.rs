@@ -0,0 +1,89 @@
+use std::fmt::{self, Display};
+use std::io;
+use std::str::FromStr;
+
+/// Errors of reading, converting and writing residue files.
+#[derive(Debug)]
+pub enum Error {
+    /// A file could not be read or written.
+    Io { file: String, source: io::Error },
+    /// A malformed line of an input file.
+    Parse {
+        file: String,
+        /// 1-based line number in `file`.
+        line: usize,
+        /// Section the line belongs to, e.g. `@<TRIPOS>ATOM` or `[ dihedrals ]`.
+        section: String,
+        /// The offending token, or the whole line if a token is missing.
+        token: String,
+        message: String,
+    },
+    /// A required section is missing from an input file.
+    MissingSection { file: String, section: String },
+    /// Invalid options or inconsistent input files.
+    Invalid(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { file, source } => write!(f, "{}: {}", file, source),
+            Error::Parse { file, line, section, token, message } => {
+                write!(f, "{}:{}: in {}: {} (at `{}`)", file, line, section, message, token)
+            },
+            Error::MissingSection { file, section } => write!(f, "{}: missing section {}", file, section),
+            Error::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    pub fn io(file: &str, source: io::Error) -> Error {
+        Error::Io { file: file.to_string(), source }
+    }
+}
+
+/// Where a line being parsed comes from, used to build [`Error::Parse`].
+#[derive(Clone, Debug)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub section: &'a str,
+}
+
+impl Location<'_> {
+    pub fn error(&self, token: &str, message: &str) -> Error {
+        Error::Parse {
+            file: self.file.to_string(),
+            line: self.line,
+            section: self.section.to_string(),
+            token: token.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Parses field `id` of a whitespace-split line, reporting `what` if it is missing or malformed.
+    pub fn field<T: FromStr>(&self, fields: &[&str], id: usize, what: &str) -> Result<T> {
+        let token = fields.get(id).ok_or_else(|| self.error(&fields.join(" "), &format!("missing {}", what)))?;
+        token.parse().map_err(|_| self.error(token, &format!("invalid {}", what)))
+    }
+
+    /// Like [`Location::field`], but a missing field is `None`.
+    pub fn opt_field<T: FromStr>(&self, fields: &[&str], id: usize, what: &str) -> Result<Option<T>> {
+        match fields.get(id) {
+            Some(_) => self.field(fields, id, what).map(Some),
+            None => Ok(None),
+        }
+    }
+}
@@ -1,5 +1,22 @@
+use std::fmt::{self, Display};
+
 use crate::mol2::MOL2;
 
+/// An `hdb` file with one entry per residue.
+#[derive(Clone, Debug, Default)]
+pub struct Hdb {
+    pub residues: Vec<HdbResidue>,
+}
+
+/// The hydrogen database entry of one residue.
+#[derive(Clone, Debug)]
+pub struct HdbResidue {
+    pub name: String,
+    pub items: Vec<HDBItem>,
+}
+
+/// One line of an hdb entry: number of H, addition type, H name and the reference heavy atoms.
+#[derive(Clone, Debug)]
 pub struct HDBItem {
     pub h_num: i32,
     pub h_type: i32,
@@ -11,6 +28,49 @@ impl HDBItem {
     pub fn new(h_num: i32, h_type: i32, h_atom: String, heavy_atoms: Vec<String>) -> HDBItem {
         HDBItem{h_num, h_type, h_atom, heavy_atoms}
     }
+
+    /// Names of the hydrogens pdb2gmx adds for this line: the H name, numbered from 1 if there are several.
+    pub fn h_names(&self) -> Vec<String> {
+        match self.h_num {
+            1 => vec![self.h_atom.to_string()],
+            n => (1..=n).map(|i| format!("{}{}", self.h_atom, i)).collect(),
+        }
+    }
+}
+
+impl HdbResidue {
+    /// A terminal variant named `name`: items involving atoms that satisfy `removed` are dropped and `item` is added.
+    pub fn terminal_variant(&self, name: &str, removed: impl Fn(&str) -> bool, item: HDBItem) -> HdbResidue {
+        let mut items: Vec<HDBItem> = self.items.iter()
+            .filter(|i| !removed(&i.h_atom) && !i.heavy_atoms.iter().any(|a| removed(a)))
+            .cloned()
+            .collect();
+        items.push(item);
+        HdbResidue { name: name.to_string(), items }
+    }
+}
+
+impl Display for Hdb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for r in &self.residues {
+            write!(f, "{}", r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for HdbResidue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:5}    {}", self.name, self.items.len())?;
+        for item in &self.items {
+            write!(f, "{:<7}{:<7}{:7}", item.h_num, item.h_type, item.h_atom)?;
+            for ha in &item.heavy_atoms {
+                write!(f, "{:7}", ha)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
 }
 
 pub fn get_htype_from_heavy_atom(mol2: &MOL2, ref_id: usize) -> i32 {
@@ -46,16 +106,16 @@ pub fn get_adj_heavy_id(mol2: &MOL2, ref_id: usize) -> Vec<usize> {
     filter_heavy(mol2, &adj)
 }
 
-fn filter_h(mol2: &MOL2, atoms_id: &Vec<usize>) -> Vec<usize> {
+fn filter_h(mol2: &MOL2, atoms_id: &[usize]) -> Vec<usize> {
     atoms_id.iter()
-        .filter(|&&a| mol2.atoms[a as usize - 1].element.eq("H"))
+        .filter(|&&a| mol2.atoms[a - 1].element.eq("H"))
         .cloned()
         .collect()
 }
 
-fn filter_heavy(mol2: &MOL2, atoms_id: &Vec<usize>) -> Vec<usize> {
+fn filter_heavy(mol2: &MOL2, atoms_id: &[usize]) -> Vec<usize> {
     atoms_id.iter()
-        .filter(|&&a| mol2.atoms[a as usize - 1].element.ne("H"))
+        .filter(|&&a| mol2.atoms[a - 1].element.ne("H"))
         .cloned()
         .collect()
 }
\ No newline at end of file
@@ -0,0 +1,529 @@
+use std::fmt::{self, Display};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::bonded::Ffbonded;
+use crate::error::{Error, Location, Result};
+use crate::hdb::HdbResidue;
+use crate::itp::{atomtypes_title, TopolAtomtype};
+use crate::nonbonded::{Atp, Nonbonded};
+use crate::output::Output;
+use crate::preprocess::SourceLine;
+use crate::rtp::{bondedtypes, Rtp, RtpResidue};
+
+/// Residues to install into a GROMACS force field directory (`*.ff`).
+#[derive(Clone, Debug)]
+pub struct Package {
+    /// Force field family of the rtp entries, `amber` or `gromos`.
+    pub ff: String,
+    pub rtps: Vec<RtpResidue>,
+    pub hdbs: Vec<HdbResidue>,
+    /// Atomtypes for `ffnonbonded.itp` and `atomtypes.atp`.
+    pub nonbonded: Nonbonded,
+    /// Bonded types for `ffbonded.itp`, if the parameters were moved out of the rtp entries.
+    pub bonded: Option<Ffbonded>,
+    /// Residue names with their type in `residuetypes.dat` (Protein, DNA or Other).
+    pub restypes: Vec<(String, String)>,
+}
+
+/// One edit of a force field file: `old` replaced by `new`, `old` being empty for added lines.
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// Path relative to the force field directory.
+    pub file: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// The edits made by an installation, kept in the force field directory so that they can be reverted.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub residues: Vec<String>,
+    pub changes: Vec<Change>,
+}
+
+/// The edited force field files of a [`Package`], written by [`Installation::write`].
+#[derive(Clone, Debug)]
+pub struct Installation {
+    pub dir: PathBuf,
+    /// Edited files relative to `dir`, with their new content.
+    pub files: Vec<(String, String)>,
+    pub manifest: Manifest,
+}
+
+// 参数相同的判据: 相对误差, 力场文件中的数值位数有限
+const TOLERANCE: f64 = 1e-3;
+
+fn same(a: f64, b: f64) -> bool {
+    (a - b).abs() <= TOLERANCE * a.abs().max(b.abs())
+}
+
+impl Package {
+    /// Edits the files of the force field directory `dir`: the entries go to `<rtp>.rtp` and `<rtp>.hdb`
+    /// (replacing entries of the same name), new atomtypes to `ffnonbonded.itp` and `atomtypes.atp`,
+    /// new bonded types to `ffbonded.itp` and the residue types to the `residuetypes.dat` of `dir` or of its parent directory.
+    pub fn install(&self, dir: &str, rtp: &str) -> Result<Installation> {
+        if !Path::new(dir).is_dir() {
+            return Err(Error::Invalid(format!("force field directory {} does not exist", dir)));
+        }
+        let residues = self.rtps.iter().map(|r| r.name.to_string()).collect();
+        let mut inst = Installation { dir: PathBuf::from(dir), files: vec![], manifest: Manifest { residues, changes: vec![] } };
+        self.install_rtp(&mut inst, &format!("{}.rtp", rtp))?;
+        self.install_hdb(&mut inst, &format!("{}.hdb", rtp))?;
+        self.install_nonbonded(&mut inst, "ffnonbonded.itp")?;
+        self.install_atp(&mut inst, "atomtypes.atp")?;
+        self.install_bonded(&mut inst, "ffbonded.itp")?;
+        self.install_restypes(&mut inst)?;
+        Ok(inst)
+    }
+
+    fn install_rtp(&self, inst: &mut Installation, file: &str) -> Result<()> {
+        let mut content = match inst.read(file)? {
+            Some(c) => c,
+            None => {
+                println!("Creating {} with the {} [ bondedtypes ]", file, self.ff);
+                return inst.create(file, Rtp::new(&self.ff, self.rtps.clone()).to_string());
+            },
+        };
+        // 条目沿用力场的 [ bondedtypes ], 函数类型不同时提醒
+        let ours = bondedtypes(&self.ff);
+        if let Some(theirs) = rtp_bondedtypes(&content).filter(|t| t != &ours) {
+            println!("Warning: [ bondedtypes ] of {} are {:?} but {:?} for the {} entries, the installed terms take the functions of the force field",
+                file, theirs, ours, self.ff);
+        }
+        for r in &self.rtps {
+            inst.put_entry(file, &mut content, &r.name, &r.to_string(), rtp_entry, true);
+        }
+        inst.set(file, content);
+        Ok(())
+    }
+
+    fn install_hdb(&self, inst: &mut Installation, file: &str) -> Result<()> {
+        let mut content = match inst.read(file)? {
+            Some(c) => c,
+            None => {
+                println!("Creating {}", file);
+                return inst.create(file, self.hdbs.iter().map(|h| h.to_string()).collect());
+            },
+        };
+        for h in &self.hdbs {
+            inst.put_entry(file, &mut content, &h.name, &h.to_string(), hdb_entry, false);
+        }
+        inst.set(file, content);
+        Ok(())
+    }
+
+    fn install_nonbonded(&self, inst: &mut Installation, file: &str) -> Result<()> {
+        let mut content = match inst.read(file)? {
+            Some(c) => c,
+            None => {
+                println!("Note: creating {}, #include it in forcefield.itp", file);
+                return inst.create(file, self.nonbonded.to_string());
+            },
+        };
+        let path = inst.dir.join(file).to_string_lossy().to_string();
+        let (existing, at) = atomtypes_section(&content, &path)?;
+        // 与已有条目重复的不再添加
+        let mut added = vec![];
+        let mut present = vec![];
+        for at in &self.nonbonded.atomtypes {
+            match existing.iter().find(|e| e.name == at.name) {
+                Some(e) if e.same_params(at) => present.push(at.name.as_str()),
+                Some(_) => println!("Warning: atomtype {} is already in {} with other parameters, kept the existing one", at.name, file),
+                None => added.push(at),
+            }
+        }
+        if !present.is_empty() {
+            println!("Note: already in {} with the same parameters: atomtypes {}", file, present.join(", "));
+        }
+        if added.is_empty() {
+            return Ok(());
+        }
+        let bond_type = match existing.is_empty() {
+            true => added.iter().any(|at| at.bond_type.is_some()),
+            false => existing.iter().any(|at| at.bond_type.is_some()),
+        };
+        let c6_c12 = self.nonbonded.defaults.as_ref().is_some_and(|d| d.comb_rule == 1);
+        let mut lines: String = added.iter().map(|at| at.line(bond_type, c6_c12) + "\n").collect();
+        let pos = match at {
+            Some(pos) => pos,
+            None => {
+                lines = format!("\n[ atomtypes ]\n{}\n{}", atomtypes_title(bond_type, c6_c12), lines);
+                content.len()
+            },
+        };
+        if !content[..pos].is_empty() && !content[..pos].ends_with('\n') {
+            lines.insert(0, '\n');
+        }
+        let names: Vec<&str> = added.iter().map(|at| at.name.as_str()).collect();
+        println!("Adding atomtypes {} to {}", names.join(", "), file);
+        content.insert_str(pos, &lines);
+        inst.change(file, String::new(), lines);
+        inst.set(file, content);
+        Ok(())
+    }
+
+    fn install_atp(&self, inst: &mut Installation, file: &str) -> Result<()> {
+        let mut content = match inst.read(file)? {
+            Some(c) => c,
+            None => {
+                println!("Creating {}", file);
+                return inst.create(file, self.nonbonded.atp().to_string());
+            },
+        };
+        let existing: Vec<(&str, Option<f64>)> = content.lines()
+            .map(|l| l.split(';').next().unwrap().split_whitespace().collect::<Vec<&str>>())
+            .filter(|f| !f.is_empty())
+            .map(|f| (f[0], f.get(1).and_then(|m| m.parse().ok())))
+            .collect();
+        let mut added = vec![];
+        for at in &self.nonbonded.atomtypes {
+            match existing.iter().find(|(name, _)| name == &at.name) {
+                Some((_, Some(mass))) if !same(*mass, at.mass) => {
+                    println!("Warning: atomtype {} is already in {} with mass {}, kept the existing one", at.name, file, mass);
+                },
+                Some(_) => (),
+                None => added.push(at.clone()),
+            }
+        }
+        if added.is_empty() {
+            return Ok(());
+        }
+        let mut lines = Atp { atomtypes: added }.to_string();
+        if !content.is_empty() && !content.ends_with('\n') {
+            lines.insert(0, '\n');
+        }
+        content.push_str(&lines);
+        inst.change(file, String::new(), lines);
+        inst.set(file, content);
+        Ok(())
+    }
+
+    fn install_bonded(&self, inst: &mut Installation, file: &str) -> Result<()> {
+        let bonded = match &self.bonded {
+            Some(b) if !b.is_empty() => b,
+            _ => return Ok(()),
+        };
+        let mut content = match inst.read(file)? {
+            Some(c) => c,
+            None => {
+                println!("Note: creating {}, #include it in forcefield.itp", file);
+                return inst.create(file, bonded.to_string());
+            },
+        };
+        let path = inst.dir.join(file).to_string_lossy().to_string();
+        let lines: Vec<SourceLine> = content.lines().enumerate()
+            .map(|(i, l)| SourceLine { file: path.to_string(), line: i + 1, text: l.to_string() })
+            .collect();
+        let mut existing = Ffbonded::default();
+        existing.read(&lines)?;
+        let (added, same, other) = bonded.missing(&existing);
+        if !same.is_empty() {
+            println!("Note: already in {} with the same parameters: bonded types {}", file, same.join(", "));
+        }
+        if !other.is_empty() {
+            println!("Warning: bonded types {} are already in {} with other parameters, kept the existing ones", other.join(", "), file);
+        }
+        if added.is_empty() {
+            return Ok(());
+        }
+        let mut lines = added.sections();
+        if !content.is_empty() && !content.ends_with('\n') {
+            lines.insert(0, '\n');
+        }
+        println!("Adding {} bonded types to {}", added.len(), file);
+        content.push_str(&lines);
+        inst.change(file, String::new(), lines);
+        inst.set(file, content);
+        Ok(())
+    }
+
+    // residuetypes.dat 一般在 top 目录, 自定义力场放在工作目录时与之同级
+    fn install_restypes(&self, inst: &mut Installation) -> Result<()> {
+        let file = match ["residuetypes.dat", "../residuetypes.dat"].into_iter().find(|f| inst.dir.join(f).is_file()) {
+            Some(f) => f,
+            None => {
+                let lines: Vec<String> = self.restypes.iter().map(|(r, t)| format!("{} {}", r, t)).collect();
+                println!("Note: no residuetypes.dat in {} or its parent directory, add `{}` to the residuetypes.dat used by pdb2gmx",
+                    inst.dir.display(), lines.join("`, `"));
+                return Ok(());
+            },
+        };
+        let mut content = inst.read(file)?.unwrap_or_default();
+        for (name, restype) in &self.restypes {
+            let line = format!("{}\t{}\n", name, restype);
+            let found = lines_of(&content).into_iter().find(|r| {
+                content[r.clone()].split_whitespace().next().is_some_and(|n| n.eq_ignore_ascii_case(name))
+            });
+            match found {
+                Some(r) if content[r.clone()].split_whitespace().nth(1) == Some(restype) => (),
+                Some(r) => {
+                    let old = content[r.clone()].to_string();
+                    println!("Changing the type of {} in {} to {}", name, file, restype);
+                    content.replace_range(r, &line);
+                    inst.change(file, old, line);
+                },
+                None => {
+                    let line = match content.is_empty() || content.ends_with('\n') {
+                        true => line,
+                        false => format!("\n{}", line),
+                    };
+                    content.push_str(&line);
+                    inst.change(file, String::new(), line);
+                },
+            }
+        }
+        inst.set(file, content);
+        Ok(())
+    }
+}
+
+impl Installation {
+    fn read(&self, file: &str) -> Result<Option<String>> {
+        if let Some((_, content)) = self.files.iter().find(|(f, _)| f == file) {
+            return Ok(Some(content.to_string()));
+        }
+        let path = self.dir.join(file);
+        match path.is_file() {
+            true => fs::read_to_string(&path).map(Some).map_err(|e| Error::io(&path.to_string_lossy(), e)),
+            false => Ok(None),
+        }
+    }
+
+    fn set(&mut self, file: &str, content: String) {
+        match self.files.iter_mut().find(|(f, _)| f == file) {
+            Some((_, c)) => *c = content,
+            None => self.files.push((file.to_string(), content)),
+        }
+    }
+
+    fn create(&mut self, file: &str, content: String) -> Result<()> {
+        self.change(file, String::new(), content.to_string());
+        self.set(file, content);
+        Ok(())
+    }
+
+    fn change(&mut self, file: &str, old: String, new: String) {
+        self.manifest.changes.push(Change { file: file.to_string(), old, new });
+    }
+
+    // 替换同名条目, 没有则追加到文件末尾
+    fn put_entry(&mut self, file: &str, content: &mut String, name: &str, text: &str, header: fn(&str) -> Option<String>, blank_line: bool) {
+        match entries(content, header).into_iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some((_, r)) if content[r.clone()] == *text => println!("Note: {} in {} is up to date", name, file),
+            Some((_, r)) => {
+                println!("Replacing {} in {}", name, file);
+                let old = content[r.clone()].to_string();
+                content.replace_range(r, text);
+                self.change(file, old, text.to_string());
+            },
+            None => {
+                println!("Adding {} to {}", name, file);
+                // rtp 条目间空一行
+                let sep = if content.is_empty() || content.ends_with("\n\n") || (content.ends_with('\n') && !blank_line) {
+                    ""
+                } else if content.ends_with('\n') || !blank_line {
+                    "\n"
+                } else {
+                    "\n\n"
+                };
+                let new = format!("{}{}", sep, text);
+                content.push_str(&new);
+                self.change(file, String::new(), new);
+            },
+        }
+    }
+
+    /// Writes the edited files (only reporting them if `output.dry_run`) and the manifest to `manifest`.
+    pub fn write(&self, output: &Output, manifest: &str) -> Result<()> {
+        if self.manifest.changes.is_empty() {
+            println!("Nothing to install, {} already holds the residues", self.dir.display());
+            return Ok(());
+        }
+        for (file, content) in &self.files {
+            let path = self.dir.join(file);
+            let n = self.manifest.changes.iter().filter(|c| &c.file == file).count();
+            if n == 0 {
+                continue;
+            }
+            if output.dry_run {
+                println!("[dry-run] Would update {} ({} changes)", path.display(), n);
+                continue;
+            }
+            fs::write(&path, content).map_err(|e| Error::io(&path.to_string_lossy(), e))?;
+            println!("Updated {}", path.display());
+        }
+        output.write(manifest, &self.manifest.to_string())?;
+        if !output.dry_run {
+            println!("Installed {} into {}, revert with `gen-rtp uninstall {}`", self.manifest.residues.join(", "), self.dir.display(), manifest);
+        }
+        Ok(())
+    }
+}
+
+impl Manifest {
+    pub fn from(file: &str) -> Result<Manifest> {
+        let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let mut manifest = Manifest::default();
+        for (ln, line) in content.lines().enumerate() {
+            let loc = Location { file, line: ln + 1, section: "manifest" };
+            // 以首字符区分: @ 文件, - 原有行, + 新行
+            match (line.get(..1), manifest.changes.last_mut()) {
+                (Some("@"), _) => manifest.changes.push(Change { file: line[1..].trim().to_string(), old: String::new(), new: String::new() }),
+                (Some("-"), Some(c)) => c.old.push_str(&format!("{}\n", &line[1..])),
+                (Some("+"), Some(c)) => c.new.push_str(&format!("{}\n", &line[1..])),
+                (Some(";"), None) | (None, None) => (),
+                (_, None) if line.starts_with("residues") => {
+                    manifest.residues = line["residues".len()..].split_whitespace().map(|s| s.to_string()).collect();
+                },
+                _ => return Err(loc.error(line, "expected `@ FILE`, `-LINE` or `+LINE`")),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Reverts the changes, last first, in the files under `dir`. Returns the number of changes that
+    /// could not be reverted because their lines were edited since.
+    pub fn revert(&self, dir: &Path, dry_run: bool) -> Result<usize> {
+        let mut files: Vec<(String, String)> = vec![];
+        let mut failed = 0;
+        for c in self.changes.iter().rev() {
+            let path = dir.join(&c.file);
+            let content = match files.iter_mut().find(|(f, _)| f == &c.file) {
+                Some((_, content)) => content,
+                None => {
+                    let content = match path.is_file() {
+                        true => fs::read_to_string(&path).map_err(|e| Error::io(&path.to_string_lossy(), e))?,
+                        false => String::new(),
+                    };
+                    files.push((c.file.to_string(), content));
+                    &mut files.last_mut().unwrap().1
+                },
+            };
+            match content.find(&c.new) {
+                Some(pos) => content.replace_range(pos..pos + c.new.len(), &c.old),
+                None => {
+                    println!("Warning: lines installed in {} have been changed since, skipped:\n{}", path.display(), c.new.trim_end());
+                    failed += 1;
+                },
+            }
+        }
+        for (file, content) in &files {
+            let path = dir.join(file);
+            let shown = path.to_string_lossy().to_string();
+            match (dry_run, content.is_empty()) {
+                (true, true) => println!("[dry-run] Would remove {}", shown),
+                (true, false) => println!("[dry-run] Would update {}", shown),
+                // 安装时新建的文件整个删除
+                (false, true) => {
+                    fs::remove_file(&path).map_err(|e| Error::io(&shown, e))?;
+                    println!("Removed {}", shown);
+                },
+                (false, false) => {
+                    fs::write(&path, content).map_err(|e| Error::io(&shown, e))?;
+                    println!("Updated {}", shown);
+                },
+            }
+        }
+        Ok(failed)
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "; Installed by gen-rtp (https://github.com/supernova4869/gen-rtp), revert with `gen-rtp uninstall <this file>`")?;
+        writeln!(f, "; Each `@ FILE` is followed by the replaced lines (-) and the installed lines (+)")?;
+        writeln!(f, "residues {}", self.residues.join(" "))?;
+        for c in &self.changes {
+            writeln!(f, "@ {}", c.file)?;
+            for line in c.old.lines() {
+                writeln!(f, "-{}", line)?;
+            }
+            for line in c.new.lines() {
+                writeln!(f, "+{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 含换行符的各行的字节范围
+fn lines_of(content: &str) -> Vec<Range<usize>> {
+    let mut start = 0;
+    content.split_inclusive('\n').map(|l| {
+        start += l.len();
+        start - l.len()..start
+    }).collect()
+}
+
+// 以 header 识别条目首行, 条目到下一条目首行为止
+fn entries(content: &str, header: fn(&str) -> Option<String>) -> Vec<(String, Range<usize>)> {
+    let mut entries: Vec<(String, Range<usize>)> = vec![];
+    for r in lines_of(content) {
+        if let Some(name) = header(&content[r.clone()]) {
+            entries.push((name, r.start..content.len()));
+            let n = entries.len();
+            if n > 1 {
+                entries[n - 2].1.end = r.start;
+            }
+        }
+    }
+    entries
+}
+
+// rtp 中 [ RES ] 开始一个条目, [ atoms ] 等为其中的小节
+fn rtp_entry(line: &str) -> Option<String> {
+    let name = line.split(';').next()?.trim().strip_prefix('[')?.strip_suffix(']')?.trim();
+    match name {
+        "atoms" | "bonds" | "angles" | "dihedrals" | "impropers" | "cmap" | "exclusions" => None,
+        _ => Some(name.to_string()),
+    }
+}
+
+// hdb 中条目首行为残基名和氢原子行数, 其余行以数字开头
+fn hdb_entry(line: &str) -> Option<String> {
+    let fields: Vec<&str> = line.split(';').next()?.split_whitespace().collect();
+    match fields.as_slice() {
+        [name, n, ..] if name.parse::<f64>().is_err() && n.parse::<usize>().is_ok() => Some(name.to_string()),
+        _ => None,
+    }
+}
+
+fn rtp_bondedtypes(content: &str) -> Option<[i32; 4]> {
+    let mut lines = content.lines()
+        .map(|l| l.split(';').next().unwrap().trim())
+        .skip_while(|l| l.replace(' ', "") != "[bondedtypes]")
+        .skip(1)
+        .filter(|l| !l.is_empty());
+    let values: Vec<i32> = lines.next()?.split_whitespace().take(4).filter_map(|v| v.parse().ok()).collect();
+    values.try_into().ok()
+}
+
+// [ atomtypes ] 中已有的原子类型, 及第一个 [ atomtypes ] 最后一行之后的位置
+fn atomtypes_section(content: &str, file: &str) -> Result<(Vec<TopolAtomtype>, Option<usize>)> {
+    let mut existing = vec![];
+    let mut section = String::new();
+    let mut pos: Option<usize> = None;
+    let mut first_done = false;
+    for (ln, r) in lines_of(content).into_iter().enumerate() {
+        let code = content[r.clone()].split(';').next().unwrap().trim();
+        if let Some(name) = code.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            first_done = first_done || (section == "atomtypes" && pos.is_some());
+            section = name.trim().to_string();
+            if section == "atomtypes" && !first_done && pos.is_none() {
+                pos = Some(r.end);
+            }
+            continue;
+        }
+        if section != "atomtypes" || code.is_empty() || code.starts_with('#') {
+            continue;
+        }
+        let loc = Location { file, line: ln + 1, section: "[ atomtypes ]" };
+        existing.push(TopolAtomtype::from(code, &loc)?);
+        if !first_done {
+            pos = Some(r.end);
+        }
+    }
+    Ok((existing, pos))
+}
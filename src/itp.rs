@@ -1,25 +1,51 @@
 use regex::Regex;
 use std::hash::{Hash, Hasher};
-use std::{collections::HashSet, fs, io::Write};
-use std::str::FromStr;
+use std::path::Path;
+use std::{collections::HashSet, fs};
 use std::fmt::{self, Debug, Display};
 
+use crate::bonded::{class, find, same_params, BondedType, Ffbonded};
+use crate::charge::renumber_cgnr;
+use crate::error::{Error, Location, Result};
 use crate::mol2::MOL2;
+use crate::preprocess::{Preprocessor, SourceLine};
+use crate::residue::{Kind, ResidueOptions};
+use crate::rtp::{bondedtypes, RtpAtom, RtpInteraction, RtpResidue};
+use crate::utils::atomic_number;
 
+/// The `[ defaults ]` of a topology or force field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Defaults {
+    /// 1 for Lennard-Jones, 2 for Buckingham.
+    pub nbfunc: i32,
+    /// 1: the atomtypes give C6 and C12; 2 (Lorentz-Berthelot) and 3 (geometric): sigma and epsilon.
+    pub comb_rule: i32,
+    pub gen_pairs: String,
+    pub fudge_lj: f64,
+    pub fudge_qq: f64,
+}
+
+/// A line of `[ atomtypes ]`. `sigma` and `epsilon` hold C6 and C12 under combination rule 1.
+#[derive(Clone, Debug)]
 pub struct TopolAtomtype {
-    name: String,
-    mass: f64,
-    charge: f64,
-    ptype: String,
-    sigma: f64,
-    epsilon: f64,
+    pub name: String,
+    /// The bonded type of OPLS-AA and acpype force fields, if given.
+    pub bond_type: Option<String>,
+    pub at_num: Option<i32>,
+    pub mass: f64,
+    pub charge: f64,
+    pub ptype: String,
+    pub sigma: f64,
+    pub epsilon: f64,
 }
 
 impl PartialEq for TopolAtomtype {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name 
-            && (self.mass - other.mass).abs() < std::f64::EPSILON
-            && (self.charge - other.charge).abs() < std::f64::EPSILON
+            && self.bond_type == other.bond_type
+            && self.at_num == other.at_num
+            && (self.mass - other.mass).abs() < f64::EPSILON
+            && (self.charge - other.charge).abs() < f64::EPSILON
             && self.ptype == other.ptype 
             && self.sigma == other.sigma 
             && self.epsilon == other.epsilon 
@@ -31,6 +57,8 @@ impl Eq for TopolAtomtype {}
 impl Hash for TopolAtomtype {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.name.hash(state);
+        self.bond_type.hash(state);
+        self.at_num.hash(state);
         self.mass.to_bits().hash(state);
         self.charge.to_bits().hash(state);
         self.ptype.hash(state);
@@ -39,6 +67,7 @@ impl Hash for TopolAtomtype {
     }
 }
 
+/// A line of `[ atoms ]`, with the atom name already replaced by the fixed mol2 name.
 #[derive(Clone, Debug)]
 pub struct TopolAtom {
     pub nr: usize,
@@ -97,9 +126,17 @@ pub struct TopolExclusion {
     ex_atoms: Vec<TopolAtom>,
 }
 
+/// A molecule topology read from a GROMACS `itp`.
 pub struct Topol {
-    atomtypes: HashSet<TopolAtomtype>,
-    moleculetype: String,
+    /// `[ defaults ]` of the itp or of the top next to it, if any.
+    pub defaults: Option<Defaults>,
+    pub atomtypes: HashSet<TopolAtomtype>,
+    /// Names of the atomtypes that are those of the target force field, see [`Topol::match_atomtypes`].
+    pub target_types: Vec<String>,
+    /// Name of the `[ moleculetype ]` read from the itp.
+    pub moleculetype: String,
+    /// Residue name of the rtp entry, taken from the mol2.
+    pub resname: String,
     nrexcl: i32,
     pub atoms: Vec<TopolAtom>,
     bonds: Vec<TopolBond>,
@@ -110,31 +147,22 @@ pub struct Topol {
     exclusions: Vec<TopolExclusion>,
 }
 
-fn get_atom_from_nr(atoms: &Vec<TopolAtom>, nr: usize) -> &TopolAtom {
-    atoms.iter().find(|&a| a.nr == nr).unwrap()
+fn get_atom_from_nr<'a>(atoms: &'a [TopolAtom], nr: usize, loc: &Location) -> Result<&'a TopolAtom> {
+    atoms.iter().find(|&a| a.nr == nr).ok_or_else(|| loc.error(&nr.to_string(), "undefined atom"))
 }
 
 impl Topol {
-    pub fn from(file: &str, mol2: &MOL2, 
-        exclude_n: &Vec<usize>, exclude_c: &Vec<usize>,
-        atom_n: Option<usize>, atom_c: Option<usize>,
-        n_name: &Option<String>, c_name: &Option<String>,
-        atom_adjn: Option<usize>, atom_adjc: Option<usize>,
-        adjn_name: &Option<String>, adjc_name: &Option<String>,
-    ) -> Topol {
+    /// Reads `file`, naming the atoms after the (fixed) `mol2` and the connection atoms after `options`.
+    pub fn from(file: &str, mol2: &MOL2, options: &ResidueOptions) -> Result<Topol> {
         println!("Reading topology of {}...", file);
-        let lines = fs::read_to_string(file).unwrap();
-        let lines: Vec<&str> = lines.split("\n").collect();
-        let re = Regex::new(r"\s*;.*").unwrap();
-        let lines: Vec<String> = lines.iter()
-            .map(|&s| re.replace(s, "").to_string())
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
+        let (mol, lines) = molecule_lines(file, &options.preprocessor, options.molecule.as_deref(), Some(mol2.atoms.len()))?;
+        if mol != mol2.resname {
+            println!("Note: the residue is named {} after the mol2, not {} as the moleculetype of the itp", mol2.resname, mol);
+        }
 
         // topol items
+        let mut defaults: Option<Defaults> = None;
         let mut attypes: HashSet<TopolAtomtype> = HashSet::new();
-        let mol = mol2.mol.sys_name.to_string();
         let mut nrexcl = 3;
         let mut atoms: Vec<TopolAtom> = vec![];
         let mut bonds: Vec<TopolBond> = vec![];
@@ -144,126 +172,241 @@ impl Topol {
         let mut dihedrals: Vec<TopolDihedral> = vec![];
         let mut exclusions: Vec<TopolExclusion> = vec![];
 
-        let mut cur_item = "";
+        let mut cur_item = String::new();
         let re = Regex::new(r".*\[(.*)].*").unwrap();
 
-        for line in &lines {
-            if re.is_match(line) {
-                let caps = re.captures(line).unwrap();
-                cur_item = caps.get(1).unwrap().as_str().trim();
+        for SourceLine { file, line: ln, text: line } in &lines {
+            if let Some(caps) = re.captures(line) {
+                cur_item = caps.get(1).unwrap().as_str().trim().to_string();
+                continue;
             }
-            else {
-                if cur_item.eq("atomtypes") {
-                    attypes.insert(TopolAtomtype::from(line));
-                } else if cur_item == "moleculetype" {
+            let section = format!("[ {} ]", cur_item);
+            let loc = Location { file, line: *ln, section: &section };
+            match cur_item.as_str() {
+                "defaults" => defaults = Some(Defaults::from(line, &loc)?),
+                "atomtypes" => {
+                    attypes.insert(TopolAtomtype::from(line, &loc)?);
+                },
+                "moleculetype" => {
                     let paras: Vec<&str> = line.split_whitespace().collect();
-                    // mol = paras[0].to_string();
-                    nrexcl = paras[1].parse().unwrap();
-                } else if cur_item == "atoms" {
+                    nrexcl = loc.field(&paras, 1, "nrexcl")?;
+                },
+                "atoms" => {
                     // Fuck, I must change the atom name here
-                    let mut a = TopolAtom::from(line);
-                    a.atom = mol2.atoms[a.nr - 1].atom_name.to_string();
-                    if let Some(atom_n) = atom_n {
-                        if a.nr == atom_n {
-                            a.atom = n_name.as_ref().unwrap().to_string();
+                    let mut a = TopolAtom::from(line, &loc)?;
+                    a.atom = match a.nr.checked_sub(1).and_then(|i| mol2.atoms.get(i)) {
+                        Some(m) => m.atom_name.to_string(),
+                        None => return Err(loc.error(&a.nr.to_string(),
+                            &format!("atom not found in the mol2 file, which has {} atoms", mol2.atoms.len()))),
+                    };
+                    for n in options.prev.iter().chain(options.next.iter()) {
+                        if a.nr == n.con_atom {
+                            a.atom = n.con_name.to_string();
                         }
-                    }
-                    if let Some(atom_c) = atom_c {
-                        if a.nr == atom_c {
-                            a.atom = c_name.as_ref().unwrap().to_string();
+                        if a.nr == n.adj_atom {
+                            a.atom = n.adj_name.to_string();
                         }
                     }
-                    if let Some(atom_adjn) = atom_adjn {
-                        if a.nr == atom_adjn {
-                            a.atom = adjn_name.as_ref().unwrap().to_string();
-                        }
-                    }
-                    if let Some(atom_adjc) = atom_adjc {
-                        if a.nr == atom_adjc {
-                            a.atom = adjc_name.as_ref().unwrap().to_string();
-                        }
-                    }
-                    if exclude_n.contains(&a.nr) {
+                    if options.prev_atoms().contains(&a.nr) {
                         a.atom = format!("-{}", a.atom.trim_start_matches('-'));
                     }
-                    if exclude_c.contains(&a.nr) {
+                    if options.next_atoms().contains(&a.nr) {
                         a.atom = format!("+{}", a.atom.trim_start_matches('+'));
                     }
                     atoms.push(a);
-                } else if cur_item == "bonds" {
-                    bonds.push(TopolBond::from(&atoms, line));
-                } else if cur_item == "pairs" {
-                    pairs.push(TopolPair::from(&atoms, line));
-                } else if cur_item == "constraints" {
-                    constraints.push(TopolConstraint::from(&atoms, line));
-                } else if cur_item == "angles" {
-                    angles.push(TopolAngle::from(&atoms, line));
-                } else if cur_item == "dihedrals" {
-                    dihedrals.push(TopolDihedral::from(&atoms, line));
-                } else if cur_item == "exclusions" {
-                    exclusions.push(TopolExclusion::from(&atoms, line));
-                }
+                },
+                "bonds" => bonds.push(TopolBond::from(&atoms, line, &loc)?),
+                "pairs" => pairs.push(TopolPair::from(&atoms, line, &loc)?),
+                "constraints" => constraints.push(TopolConstraint::from(&atoms, line, &loc)?),
+                "angles" => angles.push(TopolAngle::from(&atoms, line, &loc)?),
+                "dihedrals" => dihedrals.push(TopolDihedral::from(&atoms, line, &loc)?),
+                "exclusions" => exclusions.push(TopolExclusion::from(&atoms, line, &loc)?),
+                _ => ()
+            }
+        }
+        // itp 中没有 [ defaults ] 时, 读取同名的 top
+        let top = Path::new(file).with_extension("top");
+        if defaults.is_none() && top.is_file() {
+            let top = top.to_string_lossy();
+            defaults = read_defaults(&top)?;
+            if defaults.is_some() {
+                println!("Read [ defaults ] from {}", top);
             }
         }
+        if atoms.is_empty() {
+            return Err(Error::MissingSection { file: file.to_string(), section: "[ atoms ]".to_string() });
+        }
+        // 没有 at.num 列时, 由使用该类型的原子的元素补上
+        let attypes = attypes.into_iter().map(|at| match at.at_num {
+            Some(_) => at,
+            None => {
+                let at_num = atoms.iter()
+                    .find(|a| a._type == at.name)
+                    .and_then(|a| mol2.atoms.get(a.nr - 1))
+                    .and_then(|m| atomic_number(&m.element));
+                TopolAtomtype { at_num, ..at }
+            }
+        }).collect();
         println!("Finished reading topology of {}\n", mol);
-        Topol {
+        Ok(Topol {
+            defaults,
             atomtypes: attypes, 
+            target_types: vec![],
             moleculetype: mol,
+            resname: mol2.resname.to_string(),
             nrexcl, atoms, bonds, pairs, constraints, angles, dihedrals, exclusions
-        }
+        })
     }
 
-    pub fn to_rtp(&mut self, outfile: &str, ff: &str, 
-        exclude_n: &Vec<usize>, exclude_c: &Vec<usize>) {
-        let mut file = fs::File::create(outfile).unwrap();
-        
-        file.write_all(b"; Created by gen-rtp (https://github.com/supernova4869/gen-rtp)\n").unwrap();
-        file.write_all(format!("; converted from top of {}\n\n", self.moleculetype).as_bytes()).unwrap();
+    /// Compares the `[ defaults ]` of the itp with those of the target force field, warning about every
+    /// difference, and converts the atomtypes between sigma/epsilon and C6/C12 when only one of them uses
+    /// combination rule 1. Afterwards the atomtypes follow `target`.
+    pub fn match_defaults(&mut self, target: &Defaults) {
+        let source = match &self.defaults {
+            Some(d) => d.clone(),
+            None => {
+                println!("Note: no [ defaults ] in the itp, the atomtypes are taken to follow the target force field");
+                self.defaults = Some(target.clone());
+                return;
+            }
+        };
+        let fields = [
+            ("nbfunc", source.nbfunc.to_string(), target.nbfunc.to_string()),
+            ("comb-rule", source.comb_rule.to_string(), target.comb_rule.to_string()),
+            ("gen-pairs", source.gen_pairs.to_string(), target.gen_pairs.to_string()),
+            ("fudgeLJ", source.fudge_lj.to_string(), target.fudge_lj.to_string()),
+            ("fudgeQQ", source.fudge_qq.to_string(), target.fudge_qq.to_string()),
+        ];
+        for (name, s, t) in fields.iter().filter(|(_, s, t)| s != t) {
+            println!("Warning: {} of the itp is {} but {} in the target force field", name, s, t);
+        }
+        if source.nbfunc != 1 || target.nbfunc != 1 {
+            if source.nbfunc != target.nbfunc {
+                println!("Warning: only Lennard-Jones atomtypes can be converted, the atomtypes are written unchanged");
+            }
+        } else if (source.comb_rule == 1) != (target.comb_rule == 1) {
+            self.atomtypes = self.atomtypes.iter().map(|at| match target.comb_rule {
+                1 => at.to_c6_c12(),
+                _ => at.to_sigma_epsilon(),
+            }).collect();
+            println!("Converted the atomtypes from {} to {}", source.nonbonded_columns(), target.nonbonded_columns());
+        } else if source.comb_rule != target.comb_rule {
+            println!("Warning: the target force field combines the atomtypes by rule {} instead of {}, their cross terms will differ",
+                target.comb_rule, source.comb_rule);
+        }
+        self.defaults = Some(target.clone());
+    }
 
-        // 先写备用 atomtypes
-        file.write_all(b"[ atomtypes ]\n").unwrap();
-        file.write_all(b"; name   at.num      mass       charge   ptype     sigma (nm)    epsilon (kJ/mol)\n").unwrap();
-        for at in &self.atomtypes {
-            file.write_all(format!("{}\n", at).as_bytes()).unwrap();
+    /// Matches the atomtypes with those of the target force field by name, ignoring case as pdb2gmx does.
+    /// A type with the parameters of the target type takes its name; a clashing type is renamed to
+    /// `prefix` + name (numbered if that is taken too), in `[ atomtypes ]` and `[ atoms ]`. The types
+    /// found in the target are recorded in `target_types`.
+    pub fn match_atomtypes(&mut self, target: &[TopolAtomtype], prefix: &str) {
+        // 同名 (忽略大小写) 的类型中优先取参数相同的, 其次取完全同名的
+        let find = |name: &str, at: &TopolAtomtype| {
+            let mut found: Vec<&TopolAtomtype> = target.iter().filter(|t| t.name.eq_ignore_ascii_case(name)).collect();
+            found.sort_by_key(|t| (!t.same_params(at), t.name != name));
+            found.first().copied()
+        };
+        let mut atomtypes: Vec<&TopolAtomtype> = self.atomtypes.iter().collect();
+        atomtypes.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut renames: Vec<(String, String)> = vec![];
+        let mut reused = vec![];
+        for at in atomtypes {
+            let t = match find(&at.name, at) {
+                Some(t) => t,
+                None => continue,
+            };
+            if t.same_params(at) {
+                self.target_types.push(t.name.to_string());
+                match t.name == at.name {
+                    true => reused.push(at.name.as_str()),
+                    false => {
+                        println!("Using atomtype {} of the target force field for {} (same parameters)", t.name, at.name);
+                        renames.push((at.name.to_string(), t.name.to_string()));
+                    },
+                }
+                continue;
+            }
+            // 加前缀后仍冲突时依次编号
+            let new = (1..).find_map(|n| {
+                let name = match n {
+                    1 => format!("{}{}", prefix, at.name),
+                    n => format!("{}{}{}", prefix, at.name, n),
+                };
+                match find(&name, at) {
+                    Some(t) if t.same_params(at) => {
+                        self.target_types.push(t.name.to_string());
+                        Some(t.name.to_string())
+                    },
+                    None if !self.atomtypes.iter().any(|a| a.name.eq_ignore_ascii_case(&name)) => Some(name),
+                    _ => None,
+                }
+            }).unwrap();
+            println!("Renamed atomtype {} to {}: {} of the target force field has other parameters", at.name, new, t.name);
+            renames.push((at.name.to_string(), new));
         }
-        file.write_all(b"; *** Please manually move above atom type definitions to ffnonbonded.itp in the folder of the forcefield to be used ***\n\n\n").unwrap();
-        
-        // 再写备用 atpfor at in &self.atomtypes {
-        for at in &self.atomtypes {
-            file.write_all(format!("{:12}{:10.6}\n", at.name, at.mass).as_bytes()).unwrap();
-        }
-        file.write_all(b"; *** Please manually move above information to the atomtypes.atp in the folder of the forcefield to be used\n\n\n").unwrap();
-
-        // 以下正式rtp
-        if ff == "amber" {
-            file.write_all(b"[ bondedtypes ]\n").unwrap();
-            file.write_all(b"; bonds  angles  dihedrals  impropers all_dihedrals nrexcl HH14 RemoveDih\n").unwrap();
-            file.write_all(b"     1       1          9          4        1         3      1     0\n\n").unwrap();
-        } else if ff == "gromos" {
-            file.write_all(b"[ bondedtypes ]\n; bonds  angles  dihedrals  impropers\n").unwrap();
-            file.write_all(b"    2       2          1          2\n\n").unwrap();
-        } else {
-            println!("Error: invalid forcefield, only support amber and gromos.\n");
-            return
+        if !reused.is_empty() {
+            println!("Note: same parameters as in the target force field for atomtypes {}", reused.join(", "));
         }
-    
-        // 残基名
-        file.write_all(format!("[ {} ]\n", self.moleculetype).as_bytes()).unwrap();
-    
+        let rename = |name: &mut String| {
+            if let Some((_, new)) = renames.iter().find(|(old, _)| old == name) {
+                *name = new.to_string();
+            }
+        };
+        self.atomtypes = self.atomtypes.iter().cloned().map(|mut at| {
+            rename(&mut at.name);
+            at
+        }).collect();
+        self.atoms.iter_mut().for_each(|a| rename(&mut a._type));
+        // 键项中的原子是读取时的副本, 一并改名
+        self.bonds.iter_mut().flat_map(|b| [&mut b.ai, &mut b.aj]).for_each(|a| rename(&mut a._type));
+        self.angles.iter_mut().flat_map(|a| [&mut a.ai, &mut a.aj, &mut a.ak]).for_each(|a| rename(&mut a._type));
+        self.dihedrals.iter_mut().flat_map(|d| [&mut d.ai, &mut d.aj, &mut d.ak, &mut d.al]).for_each(|a| rename(&mut a._type));
+    }
+
+    /// The atomtypes not in the target force field, to be added to its `ffnonbonded.itp`.
+    pub fn new_atomtypes(&self) -> impl Iterator<Item = &TopolAtomtype> {
+        self.atomtypes.iter().filter(|at| !self.target_types.contains(&at.name))
+    }
+
+    /// Equilibrium length (nm) of the bond between atoms `a` and `b`, if the itp gives its parameters.
+    pub fn bond_length(&self, a: usize, b: usize) -> Option<f64> {
+        self.bonds.iter()
+            .find(|bond| (bond.ai.nr, bond.aj.nr) == (a, b) || (bond.ai.nr, bond.aj.nr) == (b, a))
+            .and_then(|bond| bond.c0)
+    }
+
+    /// Atom ids in the order of `[ atoms ]`, i.e. of the atoms of [`Topol::rtp_residue`].
+    pub fn atom_ids(&self) -> Vec<usize> {
+        self.atoms.iter().map(|a| a.nr).collect()
+    }
+
+    /// Builds the rtp entry of the residue, dropping the bonded terms of the neighbouring residues.
+    pub fn rtp_residue(&mut self, options: &ResidueOptions) -> RtpResidue {
+        let ff = options.ff.as_str();
+        let exclude_n = options.prev_atoms();
+        let exclude_c = options.next_atoms();
+        // 额外连接的残基由 specbond.dat 成键, 其原子及相关的键项全部去掉
+        let exclude_l = options.link_atoms();
+        let linked = |nrs: &[usize]| nrs.iter().any(|nr| exclude_l.contains(nr));
+
         // [ atoms ]字段：记录残基中每个原子的名称、类型和电荷、电荷组
-        file.write_all(b" [ atoms ]\n").unwrap();
-        for atom in &self.atoms {
-            if !exclude_n.is_empty() && exclude_n.contains(&atom.nr) {
-                file.write_all(format!("; {}\t; previous residue\n", atom.to_rtp()).as_bytes()).unwrap();
-            } else if !exclude_c.is_empty() && exclude_c.contains(&atom.nr) {
-                file.write_all(format!("; {}\t; next residue\n", atom.to_rtp()).as_bytes()).unwrap();
-            } else {
-                file.write_all((atom.to_rtp() + "\n").as_bytes()).unwrap();
+        let mut atoms: Vec<RtpAtom> = self.atoms.iter().map(|atom| {
+            let mut a = atom.to_rtp();
+            if exclude_n.contains(&atom.nr) {
+                a.excluded = Some("previous residue".to_string());
+            } else if exclude_c.contains(&atom.nr) {
+                a.excluded = Some("next residue".to_string());
+            } else if exclude_l.contains(&atom.nr) {
+                a.excluded = Some("linked residue".to_string());
             }
-        }
+            a
+        }).collect();
+        // 去掉相邻残基后电荷组编号有空缺, 保持原分组从1重新编号
+        renumber_cgnr(&mut atoms);
     
         // [ bonds ]字段：原子间的连接信息
-        file.write_all(b" [ bonds ]\n").unwrap();
         self.bonds.retain(|b| !exclude_n.contains(&b.ai.nr) || !exclude_n.contains(&b.aj.nr));
         self.bonds.retain(|b| !exclude_c.contains(&b.ai.nr) || !exclude_c.contains(&b.aj.nr));
         
@@ -276,12 +419,10 @@ impl Topol {
             },
             _ => ()
         }
-        for bond in &self.bonds {
-            file.write_all((bond.to_rtp() + "\n").as_bytes()).unwrap();
-        }
+        self.bonds.retain(|b| !linked(&[b.ai.nr, b.aj.nr]));
+        let bonds = self.bonds.iter().map(|b| b.to_rtp()).collect();
     
         // [ angles ]字段：键角信息
-        file.write_all(b" [ angles ]\n").unwrap();
         self.angles.retain(|a| {
             let count = [a.ai.nr, a.aj.nr, a.ak.nr].iter().filter(|&x| exclude_n.contains(x)).count();
             count <= 1
@@ -290,45 +431,258 @@ impl Topol {
             let count = [a.ai.nr, a.aj.nr, a.ak.nr].iter().filter(|&x| exclude_c.contains(x)).count();
             count <= 1
         });
-        for angle in &self.angles {
-            file.write_all((angle.to_rtp() + "\n").as_bytes()).unwrap();
-        }
+        self.angles.retain(|a| !linked(&[a.ai.nr, a.aj.nr, a.ak.nr]));
+        let angles = self.angles.iter().map(|a| a.to_rtp()).collect();
     
         // [ dihedrals ]字段：二面角信息
         // 理论上2用来描述improper, 但sobtop生成拓扑时采用2描述proper, 这里为特殊应对
-        file.write_all(b" [ dihedrals ]\n").unwrap();
+        // 核酸的磷酸二酯键两侧各两个原子的二面角 (如 C3'-O3'-P-O5') 只归入一个残基,
+        // 与键的归属一致: amber 归入含 P 的残基, gromos 归入含 O3' 的残基
+        let (max_n, max_c) = match (options.kind, ff) {
+            (Kind::Nucleic, "amber") => (2, 1),
+            (Kind::Nucleic, "gromos") => (1, 2),
+            _ => (2, 2),
+        };
         self.dihedrals.retain(|d| {
             let count = [d.ai.nr, d.aj.nr, d.ak.nr, d.al.nr].iter().filter(|&x| exclude_n.contains(x)).count();
-            count <= 2
+            count <= max_n
         });
         self.dihedrals.retain(|d| {
             let count = [d.ai.nr, d.aj.nr, d.ak.nr, d.al.nr].iter().filter(|&x| exclude_c.contains(x)).count();
-            count <= 2
+            count <= max_c
         });
+        self.dihedrals.retain(|d| !linked(&[d.ai.nr, d.aj.nr, d.ak.nr, d.al.nr]));
 
         // [ dihedrals ]字段: proper信息
-        for dihedral in self.dihedrals.iter().filter(|&d| vec![9, 1, 2].contains(&d.funct)) {
-            file.write_all((dihedral.to_rtp(ff) + "\n").as_bytes()).unwrap();
-        }
+        let dihedrals = self.dihedrals.iter()
+            .filter(|&d| [9, 1, 2].contains(&d.funct))
+            .map(|d| d.to_rtp(ff))
+            .collect();
     
         // [ impropers ]字段：反常二面角信息
-        file.write_all(b" [ impropers ]\n").unwrap();
-        for dihedral in self.dihedrals.iter().filter(|&d| d.funct == 4) {
-            file.write_all((dihedral.to_rtp(ff) + "\n").as_bytes()).unwrap();
+        let impropers = self.dihedrals.iter()
+            .filter(|&d| d.funct == 4)
+            .map(|d| d.to_rtp(ff))
+            .collect();
+
+        RtpResidue { name: self.resname.to_string(), atoms, bonds, angles, dihedrals, impropers, net_charge: None }
+    }
+
+    /// Moves the bonded parameters of the residue from `rtp` into type tables keyed by atomtype, like the
+    /// `ffbonded.itp` of a force field, leaving only the connectivity in the rtp. Call after
+    /// [`Topol::rtp_residue`]. Terms with an atom of a neighbour, with other function types than the
+    /// `[ bondedtypes ]` of the rtp, or whose types already have other parameters keep theirs; types
+    /// found in `options.target_bonded` with the same parameters are not tabulated again.
+    pub fn bonded_types(&self, rtp: &mut RtpResidue, options: &ResidueOptions) -> Ffbonded {
+        let [fb, fa, fd, fi] = bondedtypes(&options.ff);
+        let target = &options.target_bonded;
+        let mut table = Ffbonded::default();
+        let mut stats = BondedStats::default();
+        let [bonds, angles, propers, impropers] = self.bonded_terms();
+        tabulate(&bonds, &mut rtp.bonds, fb, &target.bondtypes, &mut table.bondtypes, options, &mut stats);
+        tabulate(&angles, &mut rtp.angles, fa, &target.angletypes, &mut table.angletypes, options, &mut stats);
+        tabulate(&propers, &mut rtp.dihedrals, fd, &target.dihedraltypes, &mut table.dihedraltypes, options, &mut stats);
+        tabulate(&impropers, &mut rtp.impropers, fi, &target.impropertypes, &mut table.impropertypes, options, &mut stats);
+
+        println!("Moved the parameters of {} bonded terms of {} to {} bonded types", stats.moved, rtp.name, table.len());
+        if stats.in_target > 0 {
+            println!("Note: {} terms use bonded types already known from the target force field or other residues", stats.in_target);
         }
-    
-        println!("Finished writing rtp file to {}", outfile);
+        if stats.neighbour + stats.funct + stats.differ > 0 {
+            println!("Note: {} terms keep their parameters in the rtp: {} with atoms of a neighbour, {} with other function types, {} with other parameters than their bonded type",
+                stats.neighbour + stats.funct + stats.differ, stats.neighbour, stats.funct, stats.differ);
+        }
+        table
+    }
+
+    /// Looks up the parameters of the junction terms, those with an atom of a neighbour (e.g. `-C N C5`),
+    /// in `options.target_bonded` and then in `own`, the bonded types of the residue. The neighbour
+    /// atoms take their atomtype from `options.neighbour_types` by their name in the reference residue:
+    /// from `options.neighbour_map`, then from `roles` (see [`crate::boundary::neighbour_roles`]), else
+    /// their own name without the `-`/`+` prefix (from the residue itself for a polymer). Found parameters
+    /// replace those of the capped molecule in `rtp`; the terms without any are reported and returned.
+    pub fn junction_params(&self, rtp: &mut RtpResidue, options: &ResidueOptions, own: Option<&Ffbonded>,
+                           roles: &[(String, String)]) -> Vec<String> {
+        let ff = options.ff.as_str();
+        let own = own.cloned().unwrap_or_default();
+        let atomtype = |a: &TopolAtom| -> Option<String> {
+            if !options.is_outside(a.nr) {
+                return Some(a._type.to_string());
+            }
+            let name = a.atom.trim_start_matches(['-', '+']);
+            match options.kind {
+                // 聚合物的相邻单元就是自身
+                Kind::Polymer => self.atoms.iter().find(|b| !options.is_outside(b.nr) && b.atom == name).map(|b| b._type.to_string()),
+                _ => {
+                    // 封端原子按指定的对应关系或成键位置取参考残基中的原子名
+                    let name = options.neighbour_map.iter().chain(roles).find(|(n, _)| *n == a.atom).map_or(name, |(_, r)| r.as_str());
+                    options.neighbour_types.iter().find(|(n, _)| n == name).map(|(_, t)| t.to_string())
+                },
+            }
+        };
+        let [bonds, angles, propers, impropers] = self.bonded_terms();
+        let [fb, fa, fd, fi] = bondedtypes(ff);
+        let target = &options.target_bonded;
+        let mut found = 0;
+        let mut missing = vec![];
+        for (terms, items, funct, target, own) in [
+            (&bonds, &mut rtp.bonds, fb, &target.bondtypes, &own.bondtypes),
+            (&angles, &mut rtp.angles, fa, &target.angletypes, &own.angletypes),
+            (&propers, &mut rtp.dihedrals, fd, &target.dihedraltypes, &own.dihedraltypes),
+            (&impropers, &mut rtp.impropers, fi, &target.impropertypes, &own.impropertypes),
+        ] {
+            let mut done = vec![false; terms.len()];
+            for i in 0..terms.len() {
+                if done[i] {
+                    continue;
+                }
+                for j in i..terms.len() {
+                    done[j] = done[j] || terms[j].nrs() == terms[i].nrs();
+                }
+                let term = &terms[i];
+                // amber 的 funct 2 proper 在 rtp 中带有自己的函数类型, 不替换
+                if !term.atoms.iter().any(|a| options.is_outside(a.nr)) || (ff == "amber" && term.atoms.len() == 4 && term.funct == 2) {
+                    continue;
+                }
+                let names: Vec<String> = term.atoms.iter().map(|a| a.atom.to_string()).collect();
+                let types: Vec<Option<String>> = term.atoms.iter().map(|a| atomtype(a)).collect();
+                let unknown: Vec<&str> = term.atoms.iter().zip(&types).filter(|(_, t)| t.is_none()).map(|(a, _)| a.atom.as_str()).collect();
+                if !unknown.is_empty() {
+                    missing.push(format!("{} (no atomtype for {})", names.join(" "), unknown.join(", ")));
+                    continue;
+                }
+                let types: Vec<&str> = types.iter().flatten().map(|t| t.as_str()).collect();
+                let mut params = find(target, &types, funct);
+                if params.is_empty() {
+                    params = find(own, &types, funct);
+                }
+                // 未展开宏的参数无法写入 rtp
+                if params.is_empty() || params.iter().any(|p| p.params.is_empty()) {
+                    missing.push(format!("{} ({})", names.join(" "), types.join("-")));
+                    continue;
+                }
+                if let Some(first) = items.iter().position(|it| it.atoms == names) {
+                    items.retain(|it| it.atoms != names);
+                    items.splice(first..first, params.iter().map(|p| RtpInteraction { atoms: names.clone(), params: p.params_text() }));
+                    found += 1;
+                }
+            }
+        }
+
+        if found > 0 {
+            println!("Looked up the parameters of {} junction terms of {} by atomtype", found, rtp.name);
+        }
+        if !missing.is_empty() {
+            println!("Warning: no bonded types for {} junction terms of {}, they keep the parameters of the capped molecule:", missing.len(), rtp.name);
+            for m in &missing {
+                println!("  {}", m);
+            }
+        }
+        missing
+    }
+
+    // 保留的键, 角, proper 和 improper 二面角, 与 rtp_residue 的筛选一致
+    fn bonded_terms(&self) -> [Vec<BondedTerm<'_>>; 4] {
+        [
+            self.bonds.iter().map(|b| BondedTerm::new(&[&b.ai, &b.aj], b.funct, &[b.c0, b.c1])).collect(),
+            self.angles.iter().map(|a| BondedTerm::new(&[&a.ai, &a.aj, &a.ak], a.funct, &[a.c0, a.c1])).collect(),
+            self.dihedrals.iter().filter(|d| [9, 1, 2].contains(&d.funct)).map(BondedTerm::dihedral).collect(),
+            self.dihedrals.iter().filter(|d| d.funct == 4).map(BondedTerm::dihedral).collect(),
+        ]
     }
 }
 
+// 键项的原子, 函数类型和参数, 用于按原子类型归类
+struct BondedTerm<'a> {
+    atoms: Vec<&'a TopolAtom>,
+    funct: i32,
+    params: Vec<f64>,
+}
+
+impl<'a> BondedTerm<'a> {
+    fn new(atoms: &[&'a TopolAtom], funct: i32, params: &[Option<f64>]) -> BondedTerm<'a> {
+        BondedTerm { atoms: atoms.to_vec(), funct, params: params.iter().flatten().copied().collect() }
+    }
+
+    fn dihedral(d: &'a TopolDihedral) -> BondedTerm<'a> {
+        BondedTerm::new(&[&d.ai, &d.aj, &d.ak, &d.al], d.funct, &[d.c0, d.c1, d.c2])
+    }
+
+    fn nrs(&self) -> Vec<usize> {
+        self.atoms.iter().map(|a| a.nr).collect()
+    }
+}
+
+#[derive(Default)]
+struct BondedStats {
+    moved: usize,
+    in_target: usize,
+    neighbour: usize,
+    funct: usize,
+    differ: usize,
+}
+
+// 同一组原子的多项 (如多重二面角) 合为一个类型, rtp 中只留一行不带参数的连接
+fn tabulate(terms: &[BondedTerm], items: &mut Vec<RtpInteraction>, funct: i32, target: &[BondedType],
+            table: &mut Vec<BondedType>, options: &ResidueOptions, stats: &mut BondedStats) {
+    let mut removed = vec![false; items.len()];
+    let mut done = vec![false; terms.len()];
+    for i in 0..terms.len() {
+        if done[i] {
+            continue;
+        }
+        let group: Vec<usize> = (i..terms.len()).filter(|&j| terms[j].nrs() == terms[i].nrs()).collect();
+        for &j in &group {
+            done[j] = true;
+        }
+        let term = &terms[i];
+        if term.params.is_empty() {
+            continue;
+        }
+        if term.atoms.iter().any(|a| options.is_outside(a.nr)) {
+            stats.neighbour += group.len();
+            continue;
+        }
+        if group.iter().any(|&j| class(terms[j].funct) != class(funct)) {
+            stats.funct += group.len();
+            continue;
+        }
+        let types: Vec<&str> = term.atoms.iter().map(|a| a._type.as_str()).collect();
+        let params: Vec<&[f64]> = group.iter().map(|&j| terms[j].params.as_slice()).collect();
+        let in_target = find(target, &types, funct);
+        let from_target = !in_target.is_empty();
+        let known = match from_target {
+            true => in_target,
+            false => find(table, &types, funct),
+        };
+        if known.is_empty() {
+            table.extend(params.iter().map(|p| BondedType { types: types.iter().map(|t| t.to_string()).collect(), funct, params: p.to_vec() }));
+        } else if !same_params(&known, &params) {
+            stats.differ += group.len();
+            continue;
+        } else if from_target {
+            stats.in_target += group.len();
+        }
+        stats.moved += group.len();
+        items[group[0]].params.clear();
+        for &j in &group[1..] {
+            removed[j] = true;
+        }
+    }
+    let mut removed = removed.into_iter();
+    items.retain(|_| !removed.next().unwrap());
+}
+
 impl Display for Topol {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut out = format!("; Created by gen-rtp (https://github.com/supernova4869/gen-rtp)\n");
+        let mut out = "; Created by gen-rtp (https://github.com/supernova4869/gen-rtp)\n".to_string();
         // 输出原子类型
         if !self.atomtypes.is_empty() {
-            out.push_str("\n[ atomtypes ]\n; name    at.num        mass       charge    ptype      sigma (nm)      epsilon (kJ/mol)\n");
+            let bond_type = self.atomtypes.iter().any(|at| at.bond_type.is_some());
+            let c6_c12 = self.defaults.as_ref().is_some_and(|d| d.comb_rule == 1);
+            out.push_str(&format!("\n[ atomtypes ]\n{}\n", atomtypes_title(bond_type, c6_c12)));
             for at in &self.atomtypes {
-                out.push_str(format!("{}\n", at).as_str());
+                out.push_str(&format!("{}\n", at.line(bond_type, c6_c12)));
             }
         }
         // 输出残基名
@@ -407,41 +761,328 @@ impl Display for Topol {
 }
 
 impl TopolAtomtype {
-    fn from(line: &String) -> TopolAtomtype {
+    // 与 grompp 相同, 以粒子类型所在列判断是否有 bond_type 和 at.num 两个可选列
+    pub(crate) fn from(line: &str, loc: &Location) -> Result<TopolAtomtype> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let name = paras[0].to_string();
-        // 有时候第二个是原子序号, 所以质量倒数计数
-        let mass: f64 = paras[paras.len() - 5].parse().unwrap();
-        let charge: f64 = paras[paras.len() - 4].parse().unwrap();
-        let ptype = paras[paras.len() - 3].to_string();
-        let sigma: f64 = paras[paras.len() - 2].parse().unwrap();
-        let epsilon: f64 = paras.last().unwrap().parse().unwrap();
-        TopolAtomtype{ name, mass, charge, ptype, sigma, epsilon }
+        let is_ptype = |i: usize| paras.get(i).is_some_and(|p| p.len() == 1 && p.chars().all(|c| c.is_ascii_alphabetic()));
+        let (bond_type, at_num, first) = if is_ptype(3) {
+            (None, None, 1)
+        } else if is_ptype(5) {
+            (Some(paras[1].to_string()), Some(loc.field(&paras, 2, "at.num")?), 3)
+        } else if is_ptype(4) {
+            match paras[1].starts_with(|c: char| c.is_ascii_alphabetic()) {
+                true => (Some(paras[1].to_string()), None, 2),
+                false => (None, Some(loc.field(&paras, 1, "at.num")?), 2),
+            }
+        } else {
+            return Err(loc.error(line, "expected name, [bond_type], [at.num], mass, charge, ptype and two nonbonded parameters"));
+        };
+        Ok(TopolAtomtype {
+            name: paras[0].to_string(),
+            bond_type,
+            at_num,
+            mass: loc.field(&paras, first, "mass")?,
+            charge: loc.field(&paras, first + 1, "charge")?,
+            ptype: paras[first + 2].to_string(),
+            sigma: loc.field(&paras, first + 3, "sigma")?,
+            epsilon: loc.field(&paras, first + 4, "epsilon")?,
+        })
+    }
+
+    /// The `[ atomtypes ]` line, with the bonded type column if `bond_type` (the name if there is none)
+    /// and C6 and C12 in scientific notation if `c6_c12`. A missing atomic number is written as 0.
+    pub fn line(&self, bond_type: bool, c6_c12: bool) -> String {
+        let mut out = format!("  {:<10}", self.name);
+        if bond_type {
+            out.push_str(&format!("{:<10}", self.bond_type.as_deref().unwrap_or(&self.name)));
+        }
+        out.push_str(&format!("{:>6}{:12.6}{:10.6}{:>6}", self.at_num.unwrap_or(0), self.mass, self.charge, self.ptype));
+        match c6_c12 {
+            true => out.push_str(&format!("{:16.6e}{:16.6e}", self.sigma, self.epsilon)),
+            false => out.push_str(&format!("{:16.6}{:16.6}", self.sigma, self.epsilon)),
+        }
+        out
     }
 }
 
+/// The comment line naming the `[ atomtypes ]` columns written by [`TopolAtomtype::line`].
+pub fn atomtypes_title(bond_type: bool, c6_c12: bool) -> String {
+    let mut out = format!("; {:<10}", "name");
+    if bond_type {
+        out.push_str(&format!("{:<10}", "bond_type"));
+    }
+    out.push_str(&format!("{:>6}{:>12}{:>10}{:>6}", "at.num", "mass", "charge", "ptype"));
+    match c6_c12 {
+        true => out.push_str(&format!("{:>16}{:>16}", "c6", "c12")),
+        false => out.push_str(&format!("{:>16}{:>16}", "sigma (nm)", "eps (kJ/mol)")),
+    }
+    out
+}
+
 impl Display for TopolAtomtype {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "  {:8}{:8}{:10.6}{:13.6}{:>5}{:18.6}{:16.6}", self.name, self.name, self.mass, self.charge, self.ptype, self.sigma, self.epsilon)
+        write!(f, "{}", self.line(self.bond_type.is_some(), false))
+    }
+}
+
+impl TopolAtomtype {
+    /// Whether the mass and the two nonbonded parameters agree with `other` to 0.1%, the precision
+    /// of force field files.
+    pub fn same_params(&self, other: &TopolAtomtype) -> bool {
+        let same = |a: f64, b: f64| (a - b).abs() <= 1e-3 * a.abs().max(b.abs());
+        same(self.mass, other.mass) && same(self.sigma, other.sigma) && same(self.epsilon, other.epsilon)
+    }
+
+    /// The atomtype with sigma and epsilon turned into C6 = 4 eps sigma^6 and C12 = 4 eps sigma^12.
+    pub fn to_c6_c12(&self) -> TopolAtomtype {
+        let c6 = 4.0 * self.epsilon * self.sigma.powi(6);
+        let c12 = 4.0 * self.epsilon * self.sigma.powi(12);
+        TopolAtomtype { sigma: c6, epsilon: c12, ..self.clone() }
+    }
+
+    /// The atomtype with C6 and C12 turned into sigma = (C12/C6)^(1/6) and epsilon = C6^2/(4 C12).
+    pub fn to_sigma_epsilon(&self) -> TopolAtomtype {
+        let (c6, c12) = (self.sigma, self.epsilon);
+        match c6 == 0.0 || c12 == 0.0 {
+            true => TopolAtomtype { sigma: 0.0, epsilon: 0.0, ..self.clone() },
+            false => TopolAtomtype { sigma: (c12 / c6).powf(1.0 / 6.0), epsilon: c6 * c6 / (4.0 * c12), ..self.clone() },
+        }
+    }
+}
+
+impl Defaults {
+    fn from(line: &str, loc: &Location) -> Result<Defaults> {
+        let paras: Vec<&str> = line.split_whitespace().collect();
+        Ok(Defaults {
+            nbfunc: loc.field(&paras, 0, "nbfunc")?,
+            comb_rule: loc.field(&paras, 1, "comb-rule")?,
+            gen_pairs: loc.opt_field(&paras, 2, "gen-pairs")?.unwrap_or_else(|| "no".to_string()),
+            fudge_lj: loc.opt_field(&paras, 3, "fudgeLJ")?.unwrap_or(1.0),
+            fudge_qq: loc.opt_field(&paras, 4, "fudgeQQ")?.unwrap_or(1.0),
+        })
+    }
+
+    /// Titles of the last two `[ atomtypes ]` columns under these defaults.
+    pub fn nonbonded_columns(&self) -> &'static str {
+        match self.comb_rule {
+            1 => "c6 and c12",
+            _ => "sigma and epsilon",
+        }
+    }
+}
+
+impl Display for Defaults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:6}{:15}{:>15}{:14}{:11}", self.nbfunc, self.comb_rule, self.gen_pairs, self.fudge_lj, self.fudge_qq)
+    }
+}
+
+/// The `[ defaults ]` of a top, itp or `forcefield.itp`, `None` if it has none.
+pub fn read_defaults(file: &str) -> Result<Option<Defaults>> {
+    let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+    let re = Regex::new(r"^\s*\[\s*(\w+)\s*\]").unwrap();
+    let mut cur_item = String::new();
+    for (ln, line) in content.lines().enumerate() {
+        let line = line.split(';').next().unwrap().trim();
+        if let Some(caps) = re.captures(line) {
+            cur_item = caps[1].to_string();
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') || cur_item != "defaults" {
+            continue;
+        }
+        return Defaults::from(line, &Location { file, line: ln + 1, section: "[ defaults ]" }).map(Some);
+    }
+    Ok(None)
+}
+
+// 力场目录或其 forcefield.itp
+pub(crate) fn forcefield_file(path: &str) -> String {
+    match Path::new(path).is_dir() {
+        true => Path::new(path).join("forcefield.itp").to_string_lossy().to_string(),
+        false => path.to_string(),
+    }
+}
+
+/// The `[ defaults ]` of a target force field, given as its `.ff` directory or its `forcefield.itp`.
+pub fn forcefield_defaults(path: &str) -> Result<Defaults> {
+    let file = forcefield_file(path);
+    read_defaults(&file)?.ok_or(Error::MissingSection { file, section: "[ defaults ]".to_string() })
+}
+
+/// The `[ atomtypes ]` of a target force field, given as its `.ff` directory or its `forcefield.itp`,
+/// including those of the files it `#include`s (e.g. `ffnonbonded.itp`).
+pub fn forcefield_atomtypes(path: &str) -> Result<Vec<TopolAtomtype>> {
+    let re = Regex::new(r"^\[\s*(\w+)\s*\]").unwrap();
+    let mut section = String::new();
+    let mut atomtypes = vec![];
+    for l in stripped_lines(&forcefield_file(path), &Preprocessor::default())? {
+        if let Some(caps) = re.captures(&l.text) {
+            section = caps[1].to_string();
+            continue;
+        }
+        if section == "atomtypes" {
+            let loc = Location { file: &l.file, line: l.line, section: "[ atomtypes ]" };
+            atomtypes.push(TopolAtomtype::from(&l.text, &loc)?);
+        }
+    }
+    Ok(atomtypes)
+}
+
+/// A `[ moleculetype ]` of an itp or top: its name and number of atoms.
+#[derive(Clone, Debug)]
+pub struct MoleculeType {
+    pub name: String,
+    pub natoms: usize,
+}
+
+/// The molecule types of an itp or top, in order.
+pub fn read_molecules(file: &str, preprocessor: &Preprocessor) -> Result<Vec<MoleculeType>> {
+    Ok(molecules(&stripped_lines(file, preprocessor)?))
+}
+
+/// Index in `molecules` of the molecule named `wanted` (or numbered so, from 1); without `wanted`, of the
+/// only molecule, or else of the only one with `natoms` atoms.
+pub fn select_molecule(molecules: &[MoleculeType], wanted: Option<&str>, natoms: Option<usize>) -> Result<usize> {
+    let list = || molecules.iter().map(|m| format!("{} ({} atoms)", m.name, m.natoms)).collect::<Vec<String>>().join(", ");
+    if let Some(w) = wanted {
+        return molecules.iter().position(|m| m.name == w)
+            .or_else(|| w.parse::<usize>().ok().filter(|&i| i >= 1 && i <= molecules.len()).map(|i| i - 1))
+            .ok_or_else(|| Error::Invalid(format!("no molecule type `{}`, the topology has {}", w, list())));
+    }
+    if molecules.len() == 1 {
+        return Ok(0);
+    }
+    let matched: Vec<usize> = (0..molecules.len()).filter(|&i| Some(molecules[i].natoms) == natoms).collect();
+    match matched[..] {
+        [i] => Ok(i),
+        _ => Err(Error::Invalid(format!("cannot tell which molecule type to convert among {}, choose one with --molecule", list()))),
+    }
+}
+
+// 预处理并去掉注释和空行, 保留每行所在文件和原始行号以便报错
+fn stripped_lines(file: &str, preprocessor: &Preprocessor) -> Result<Vec<SourceLine>> {
+    let re = Regex::new(r"\s*;.*").unwrap();
+    Ok(preprocessor.process(file)?.into_iter()
+        .map(|l| SourceLine { text: re.replace(&l.text, "").trim().to_string(), ..l })
+        .filter(|l| !l.text.is_empty())
+        .collect())
+}
+
+// 各 [ moleculetype ] 的名字和原子数
+fn molecules(lines: &[SourceLine]) -> Vec<MoleculeType> {
+    let re = Regex::new(r"^\[\s*(\w+)\s*\]").unwrap();
+    let mut molecules: Vec<MoleculeType> = vec![];
+    let mut cur_item = String::new();
+    for l in lines {
+        if let Some(caps) = re.captures(&l.text) {
+            cur_item = caps[1].to_string();
+            continue;
+        }
+        match cur_item.as_str() {
+            "moleculetype" => {
+                molecules.push(MoleculeType { name: l.text.split_whitespace().next().unwrap().to_string(), natoms: 0 });
+                // 名字只取一行
+                cur_item.clear();
+            },
+            "atoms" => if let Some(m) = molecules.last_mut() {
+                m.natoms += 1;
+            },
+            _ => (),
+        }
+    }
+    molecules
+}
+
+// 文件所在的力场目录 (*.ff)
+fn forcefield_dir(file: &str) -> Option<&Path> {
+    Path::new(file).ancestors().find(|p| p.extension().is_some_and(|e| e == "ff"))
+}
+
+// 选中分子的名字, 以及去掉其余分子各段后的行; 分子之外的段 (defaults, atomtypes 等) 只保留
+// 顶层文件及其本地包含的, 力场目录中包含进来的不算这个残基的参数
+fn molecule_lines(file: &str, preprocessor: &Preprocessor, wanted: Option<&str>, natoms: Option<usize>) -> Result<(String, Vec<SourceLine>)> {
+    let lines = stripped_lines(file, preprocessor)?;
+    let molecules = molecules(&lines);
+    if molecules.is_empty() {
+        return Err(Error::MissingSection { file: file.to_string(), section: "[ moleculetype ]".to_string() });
+    }
+    let index = select_molecule(&molecules, wanted, natoms)?;
+    let re = Regex::new(r"^\[\s*(\w+)\s*\]").unwrap();
+    let mut current: Option<usize> = None;
+    let mut count = 0;
+    let mut kept = vec![];
+    let top_ff = forcefield_dir(file);
+    for l in lines {
+        let header = re.captures(&l.text);
+        if let Some(caps) = &header {
+            match &caps[1] {
+                "moleculetype" => {
+                    current = Some(count);
+                    count += 1;
+                },
+                "system" | "molecules" | "defaults" | "atomtypes" | "bondtypes" | "pairtypes" | "angletypes"
+                    | "dihedraltypes" | "constrainttypes" | "nonbond_params" => current = None,
+                _ => (),
+            }
+        }
+        // 段名保留, 以免包含之后的行被归到前一段
+        let from_ff = header.is_none() && forcefield_dir(&l.file).is_some_and(|d| Some(d) != top_ff);
+        if (current.is_none() && !from_ff) || current == Some(index) {
+            kept.push(l);
+        }
+    }
+    Ok((molecules[index].name.to_string(), kept))
+}
+
+/// The residue number and name of every atom in `[ atoms ]` of an itp, as (nr, resnr, residue).
+pub fn read_atom_residues(file: &str, preprocessor: &Preprocessor, molecule: Option<&str>) -> Result<Vec<(usize, i32, String)>> {
+    let re = Regex::new(r"^\s*\[\s*(\w+)\s*\]").unwrap();
+    let mut cur_item = String::new();
+    let mut residues = vec![];
+    for l in molecule_lines(file, preprocessor, molecule, None)?.1 {
+        let line = l.text.as_str();
+        if let Some(caps) = re.captures(line) {
+            cur_item = caps[1].to_string();
+            continue;
+        }
+        if line.is_empty() || cur_item != "atoms" {
+            continue;
+        }
+        let a = TopolAtom::from(line, &Location { file: &l.file, line: l.line, section: "[ atoms ]" })?;
+        residues.push((a.nr, a.resnr, a.resname));
+    }
+    if residues.is_empty() {
+        return Err(Error::MissingSection { file: file.to_string(), section: "[ atoms ]".to_string() });
+    }
+    Ok(residues)
+}
+
+// 键项要么不带参数 (取自力场), 要么给全写出所需的参数
+fn check_params(line: &str, params: &[Option<f64>], loc: &Location) -> Result<()> {
+    let given = params.iter().filter(|c| c.is_some()).count();
+    match given == 0 || given == params.len() {
+        true => Ok(()),
+        false => Err(loc.error(line.trim(), &format!("expected {} parameters", params.len()))),
     }
 }
 
 impl TopolAtom {
-    fn from(line: &String) -> TopolAtom {
+    fn from(line: &str, loc: &Location) -> Result<TopolAtom> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let nr: usize = paras[0].parse().unwrap();
-        let _type = paras[1].to_string();
-        let resnr: i32 = paras[2].parse().unwrap();
-        let resname = paras[3].to_string();
-        let atom = paras[4].to_string();
-        let cgnr: i32 = paras[5].parse().unwrap();
-        let charge: f64 = paras[6].parse().unwrap();
-        let mass: Option<f64> = get_param_at(&paras, 7);
-        TopolAtom{ nr, _type, resnr, resname, atom, cgnr, charge, mass }
+        let nr: usize = loc.field(&paras, 0, "nr")?;
+        let _type: String = loc.field(&paras, 1, "type")?;
+        let resnr: i32 = loc.field(&paras, 2, "resnr")?;
+        let resname: String = loc.field(&paras, 3, "resname")?;
+        let atom: String = loc.field(&paras, 4, "atom")?;
+        let cgnr: i32 = loc.field(&paras, 5, "cgnr")?;
+        let charge: f64 = loc.field(&paras, 6, "charge")?;
+        let mass: Option<f64> = loc.opt_field(&paras, 7, "parameter")?;
+        Ok(TopolAtom{ nr, _type, resnr, resname, atom, cgnr, charge, mass })
     }
 
-    fn to_rtp(&self) -> String {
-        format!("{:>8}{:>6}{:12.6}{:5}", self.atom, self._type, self.charge, self.cgnr)
+    fn to_rtp(&self) -> RtpAtom {
+        RtpAtom { name: self.atom.to_string(), atomtype: self._type.to_string(), charge: self.charge, cgnr: self.cgnr, excluded: None }
     }
 }
 
@@ -458,23 +1099,25 @@ impl Display for TopolAtom {
 }
 
 impl TopolBond {
-    fn from(atoms: &Vec<TopolAtom>, line: &String) -> TopolBond {
+    fn from(atoms: &[TopolAtom], line: &str, loc: &Location) -> Result<TopolBond> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let ai: usize = paras[0].parse().unwrap();
-        let aj: usize = paras[1].parse().unwrap();
-        let funct: i32 = paras[2].parse().unwrap();
-        let ai = get_atom_from_nr(atoms, ai).to_owned();
-        let aj = get_atom_from_nr(atoms, aj).to_owned();
-        let c0: Option<f64> = get_param_at(&paras, 3);
-        let c1: Option<f64> = get_param_at(&paras, 4);
-        TopolBond{ ai, aj, funct, c0, c1 }
+        let ai: usize = loc.field(&paras, 0, "ai")?;
+        let aj: usize = loc.field(&paras, 1, "aj")?;
+        let funct: i32 = loc.field(&paras, 2, "funct")?;
+        let ai = get_atom_from_nr(atoms, ai, loc)?.to_owned();
+        let aj = get_atom_from_nr(atoms, aj, loc)?.to_owned();
+        let c0: Option<f64> = loc.opt_field(&paras, 3, "parameter")?;
+        let c1: Option<f64> = loc.opt_field(&paras, 4, "parameter")?;
+        check_params(line, &[c0, c1], loc)?;
+        Ok(TopolBond{ ai, aj, funct, c0, c1 })
     }
 
-    fn to_rtp(&self) -> String {
-        match self.c0 {
-            Some(c0) => format!("{:>7}{:>7}{:13.6}{:13.6e}", self.ai.atom, self.aj.atom, c0, self.c1.unwrap()),
-            None => format!("{:7}{:7}", self.ai.atom, self.aj.atom)
-        }
+    fn to_rtp(&self) -> RtpInteraction {
+        let params = match self.c0 {
+            Some(c0) => format!("{:13.6}{:13.6e}", c0, self.c1.unwrap()),
+            None => "".to_string()
+        };
+        RtpInteraction { atoms: vec![self.ai.atom.to_string(), self.aj.atom.to_string()], params }
     }
 }
 
@@ -489,14 +1132,14 @@ impl Display for TopolBond {
 }
 
 impl TopolPair {
-    fn from(atoms: &Vec<TopolAtom>, line: &String) -> TopolPair {
+    fn from(atoms: &[TopolAtom], line: &str, loc: &Location) -> Result<TopolPair> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let ai: usize = paras[0].parse().unwrap();
-        let aj: usize = paras[1].parse().unwrap();
-        let funct: i32 = paras[2].parse().unwrap();
-        let ai = get_atom_from_nr(atoms, ai).to_owned();
-        let aj = get_atom_from_nr(atoms, aj).to_owned();
-        TopolPair{ ai, aj, funct }
+        let ai: usize = loc.field(&paras, 0, "ai")?;
+        let aj: usize = loc.field(&paras, 1, "aj")?;
+        let funct: i32 = loc.field(&paras, 2, "funct")?;
+        let ai = get_atom_from_nr(atoms, ai, loc)?.to_owned();
+        let aj = get_atom_from_nr(atoms, aj, loc)?.to_owned();
+        Ok(TopolPair{ ai, aj, funct })
     }
 }
 
@@ -507,18 +1150,18 @@ impl Display for TopolPair {
 }
 
 impl TopolConstraint {
-    fn from(atoms: &Vec<TopolAtom>, line: &String) -> TopolConstraint {
+    fn from(atoms: &[TopolAtom], line: &str, loc: &Location) -> Result<TopolConstraint> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let ai: usize = paras[0].parse().unwrap();
-        let aj: usize = paras[1].parse().unwrap();
-        let funct: i32 = paras[2].parse().unwrap();
-        let ai = get_atom_from_nr(atoms, ai).to_owned();
-        let aj = get_atom_from_nr(atoms, aj).to_owned();
+        let ai: usize = loc.field(&paras, 0, "ai")?;
+        let aj: usize = loc.field(&paras, 1, "aj")?;
+        let funct: i32 = loc.field(&paras, 2, "funct")?;
+        let ai = get_atom_from_nr(atoms, ai, loc)?.to_owned();
+        let aj = get_atom_from_nr(atoms, aj, loc)?.to_owned();
         let cs: Option<Vec<f64>> = match paras.get(4..) {
-            Some(s) => Some(s.iter().map(|&s| s.parse().unwrap()).collect()),
+            Some(s) => Some((0..s.len()).map(|i| loc.field(s, i, "parameter")).collect::<Result<_>>()?),
             None => None
         };
-        TopolConstraint{ ai, aj, funct, cs }
+        Ok(TopolConstraint{ ai, aj, funct, cs })
     }
 }
 
@@ -535,25 +1178,27 @@ impl Display for TopolConstraint {
 }
 
 impl TopolAngle {
-    fn from(atoms: &Vec<TopolAtom>, line: &String) -> TopolAngle {
+    fn from(atoms: &[TopolAtom], line: &str, loc: &Location) -> Result<TopolAngle> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let ai: usize = paras[0].parse().unwrap();
-        let aj: usize = paras[1].parse().unwrap();
-        let ak: usize = paras[2].parse().unwrap();
-        let funct: i32 = paras[3].parse().unwrap();
-        let ai = get_atom_from_nr(atoms, ai).to_owned();
-        let aj = get_atom_from_nr(atoms, aj).to_owned();
-        let ak = get_atom_from_nr(atoms, ak).to_owned();
-        let c0: Option<f64> = get_param_at(&paras, 4);
-        let c1: Option<f64> = get_param_at(&paras, 5);
-        TopolAngle{ ai, aj, ak, funct, c0, c1 }
+        let ai: usize = loc.field(&paras, 0, "ai")?;
+        let aj: usize = loc.field(&paras, 1, "aj")?;
+        let ak: usize = loc.field(&paras, 2, "ak")?;
+        let funct: i32 = loc.field(&paras, 3, "funct")?;
+        let ai = get_atom_from_nr(atoms, ai, loc)?.to_owned();
+        let aj = get_atom_from_nr(atoms, aj, loc)?.to_owned();
+        let ak = get_atom_from_nr(atoms, ak, loc)?.to_owned();
+        let c0: Option<f64> = loc.opt_field(&paras, 4, "parameter")?;
+        let c1: Option<f64> = loc.opt_field(&paras, 5, "parameter")?;
+        check_params(line, &[c0, c1], loc)?;
+        Ok(TopolAngle{ ai, aj, ak, funct, c0, c1 })
     }
 
-    fn to_rtp(&self) -> String {
-        match self.c0 {
-            Some(c0) => format!("{:>7}{:>7}{:>7}{:10.2}{:9.2}", self.ai.atom, self.aj.atom, self.ak.atom, c0, self.c1.unwrap()),
-            None => format!("{:7}{:7}{:7}", self.ai.atom, self.aj.atom, self.ak.atom)
-        }
+    fn to_rtp(&self) -> RtpInteraction {
+        let params = match self.c0 {
+            Some(c0) => format!("{:10.2}{:9.2}", c0, self.c1.unwrap()),
+            None => "".to_string()
+        };
+        RtpInteraction { atoms: vec![self.ai.atom.to_string(), self.aj.atom.to_string(), self.ak.atom.to_string()], params }
     }
 }
 
@@ -568,40 +1213,45 @@ impl Display for TopolAngle {
 }
 
 impl TopolDihedral {
-    fn from(atoms: &Vec<TopolAtom>, line: &String) -> TopolDihedral {
+    fn from(atoms: &[TopolAtom], line: &str, loc: &Location) -> Result<TopolDihedral> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let ai: usize = paras[0].parse().unwrap();
-        let aj: usize = paras[1].parse().unwrap();
-        let ak: usize = paras[2].parse().unwrap();
-        let al: usize = paras[3].parse().unwrap();
-        let funct: i32 = paras[4].parse().unwrap();
-        let ai = get_atom_from_nr(atoms, ai).to_owned();
-        let aj = get_atom_from_nr(atoms, aj).to_owned();
-        let ak = get_atom_from_nr(atoms, ak).to_owned();
-        let al = get_atom_from_nr(atoms, al).to_owned();
-        let c0: Option<f64> = get_param_at(&paras, 5);
-        let c1: Option<f64> = get_param_at(&paras, 6);
-        let c2: Option<f64> = get_param_at(&paras, 7);
-        TopolDihedral{ ai, aj, ak, al, funct, c0, c1, c2 }
-    }
-    fn to_rtp(&self, ff: &str) -> String {
-        match self.c0 {
+        let ai: usize = loc.field(&paras, 0, "ai")?;
+        let aj: usize = loc.field(&paras, 1, "aj")?;
+        let ak: usize = loc.field(&paras, 2, "ak")?;
+        let al: usize = loc.field(&paras, 3, "al")?;
+        let funct: i32 = loc.field(&paras, 4, "funct")?;
+        let ai = get_atom_from_nr(atoms, ai, loc)?.to_owned();
+        let aj = get_atom_from_nr(atoms, aj, loc)?.to_owned();
+        let ak = get_atom_from_nr(atoms, ak, loc)?.to_owned();
+        let al = get_atom_from_nr(atoms, al, loc)?.to_owned();
+        let c0: Option<f64> = loc.opt_field(&paras, 5, "parameter")?;
+        let c1: Option<f64> = loc.opt_field(&paras, 6, "parameter")?;
+        let c2: Option<f64> = loc.opt_field(&paras, 7, "parameter")?;
+        // funct 2 只有两个参数, 其余写出时需要三个
+        match funct {
+            2 => check_params(line, &[c0, c1], loc)?,
+            _ => check_params(line, &[c0, c1, c2], loc)?,
+        }
+        Ok(TopolDihedral{ ai, aj, ak, al, funct, c0, c1, c2 })
+    }
+    fn to_rtp(&self, ff: &str) -> RtpInteraction {
+        let params = match self.c0 {
             Some(c0) => {
                 match self.funct {
                     // 如果是2且是amber力场, 将funct作为第一个参数, 提示删掉
                     2 => match ff {
-                        "amber" => format!("{:>7}{:>7}{:>7}{:>7}{:9}{:10.2}{:9.2}        ; Delete the default funct \"9\" before 2 after pdb2gmx!!!", 
-                                    self.ai.atom, self.aj.atom, self.ak.atom, self.al.atom, self.funct, c0, self.c1.unwrap()),
-                        "gromos" => format!("{:>7}{:>7}{:>7}{:>7}{:10.2}{:9.2}", 
-                                    self.ai.atom, self.aj.atom, self.ak.atom, self.al.atom, c0, self.c1.unwrap()),
+                        "amber" => format!("{:9}{:10.2}{:9.2}        ; Delete the default funct \"9\" before 2 after pdb2gmx!!!", 
+                                    self.funct, c0, self.c1.unwrap()),
+                        "gromos" => format!("{:10.2}{:9.2}", c0, self.c1.unwrap()),
                         _ => "".to_string()
                     },
-                    _ => format!("{:>7}{:>7}{:>7}{:>7}{:10.2}{:9.2}{:8}", 
-                                self.ai.atom, self.aj.atom, self.ak.atom, self.al.atom, c0, self.c1.unwrap(), self.c2.unwrap())
+                    _ => format!("{:10.2}{:9.2}{:8}", c0, self.c1.unwrap(), self.c2.unwrap())
                 }
             },
-            None => format!("{:>7}{:>7}{:>7}{:>7}", self.ai.atom, self.aj.atom, self.ak.atom, self.al.atom)
-        }
+            None => "".to_string()
+        };
+        let atoms = [&self.ai, &self.aj, &self.ak, &self.al].iter().map(|a| a.atom.to_string()).collect();
+        RtpInteraction { atoms, params }
     }
 }
 
@@ -623,14 +1273,12 @@ impl Display for TopolDihedral {
 }
 
 impl TopolExclusion {
-    fn from(atoms: &Vec<TopolAtom>, line: &String) -> TopolExclusion {
+    fn from(atoms: &[TopolAtom], line: &str, loc: &Location) -> Result<TopolExclusion> {
         let paras: Vec<&str> = line.split_whitespace().collect();
-        let atnums: Option<Vec<usize>> = match paras.get(..) {
-            Some(s) => Some(s.iter().map(|&s| s.parse().unwrap()).collect()),
-            None => None
-        };
-        let ex_atoms = atnums.unwrap().iter().map(|&a| get_atom_from_nr(&atoms, a).to_owned()).collect();
-        TopolExclusion{ ex_atoms }
+        let ex_atoms = (0..paras.len())
+            .map(|i| get_atom_from_nr(atoms, loc.field(&paras, i, "atom index")?, loc).cloned())
+            .collect::<Result<_>>()?;
+        Ok(TopolExclusion{ ex_atoms })
     }
 }
 
@@ -644,10 +1292,57 @@ impl Display for TopolExclusion {
     }
 }
 
-fn get_param_at<T: FromStr>(paras: &Vec<&str>, id: usize) -> Option<T> where <T as FromStr>::Err: Debug {
-    let p: Option<T> = match paras.get(id) {
-        Some(&s) => Some(s.parse().unwrap()),
-        None => None
-    };
-    return p
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOC: Location = Location { file: "TYS.itp", line: 1, section: "[ bonds ]" };
+
+    fn atoms() -> Vec<TopolAtom> {
+        ["1 c 1 MOL C 1 0.0 12.01", "2 o 1 MOL O 2 0.0 16.00", "3 c3 1 MOL C3 3 0.0 12.01", "4 hc 1 MOL H31 4 0.0 1.008"]
+            .iter().map(|l| TopolAtom::from(l, &LOC).unwrap()).collect()
+    }
+
+    #[test]
+    fn bond_with_only_r0_is_an_error() {
+        let err = TopolBond::from(&atoms(), "1 2 1 0.121830", &LOC).err().unwrap();
+        assert!(err.to_string().contains("expected 2 parameters"), "{}", err);
+        let bond = TopolBond::from(&atoms(), "1 2 1 0.121830 5.336274E+05", &LOC).unwrap();
+        assert_eq!(bond.to_rtp().params, format!("{:13.6}{:13.6e}", 0.12183, 5.336274e5));
+        assert_eq!(TopolBond::from(&atoms(), "1 2 1", &LOC).unwrap().to_rtp().params, "");
+    }
+
+    #[test]
+    fn angle_with_only_theta_is_an_error() {
+        assert!(TopolAngle::from(&atoms(), "2 1 3 1 123.20", &LOC).is_err());
+        assert!(TopolAngle::from(&atoms(), "2 1 3 1 123.20 564.00", &LOC).is_ok());
+    }
+
+    #[test]
+    fn dihedral_without_multiplicity_is_an_error() {
+        let err = TopolDihedral::from(&atoms(), "2 1 3 4 9 0.0 3.5", &LOC).err().unwrap();
+        assert!(err.to_string().contains("expected 3 parameters"), "{}", err);
+        // funct 2 的 improper 只有两个参数
+        let improper = TopolDihedral::from(&atoms(), "2 1 3 4 2 0.0 3.5", &LOC).unwrap();
+        assert_eq!(improper.to_rtp("gromos").params, format!("{:10.2}{:9.2}", 0.0, 3.5));
+        let proper = TopolDihedral::from(&atoms(), "2 1 3 4 9 0.0 3.5 3", &LOC).unwrap();
+        assert_eq!(proper.to_string().split_whitespace().last(), Some("3"));
+    }
+
+    #[test]
+    fn atomtypes_of_forcefield_includes_are_not_new() {
+        let dir = std::env::temp_dir().join(format!("gen-rtp_{}_top", std::process::id()));
+        fs::create_dir_all(dir.join("amber99sb.ff")).unwrap();
+        fs::write(dir.join("amber99sb.ff/forcefield.itp"), "[ defaults ]\n1 2 yes 0.5 0.8333\n\n[ atomtypes ]\n\
+            CT 6 12.01 0.0000 A 3.39967e-01 4.57730e-01\nOW 8 16.00 0.0000 A 3.15061e-01 6.36386e-01\n").unwrap();
+        fs::copy("examples/TYS.itp", dir.join("TYS.itp")).unwrap();
+        let top = dir.join("TYS.top");
+        fs::write(&top, "#include \"amber99sb.ff/forcefield.itp\"\n#include \"TYS.itp\"\n\n[ system ]\nTYS\n\n[ molecules ]\nTYS 1\n").unwrap();
+        let mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        let topol = Topol::from(top.to_str().unwrap(), &mol2, &ResidueOptions::default());
+        fs::remove_dir_all(&dir).unwrap();
+        let mut names: Vec<String> = topol.unwrap().atomtypes.into_iter().map(|a| a.name).collect();
+        names.sort();
+        assert_eq!(names, ["c", "c3", "ca", "h1", "ha", "hc", "hn", "n", "o", "os", "s6"]);
+    }
 }
@@ -0,0 +1,41 @@
+//! Generate GROMACS `rtp` and `hdb` entries of a nonstandard residue from a capped molecule:
+//! a `mol2` giving the structure and an `itp` (from Sobtop, acpype, ...) giving the parameters.
+//!
+//! ```no_run
+//! use gen_rtp::mol2::MOL2;
+//! use gen_rtp::residue::{convert, fix_atom_names, Neighbour, ResidueOptions};
+//!
+//! # fn main() -> gen_rtp::error::Result<()> {
+//! let options = ResidueOptions {
+//!     prev: Some(Neighbour { atoms: (1..=6).collect(), con_atom: 1, con_name: "-C".to_string(), adj_atom: 7, adj_name: "N".to_string() }),
+//!     next: Some(Neighbour { atoms: (31..=36).collect(), con_atom: 31, con_name: "+N".to_string(), adj_atom: 9, adj_name: "C".to_string() }),
+//!     ..Default::default()
+//! };
+//! let mut mol2 = MOL2::from("TYS.mol2")?;
+//! fix_atom_names(&mut mol2, &options)?;
+//! let residue = convert(&mol2, "TYS.itp", &options)?;
+//! println!("{}", residue.rtp_file(&options.ff));
+//! println!("{}", residue.hdb_file());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Malformed input files are reported as [`error::Error`] with the file, line, section and token.
+
+pub mod bonded;
+pub mod boundary;
+pub mod charge;
+pub mod error;
+pub mod hdb;
+pub mod install;
+pub mod itp;
+pub mod mapping;
+pub mod mol2;
+pub mod naming;
+pub mod nonbonded;
+pub mod output;
+pub mod preprocess;
+pub mod residue;
+pub mod rtp;
+pub mod specbond;
+pub mod utils;
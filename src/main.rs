@@ -1,18 +1,91 @@
-mod mol2;
-mod itp;
-mod hdb;
-mod utils;
+mod cli;
+mod convert;
+mod batch;
+mod apply;
 
-use mol2::MOL2;
-use hdb::get_adj_h_id;
-use itp::Topol;
+use cli::{Args, USAGE};
+use convert::convert;
+use gen_rtp::error::Error;
+use gen_rtp::install::Manifest;
+use gen_rtp::utils;
 use std::io;
 use std::env;
+use std::fs;
 use std::process::exit;
 
-use crate::utils::get_input;
-
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|s| s.as_str()) == Some("batch") {
+        // batch 只接受输出相关选项和一个任务文件
+        let batch_args = match batch::parse_args(&args[1..]) {
+            Ok(batch_args) => batch_args,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                eprintln!("{}", USAGE);
+                exit(2)
+            }
+        };
+        let (job_file, overwrite, dry_run) = batch_args;
+        match batch::run(&job_file, overwrite, dry_run) {
+            Ok(true) => exit(0),
+            Ok(false) => exit(1),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(1)
+            }
+        }
+    }
+    if args.first().map(|s| s.as_str()) == Some("uninstall") {
+        let manifest = match Args::parse(&args[1..]) {
+            Ok(Args { mol2: Some(manifest), dry_run, .. }) => (manifest, dry_run),
+            Ok(_) => {
+                eprintln!("Error: `uninstall` requires exactly one manifest file.");
+                eprintln!("{}", USAGE);
+                exit(2)
+            },
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                eprintln!("{}", USAGE);
+                exit(2)
+            }
+        };
+        let (manifest, dry_run) = manifest;
+        match uninstall(&manifest, dry_run) {
+            Ok(true) => exit(0),
+            Ok(false) => exit(1),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(1)
+            }
+        }
+    }
+    if args.first().map(|s| s.as_str()) == Some("apply-map") {
+        match apply::run(&args[1..]) {
+            Ok(()) => exit(0),
+            Err(apply::ApplyError::Usage(e)) => {
+                eprintln!("Error: {}", e);
+                eprintln!("{}", USAGE);
+                exit(2)
+            },
+            Err(apply::ApplyError::Failed(e)) => {
+                eprintln!("Error: {}", e);
+                exit(1)
+            }
+        }
+    }
+    let args = match Args::parse(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("{}", USAGE);
+            exit(2)
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return
+    }
+
     // 读取mol2
     println!(" GEN-RTP v0.4: An `rtp` and `hdb` file generator from itp.");
     println!(" Totally used together with Sobtop or acpype, etc.");
@@ -20,125 +93,64 @@ fn main() {
     println!(" Developed by Jiaxing Zhang, at Tianjin University");
     println!(" Contact me: zhangjiaxing7137@tju.edu.cn");
     println!();
-    println!("Input path of `mol2` file, e.g. D:/Conan/Haibara_Ai.mol2");
-    println!("(Hint: You can directly load it by `gen-rtp Miyano_Shiho.mol2`)");
-    let args: Vec<String> = env::args().collect();
-    let mol2_file = match args.len() {
-        1 => utils::read_file(),
-        2 => args[1].to_owned(),
-        _ => {
-            println!("Too more arguments.");
-            io::stdin().read_line(&mut String::new()).unwrap();
-            exit(0)
+    let mol2_file = match (&args.mol2, args.interactive) {
+        (Some(f), _) => f.to_string(),
+        (None, true) => {
+            println!("Input path of `mol2` file, e.g. D:/Conan/Haibara_Ai.mol2");
+            println!("(Hint: You can directly load it by `gen-rtp Miyano_Shiho.mol2`)");
+            utils::read_file()
+        },
+        (None, false) => {
+            eprintln!("Error: no `mol2` file given.");
+            eprintln!("{}", USAGE);
+            exit(2)
         }
     };
-    println!("Reading mol2 file: {}", mol2_file);
     
     // 修改原子名
-    println!("Change heavy atom names to element+id? ([y]/n)");
-    let change_name = get_input("y".to_string());
-    let change_name = if change_name.starts_with(['y', 'Y']) {
-        true
-    } else {
-        false
-    };
-    
-    // 输入排除列表
-    let (prev_atoms, 
-         next_atoms, 
-         prev_con_atom, 
-         next_con_atom,
-         prev_con_atom_name,
-         next_con_atom_name,
-         prev_adj_atom,
-         next_adj_atom,
-         prev_adj_atom_name,
-         next_adj_atom_name) = utils::get_exclude_atoms();
-    
-    println!("Fixing atom names...");
-    let mol2 = &mut MOL2::from(mol2_file.as_str());
-    let mol2_bak = &mol2.clone();
-    let mut heavy_atom_id = 1;
-    for a in &mol2_bak.atoms {
-        if a.element.ne("H") {
-            // 修改重原子命名为元素名+该元素出现次数
-            if change_name {
-                mol2.atoms[a.atom_id - 1].atom_name = a.element.to_owned() + heavy_atom_id.to_string().as_str();
-                heavy_atom_id += 1;
-            }
-            if let Some(prev_adj_atom) = prev_adj_atom {
-                if a.atom_id == prev_adj_atom {
-                    mol2.atoms[prev_adj_atom - 1].atom_name = prev_adj_atom_name.to_owned().unwrap();
-                }
-            }
-            if let Some(next_adj_atom) = next_adj_atom {
-                if a.atom_id == next_adj_atom {
-                    mol2.atoms[next_adj_atom - 1].atom_name = next_adj_atom_name.to_owned().unwrap();
-                }
-            }
-            // 修改邻接H命名
-            let adj_h = get_adj_h_id(mol2, a.atom_id);
-            for (i, &h) in adj_h.iter().enumerate() {
-                // 根据相连H数量修改H名字
-                let h_basename = mol2.get_hbasename(&mol2.atoms[h as usize - 1]);
-                mol2.atoms[h as usize - 1].atom_name = match adj_h.len() {
-                    1 => h_basename,
-                    _ => h_basename + (i + 1).to_string().as_str()
-                };
-            }
+    let converted = match convert(&args, &mol2_file) {
+        Ok(converted) => converted,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1)
         }
-    }
-    println!("Finished fixing atom names.");
+    };
 
-    // 输出mol2
-    let mol2_stem = utils::get_stemname(&mol2_file);
-    let parent_path = utils::get_parent_path(&mol2_file);
-    let out = parent_path.join(mol2_stem.to_string() + ".mol2");
-    mol2.output(out.as_os_str().to_str().unwrap());
+    // 输出改名后的mol2及rtp和hdb, 默认与输入文件同目录
+    let output = converted.output(&args);
+    let written = converted.write_mol2(&args, &output)
+        .and_then(|_| converted.write_mapping(&args, &output))
+        .and_then(|_| converted.write_rtp(&args, &output))
+        .and_then(|_| converted.write_nonbonded(&args, &output))
+        .and_then(|_| converted.write_ffbonded(&args, &output))
+        .and_then(|_| converted.write_hdb(&args, &output))
+        .and_then(|_| converted.write_specbond(&args, &output))
+        .and_then(|_| converted.install(&args, &output));
+    if let Err(e) = written {
+        eprintln!("Error: {}", e);
+        exit(1)
+    }
 
-    // 读取itp, 更新H原子名, 选择性删除连接原子成键信息
-    let itp_file = parent_path.join(mol2_stem.to_string() + ".itp");
-    println!("Input the `itp` file name generated by Sobtop (default: {}):", itp_file.to_str().unwrap());
-    let inp = utils::get_input(itp_file.to_str().unwrap().to_string());
-    let itp_file = match inp.is_empty() {
-        true => utils::read_file(),
-        false => inp
-    };
-    let mut itp = Topol::from(itp_file.as_str(), mol2, 
-        &prev_atoms, 
-        &next_atoms,
-        prev_con_atom, 
-        next_con_atom,
-        &prev_con_atom_name, 
-        &next_con_atom_name,
-        prev_adj_atom, 
-        next_adj_atom,
-        &prev_adj_atom_name, 
-        &next_adj_atom_name,
-    );
-    // 输出rtp, 特殊处理2号规则
-    let itp_stem = utils::get_stemname(&itp_file);
-    let parent_path = utils::get_parent_path(&itp_file);
-    let rtp_name = itp_stem.to_string() + ".rtp";
-    let out = &parent_path.join(rtp_name);
-    let out = out.as_os_str().to_str().unwrap();
-    itp.to_rtp(out, "amber", &prev_atoms, &next_atoms);
-    // 输出hdb, 根据H类型
-    let hdb_name = itp_stem + ".hdb";
-    let out = parent_path.join(hdb_name);
-    let out = out.as_os_str().to_str().unwrap();
-    mol2.to_hdb(out,
-        &prev_atoms, 
-        &next_atoms, 
-        prev_con_atom, 
-        next_con_atom,
-        &prev_con_atom_name, 
-        &next_con_atom_name,
-        prev_adj_atom, 
-        next_adj_atom,
-        &prev_adj_atom_name, 
-        &next_adj_atom_name,);
+    if args.interactive {
+        println!("Press any key to exit");
+        io::stdin().read_line(&mut String::new()).expect("Failed to read line");
+    }
+}
 
-    println!("Press any key to exit");
-    io::stdin().read_line(&mut String::new()).expect("Failed to read line");
+// 按清单撤销安装, 全部撤销后删除清单, 返回是否全部成功
+fn uninstall(file: &str, dry_run: bool) -> Result<bool, Error> {
+    let manifest = Manifest::from(file)?;
+    let failed = manifest.revert(&utils::get_parent_path(file), dry_run)?;
+    if failed > 0 {
+        println!("{} of {} changes could not be reverted, kept {}", failed, manifest.changes.len(), file);
+        return Ok(false);
+    }
+    match dry_run {
+        true => println!("[dry-run] Would remove {}", file),
+        false => {
+            fs::remove_file(file).map_err(|e| Error::io(file, e))?;
+            println!("Uninstalled {}", manifest.residues.join(", "));
+        },
+    }
+    Ok(true)
 }
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::error::{Error, Location, Result};
+use crate::mol2::MOL2;
+use crate::residue::ResidueOptions;
+
+/// Which part of the capped molecule an atom belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Atom of the previous residue.
+    Prev,
+    /// Atom of the next residue.
+    Next,
+    /// Atom of a residue joined by an extra link.
+    Link,
+    /// Connection atom, on either side of an inter-residue bond.
+    Connection,
+    /// Other atom of the current residue.
+    Body,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Prev => write!(f, "prev"),
+            Role::Next => write!(f, "next"),
+            Role::Link => write!(f, "link"),
+            Role::Connection => write!(f, "connection"),
+            Role::Body => write!(f, "body"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Role, String> {
+        match s {
+            "prev" => Ok(Role::Prev),
+            "next" => Ok(Role::Next),
+            "link" => Ok(Role::Link),
+            "connection" => Ok(Role::Connection),
+            "body" => Ok(Role::Body),
+            _ => Err(format!("unknown role `{}`", s)),
+        }
+    }
+}
+
+/// One renamed atom.
+#[derive(Clone, Debug)]
+pub struct NameMap {
+    /// Atom id in the mol2 and itp.
+    pub id: usize,
+    pub old_name: String,
+    pub new_name: String,
+    pub element: String,
+    pub role: Role,
+}
+
+/// The old→new atom names of a renamed molecule, written as CSV or JSON.
+#[derive(Clone, Debug, Default)]
+pub struct Mapping {
+    pub atoms: Vec<NameMap>,
+    /// Name of the residue the atoms belong to; only atoms of this residue are renamed in a PDB.
+    /// Missing in mappings written before it was recorded.
+    pub resname: Option<String>,
+}
+
+impl Mapping {
+    /// Compares the atom names of `old` and its renamed copy `new`.
+    pub fn new(old: &MOL2, new: &MOL2, options: &ResidueOptions) -> Mapping {
+        let atoms = old.atoms.iter().zip(&new.atoms).map(|(o, n)| {
+            let connection = options.prev.iter().chain(options.next.iter())
+                .any(|nb| nb.con_atom == o.atom_id || nb.adj_atom == o.atom_id)
+                || options.links.iter().any(|l| l.con_atom == o.atom_id || l.adj_atom == o.atom_id);
+            let role = match connection {
+                true => Role::Connection,
+                false if options.prev_atoms().contains(&o.atom_id) => Role::Prev,
+                false if options.next_atoms().contains(&o.atom_id) => Role::Next,
+                false if options.is_outside(o.atom_id) => Role::Link,
+                false => Role::Body,
+            };
+            NameMap {
+                id: o.atom_id, old_name: o.atom_name.to_string(), new_name: n.atom_name.to_string(),
+                element: o.element.to_string(), role
+            }
+        }).collect();
+        Mapping { atoms, resname: Some(new.resname.to_string()) }
+    }
+
+    /// Reads a mapping file written by [`Mapping::to_csv`] or [`Mapping::to_json`] (by extension).
+    pub fn from(file: &str) -> Result<Mapping> {
+        let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let is_json = Path::new(file).extension().is_some_and(|e| e.eq_ignore_ascii_case("json"));
+        let mut atoms = vec![];
+        let mut resname: Option<String> = None;
+        match is_json {
+            true => {
+                // 只解析本程序写出的格式: 每行一个对象
+                let re_obj = Regex::new(r"\{[^}]*\}").unwrap();
+                let re_kv = Regex::new(r#""(\w+)"\s*:\s*(?:"((?:[^"\\]|\\.)*)"|(-?\d+))"#).unwrap();
+                for (ln, line) in content.lines().enumerate() {
+                    for obj in re_obj.find_iter(line) {
+                        let loc = Location { file, line: ln + 1, section: "mapping" };
+                        let kv: HashMap<&str, &str> = re_kv.captures_iter(obj.as_str())
+                            .map(|c| (c.get(1).unwrap().as_str(), c.get(2).or(c.get(3)).unwrap().as_str()))
+                            .collect();
+                        let fields: Vec<&str> = ["id", "old_name", "new_name", "element", "role"].iter()
+                            .map(|k| kv.get(k).copied().unwrap_or(""))
+                            .collect();
+                        atoms.push(NameMap::from_fields(&fields, &loc)?);
+                        resname = resname.or(kv.get("resname").map(|r| r.to_string()));
+                    }
+                }
+            },
+            false => for (ln, line) in content.lines().enumerate().skip(1) {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let loc = Location { file, line: ln + 1, section: "mapping" };
+                let fields: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+                atoms.push(NameMap::from_fields(&fields, &loc)?);
+                resname = resname.or(loc.opt_field(&fields, 5, "residue name")?);
+            },
+        }
+        Ok(Mapping { atoms, resname: resname.filter(|r| !r.is_empty()) })
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = "id,old_name,new_name,element,role,resname\n".to_string();
+        let resname = self.resname.as_deref().unwrap_or("");
+        for a in &self.atoms {
+            out.push_str(&format!("{},{},{},{},{},{}\n", a.id, a.old_name, a.new_name, a.element, a.role, resname));
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let resname = self.resname.as_deref().unwrap_or("");
+        let lines: Vec<String> = self.atoms.iter().map(|a| {
+            format!("  {{\"id\": {}, \"old_name\": \"{}\", \"new_name\": \"{}\", \"element\": \"{}\", \"role\": \"{}\", \"resname\": \"{}\"}}",
+                a.id, a.old_name, a.new_name, a.element, a.role, resname)
+        }).collect();
+        format!("[\n{}\n]\n", lines.join(",\n"))
+    }
+
+    /// New name of atom `id`, if it was `old_name`.
+    pub fn by_id(&self, id: usize, old_name: &str) -> Option<&str> {
+        self.atoms.iter().find(|a| a.id == id && a.old_name == old_name).map(|a| a.new_name.as_str())
+    }
+
+    /// New name of `old_name`, if no other atom had the same old name.
+    pub fn by_name(&self, old_name: &str) -> Option<&str> {
+        let mut found = self.atoms.iter().filter(|a| a.old_name == old_name);
+        match (found.next(), found.next()) {
+            (Some(a), None) => Some(a.new_name.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Whether a PDB `ATOM`/`HETATM` line belongs to the residue of the mapping (any residue if its name
+    /// is unknown) and, if given, to residue number `resid`.
+    fn in_scope(&self, line: &str, resid: Option<i32>) -> bool {
+        let resname = line.get(17..21).map(|r| r.trim());
+        let number = line.get(22..26).and_then(|n| n.trim().parse().ok());
+        self.resname.as_ref().is_none_or(|r| resname == Some(r.as_str())) && resid.is_none_or(|id| number == Some(id))
+    }
+
+    /// Serial numbers of the atoms of a PDB file the mapping applies to, see [`Mapping::apply_pdb`].
+    pub fn pdb_atoms(&self, content: &str, resid: Option<i32>) -> Vec<usize> {
+        content.lines()
+            .filter(|line| matches!(line.get(..6), Some("ATOM  " | "HETATM")) && self.in_scope(line, resid))
+            .filter_map(|line| line.get(6..11).and_then(|s| s.trim().parse().ok()))
+            .collect()
+    }
+
+    /// Renames the atoms of a PDB file, matching by serial number and old name (or old name only). Only
+    /// the atoms of the residue named [`Mapping::resname`] (numbered `resid`, if given) are renamed.
+    pub fn apply_pdb(&self, content: &str, resid: Option<i32>) -> String {
+        let lines: Vec<String> = content.lines().map(|line| {
+            let (serial, old) = match (line.get(..6), line.get(6..11), line.get(12..16)) {
+                (Some("ATOM  " | "HETATM"), Some(serial), Some(old)) if self.in_scope(line, resid) =>
+                    (serial.trim().parse().unwrap_or(0), old.trim()),
+                _ => return line.to_string(),
+            };
+            match self.by_id(serial, old).or_else(|| self.by_name(old)) {
+                // 4个字符的原子名从第13列开始, 其余从第14列开始
+                Some(new) => {
+                    let new = match new.len() {
+                        4.. => new.to_string(),
+                        _ => format!(" {:<3}", new),
+                    };
+                    format!("{}{}{}", &line[..12], new, &line[16..])
+                },
+                None => line.to_string(),
+            }
+        }).collect();
+        lines.join("\n") + "\n"
+    }
+
+    /// Renames index groups of a `.ndx` file named after an atom, e.g. `[ C1 ]` or `[ a_C1 ]`. With
+    /// `atoms` (see [`Mapping::pdb_atoms`]), only groups made up of those atoms are renamed.
+    pub fn apply_ndx(&self, content: &str, atoms: Option<&[usize]>) -> String {
+        let re = Regex::new(r"^(\s*\[\s*)(a_)?(\S+)(\s*\].*)$").unwrap();
+        // 先读出每组的原子序号
+        let mut groups: Vec<Vec<usize>> = vec![];
+        for line in content.lines() {
+            match re.is_match(line) {
+                true => groups.push(vec![]),
+                false => if let Some(g) = groups.last_mut() {
+                    g.extend(line.split_whitespace().filter_map(|s| s.parse::<usize>().ok()));
+                },
+            }
+        }
+        let in_scope = |group: &[usize]| atoms.is_none_or(|atoms| !group.is_empty() && group.iter().all(|a| atoms.contains(a)));
+        let mut group = 0;
+        let lines: Vec<String> = content.lines().map(|line| {
+            match re.captures(line) {
+                Some(c) => {
+                    group += 1;
+                    match self.by_name(&c[3]).filter(|_| in_scope(&groups[group - 1])) {
+                        Some(new) => format!("{}{}{}{}", &c[1], c.get(2).map_or("", |m| m.as_str()), new, &c[4]),
+                        None => line.to_string(),
+                    }
+                },
+                None => line.to_string(),
+            }
+        }).collect();
+        lines.join("\n") + "\n"
+    }
+
+    /// Renames the atom column of `[ atoms ]` (matching by nr) and atom names in the comments of other
+    /// sections of an itp, e.g. a restraint itp.
+    pub fn apply_itp(&self, content: &str) -> String {
+        let re_section = Regex::new(r"^\s*\[\s*(\w+)\s*\]").unwrap();
+        let re_word = Regex::new(r"[^\s,;()\-]+").unwrap();
+        let mut section = String::new();
+        let lines: Vec<String> = content.lines().map(|line| {
+            if let Some(c) = re_section.captures(line) {
+                section = c[1].to_string();
+                return line.to_string();
+            }
+            let (data, comment) = match line.find(';') {
+                Some(i) => line.split_at(i),
+                None => (line, ""),
+            };
+            let fields: Vec<&str> = data.split_whitespace().collect();
+            let data = match (section.as_str(), fields.first().and_then(|s| s.parse().ok()), fields.get(4)) {
+                ("atoms", Some(nr), Some(&old)) => match self.by_id(nr, old) {
+                    // 原位替换第5列, 尽量保持列宽
+                    Some(new) => replace_field(data, 4, new),
+                    None => data.to_string(),
+                },
+                _ => data.to_string(),
+            };
+            let comment = match section.as_str() {
+                "atoms" => comment.to_string(),
+                _ => re_word.replace_all(comment, |c: &regex::Captures| {
+                    self.by_name(&c[0]).unwrap_or(&c[0]).to_string()
+                }).to_string(),
+            };
+            data + &comment
+        }).collect();
+        lines.join("\n") + "\n"
+    }
+}
+
+impl NameMap {
+    fn from_fields(fields: &[&str], loc: &Location) -> Result<NameMap> {
+        let role: String = loc.field(fields, 4, "role")?;
+        Ok(NameMap {
+            id: loc.field(fields, 0, "atom id")?,
+            old_name: loc.field(fields, 1, "old name")?,
+            new_name: loc.field(fields, 2, "new name")?,
+            element: loc.field(fields, 3, "element")?,
+            role: role.parse().map_err(|e: String| loc.error(&role, &e))?,
+        })
+    }
+}
+
+// 替换第 id 个字段, 与原字段右对齐, 过长时占用前面的空格
+fn replace_field(line: &str, id: usize, new: &str) -> String {
+    let re = Regex::new(r"\S+").unwrap();
+    let found = re.find_iter(line).nth(id);
+    match found {
+        Some(m) => {
+            let prev_end = line[..m.start()].trim_end().len();
+            let start = m.end().saturating_sub(new.len()).max(prev_end + 1).min(m.start());
+            let width = m.end() - start;
+            format!("{}{:>width$}{}", &line[..start], new, &line[m.end()..], width = width)
+        },
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naming::Naming;
+    use crate::residue::{fix_atom_names, Neighbour};
+
+    fn pdb_line(serial: usize, name: &str, resname: &str, resid: i32) -> String {
+        format!("ATOM  {:>5}  {:<3} {:>3} A{:>4}      10.000  10.000  10.000  1.00  0.00", serial, name, resname, resid)
+    }
+
+    #[test]
+    fn only_the_mapped_residue_is_renamed() {
+        let options = ResidueOptions {
+            prev: Some(Neighbour { atoms: (1..=6).collect(), con_atom: 1, con_name: "-C".to_string(), adj_atom: 7, adj_name: "N".to_string() }),
+            next: Some(Neighbour { atoms: (31..=36).collect(), con_atom: 31, con_name: "+N".to_string(), adj_atom: 9, adj_name: "C".to_string() }),
+            naming: Naming::Amino,
+            ..Default::default()
+        };
+        let old = MOL2::from("examples/TYS.mol2").unwrap();
+        let mut new = old.clone();
+        fix_atom_names(&mut new, &options).unwrap();
+        let mapping = Mapping::new(&old, &new, &options);
+        assert_eq!(mapping.resname.as_deref(), Some("TYS"));
+        let atom = mapping.atoms.iter().find(|a| a.old_name == "C5").unwrap();
+        assert_eq!(atom.new_name, "CA");
+
+        let pdb = [pdb_line(100, "C5", "ALA", 1), pdb_line(101, "C5", "TYS", 2), pdb_line(102, "C5", "TYS", 3)].join("\n");
+        let renamed: Vec<String> = mapping.apply_pdb(&pdb, None).lines().map(|l| l[12..16].trim().to_string()).collect();
+        assert_eq!(renamed, ["C5", "CA", "CA"]);
+        let renamed: Vec<String> = mapping.apply_pdb(&pdb, Some(3)).lines().map(|l| l[12..16].trim().to_string()).collect();
+        assert_eq!(renamed, ["C5", "C5", "CA"]);
+
+        let ndx = "[ C5 ]\n 100 101\n[ a_C5 ]\n 101 102\n";
+        let atoms = mapping.pdb_atoms(&pdb, None);
+        assert_eq!(atoms, [101, 102]);
+        assert_eq!(mapping.apply_ndx(ndx, Some(&atoms)), "[ C5 ]\n 100 101\n[ a_CA ]\n 101 102\n");
+    }
+
+    #[test]
+    fn resname_is_read_back() {
+        let atom = NameMap { id: 8, old_name: "C5".to_string(), new_name: "CA".to_string(), element: "C".to_string(), role: Role::Body };
+        let mapping = Mapping { atoms: vec![atom], resname: Some("TYS".to_string()) };
+        for (ext, content) in [("csv", mapping.to_csv()), ("json", mapping.to_json())] {
+            let file = std::env::temp_dir().join(format!("gen-rtp_{}_TYS_names.{}", std::process::id(), ext));
+            fs::write(&file, content).unwrap();
+            let read = Mapping::from(file.to_str().unwrap()).unwrap();
+            fs::remove_file(&file).unwrap();
+            assert_eq!(read.resname.as_deref(), Some("TYS"));
+            assert_eq!(read.by_id(8, "C5"), Some("CA"));
+        }
+    }
+}
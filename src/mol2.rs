@@ -1,266 +1,540 @@
-use std::process::exit;
-use std::{fs, io::Write};
+use std::fs;
 use std::path::Path;
-use crate::hdb::HDBItem;
+use crate::error::{Error, Location, Result};
+use crate::hdb::{HDBItem, HdbResidue};
+use crate::residue::ResidueOptions;
 use crate::hdb::{get_adj_h_id, get_adj_heavy_id, get_htype_from_heavy_atom};
 use std::fmt::{self, Debug, Display};
 
-#[derive(Debug)]
-#[allow(dead_code)]
-#[derive(Clone)]
+/// One molecule of a Tripos mol2 file.
+#[derive(Clone, Debug)]
 pub struct MOL2 {
+    /// Residue name of the generated rtp and hdb, the file stem by default; not written to the mol2.
+    pub resname: String,
     pub mol: Molecule,
     pub atoms: Vec<Atom>,
     pub bonds: Vec<Bond>,
+    pub substructures: Vec<Substructure>,
+    /// Lines of `@<TRIPOS>COMMENT`.
+    pub comment: Vec<String>,
+    pub crysin: Option<Crysin>,
+    pub atom_attrs: Vec<AtomAttr>,
+    /// Other sections, kept verbatim as (name, lines).
+    pub others: Vec<(String, Vec<String>)>,
+}
+
+// 一个分子中的一段 @<TRIPOS>, 行号从1开始
+struct Section<'a> {
+    name: &'a str,
+    lines: Vec<(usize, &'a str)>,
 }
 
 impl MOL2 {
-    pub fn new(mol: Molecule, atoms: Vec<Atom>, bonds: Vec<Bond>) -> MOL2 {
-        MOL2 { mol, atoms, bonds }
+    pub fn new(resname: &str, mol: Molecule, atoms: Vec<Atom>, bonds: Vec<Bond>) -> MOL2 {
+        MOL2 {
+            resname: resname.to_string(), mol, atoms, bonds,
+            substructures: vec![], comment: vec![], crysin: None, atom_attrs: vec![], others: vec![]
+        }
     }
-    pub fn from(file: &str) -> MOL2 {
-        // 读取文件
-        let mol2_content = fs::read_to_string(file).unwrap();
-        let mut mol2_content: Vec<&str> = mol2_content.split("\n").collect();
-        mol2_content.iter_mut().for_each(|s| *s = s.trim());
-
-        // Molecule定位
-        let mol_ln = mol2_content.iter().enumerate().find_map(|(index, &s)| {
-            if s.eq("@<TRIPOS>MOLECULE") {
-                Some(index)
-            } else {
-                None
+
+    /// Reads the first molecule of a Tripos mol2 file.
+    pub fn from(file: &str) -> Result<MOL2> {
+        let mut mols = MOL2::read_all(file)?;
+        if mols.len() > 1 {
+            println!("Found {} molecules in {}, using the first one.", mols.len(), file);
+        }
+        Ok(mols.remove(0))
+    }
+
+    /// Reads all molecules of a Tripos mol2 file.
+    pub fn read_all(file: &str) -> Result<Vec<MOL2>> {
+        let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let resname = Path::new(file).file_stem().and_then(|s| s.to_str()).unwrap_or("MOL");
+
+        // 按 @<TRIPOS> 切分, 第一段之前的内容(如旧版本写入的 `;` 行)忽略
+        let mut mols: Vec<Vec<Section>> = vec![];
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim_end();
+            if let Some(name) = line.trim().strip_prefix("@<TRIPOS>") {
+                if name == "MOLECULE" {
+                    mols.push(vec![]);
+                }
+                match mols.last_mut() {
+                    Some(m) => m.push(Section { name, lines: vec![] }),
+                    None => return Err(Location { file, line: i + 1, section: "header" }
+                        .error(line, "section before @<TRIPOS>MOLECULE")),
+                }
+            } else if let Some(s) = mols.last_mut().and_then(|m| m.last_mut()) {
+                if !line.trim_start().starts_with('#') {
+                    s.lines.push((i + 1, line));
+                }
             }
-        }).unwrap();
+        }
+        if mols.is_empty() {
+            return Err(Error::MissingSection { file: file.to_string(), section: "@<TRIPOS>MOLECULE".to_string() });
+        }
+        mols.iter().map(|sections| MOL2::from_sections(file, resname, sections)).collect()
+    }
+
+    fn from_sections(file: &str, resname: &str, sections: &[Section]) -> Result<MOL2> {
+        let find_section = |name: &str| sections.iter().find(|s| s.name == name)
+            .ok_or_else(|| Error::MissingSection { file: file.to_string(), section: format!("@<TRIPOS>{}", name) });
+        let non_empty = |s: &Section| -> Vec<(usize, String)> {
+            s.lines.iter().filter(|(_, l)| !l.trim().is_empty()).map(|(i, l)| (*i, l.trim().to_string())).collect()
+        };
 
         // Molecule字段
-        let sys_name = Path::new(file).file_stem().unwrap().to_str().unwrap();
-        let num: Vec<i32> = mol2_content[mol_ln + 2].trim().split_whitespace().map(|s| s.parse().unwrap()).collect();
-        let at_num = num.get(0);
-        if at_num.is_none() {
-            println!("Error: atom number is 0.");
-            exit(0);
+        let mol = Molecule::from(file, &find_section("MOLECULE")?.lines)?;
+        let mut mol2 = MOL2::new(resname, mol, vec![], vec![]);
+
+        // Atom字段
+        let section = find_section("ATOM")?;
+        for (ln, line) in non_empty(section) {
+            let loc = Location { file, line: ln, section: "@<TRIPOS>ATOM" };
+            let atom = Atom::from(&line, &loc)?;
+            // 原子编号须从1连续编号, 后续按编号索引
+            if atom.atom_id != mol2.atoms.len() + 1 {
+                return Err(loc.error(line.split_whitespace().next().unwrap_or(""),
+                    &format!("atom id should be {}", mol2.atoms.len() + 1)));
+            }
+            mol2.atoms.push(atom);
         }
-        let bond_num = num.get(1);
-        if bond_num.is_none() {
-            println!("Error: bond number is 0.");
-            exit(0);
+        if mol2.atoms.len() != mol2.mol.num_atoms {
+            let ln = section.lines.last().map_or(0, |l| l.0);
+            return Err(Location { file, line: ln, section: "@<TRIPOS>ATOM" }
+                .error("", &format!("expected {} atoms, found {}", mol2.mol.num_atoms, mol2.atoms.len())));
         }
-        let sub_struct_num = num.get(2);
-        let prop_num = num.get(3);
-        let set_num = num.get(4);
-        let sys_type = Some(mol2_content[mol_ln + 3].trim().to_string());
-        let at_charge = Some(mol2_content[mol_ln + 4].trim().to_string());
-        let mol = Molecule::new(sys_name, at_num, bond_num, sub_struct_num, prop_num, set_num, sys_type, at_charge);
 
-        // Atom字段
-        let atom_ln = mol2_content.iter().enumerate().find_map(|(index, &s)| {
-            if s.eq("@<TRIPOS>ATOM") {
-                Some(index)
-            } else {
-                None
+        // Bond字段, 允许缺失
+        if let Ok(section) = find_section("BOND") {
+            for (ln, line) in non_empty(section) {
+                let loc = Location { file, line: ln, section: "@<TRIPOS>BOND" };
+                let bond = Bond::from(&line, &loc)?;
+                for a in [bond.a1, bond.a2] {
+                    if a == 0 || a > mol2.atoms.len() {
+                        return Err(loc.error(&a.to_string(), "bond to an undefined atom"));
+                    }
+                }
+                mol2.bonds.push(bond);
             }
-        }).unwrap();
-        let mut atoms: Vec<Atom> = Vec::new();
-        for &at_line in &mol2_content[atom_ln + 1 .. atom_ln + 1 + *at_num.unwrap() as usize] {
-            let mut atom = Atom::from(at_line);
-            atom.sub_struct_name = sys_name.to_string();
-            atoms.push(atom);
         }
-        
-        // Bond字段
-        let bond_ln = mol2_content.iter().enumerate().find_map(|(index, &s)| {
-            if s.eq("@<TRIPOS>BOND") {
-                Some(index)
-            } else {
-                None
+        if mol2.bonds.len() != mol2.mol.num_bonds {
+            return Err(Error::Invalid(format!("{}: expected {} bonds in @<TRIPOS>BOND, found {}",
+                file, mol2.mol.num_bonds, mol2.bonds.len())));
+        }
+
+        // 其余字段
+        for section in sections {
+            let label = format!("@<TRIPOS>{}", section.name);
+            match section.name {
+                "MOLECULE" | "ATOM" | "BOND" => (),
+                "SUBSTRUCTURE" => for (ln, line) in non_empty(section) {
+                    let loc = Location { file, line: ln, section: &label };
+                    mol2.substructures.push(Substructure::from(&line, &loc)?);
+                },
+                "COMMENT" => {
+                    mol2.comment = section.lines.iter().map(|(_, l)| l.to_string()).collect();
+                    while mol2.comment.last().is_some_and(|l| l.trim().is_empty()) {
+                        mol2.comment.pop();
+                    }
+                },
+                "CRYSIN" => if let Some((ln, line)) = non_empty(section).first() {
+                    let loc = Location { file, line: *ln, section: &label };
+                    mol2.crysin = Some(Crysin::from(line, &loc)?);
+                },
+                "UNITY_ATOM_ATTR" => {
+                    let lines = non_empty(section);
+                    let mut lines = lines.iter();
+                    while let Some((ln, line)) = lines.next() {
+                        let loc = Location { file, line: *ln, section: &label };
+                        let fields: Vec<&str> = line.split_whitespace().collect();
+                        let atom_id: usize = loc.field(&fields, 0, "atom id")?;
+                        let num: usize = loc.field(&fields, 1, "number of attributes")?;
+                        let mut attrs = vec![];
+                        for _ in 0..num {
+                            let (ln, line) = lines.next().ok_or_else(|| loc.error(line, "missing attribute lines"))?;
+                            let loc = Location { file, line: *ln, section: &label };
+                            let (name, value) = line.split_once(char::is_whitespace)
+                                .ok_or_else(|| loc.error(line, "expected attribute name and value"))?;
+                            attrs.push((name.to_string(), value.trim().to_string()));
+                        }
+                        mol2.atom_attrs.push(AtomAttr { atom_id, attrs });
+                    }
+                },
+                name => {
+                    let mut lines: Vec<String> = section.lines.iter().map(|(_, l)| l.to_string()).collect();
+                    while lines.last().is_some_and(|l| l.trim().is_empty()) {
+                        lines.pop();
+                    }
+                    mol2.others.push((name.to_string(), lines));
+                },
             }
-        }).unwrap();
-        let mut bonds: Vec<Bond> = Vec::new();
-        for &bond_line in &mol2_content[bond_ln + 1 .. bond_ln + 1 + *bond_num.unwrap() as usize] {
-            bonds.push(Bond::from(bond_line));
         }
+        Ok(mol2)
+    }
 
-        // total mol2
-        MOL2::new(mol, atoms, bonds)
+    /// Writes the molecule to `outfile`.
+    pub fn output(&self, outfile: &str) -> Result<()> {
+        MOL2::write_all(outfile, std::slice::from_ref(self))
+    }
+
+    /// Writes all molecules to `outfile`.
+    pub fn write_all(outfile: &str, mols: &[MOL2]) -> Result<()> {
+        fs::write(outfile, MOL2::to_string_all(mols)).map_err(|e| Error::io(outfile, e))?;
+        println!("Written to {}", outfile);
+        Ok(())
     }
+
+    /// Content of a mol2 file holding all `mols`.
+    pub fn to_string_all(mols: &[MOL2]) -> String {
+        let content: Vec<String> = mols.iter().map(|m| m.to_string()).collect();
+        content.join("\n")
+    }
+}
+
+// 坐标和电荷至少保留4位小数, 更多位数时原样输出以免丢失精度
+fn fmt_num(v: f64, width: usize) -> String {
+    let s = format!("{:.4}", v);
+    let s = match s.parse::<f64>() == Ok(v) {
+        true => s,
+        false => v.to_string(),
+    };
+    format!("{:>width$}", s, width = width)
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-#[derive(Clone)]
+/// `@<TRIPOS>MOLECULE`.
+#[derive(Clone, Debug)]
 pub struct Molecule {
-    pub sys_name: String,
-    at_num: i32,
-    bond_num: i32,
-    sub_struct_num: i32,
-    prop_num: i32,
-    set_num: i32,
-    sys_type: String,
-    at_charge: String,
+    pub name: String,
+    pub num_atoms: usize,
+    pub num_bonds: usize,
+    pub num_subst: usize,
+    pub num_feat: usize,
+    pub num_sets: usize,
+    /// SMALL, BIOPOLYMER, PROTEIN, ...
+    pub mol_type: String,
+    /// NO_CHARGES, USER_CHARGES, GASTEIGER, ...
+    pub charge_type: String,
+    pub status_bits: Option<String>,
+    pub comment: Option<String>,
 }
 
-impl Display for Molecule {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "@<TRIPOS>MOLECULE\n{}\n{:5}{:6}     1 0 0\n{}\n{}\n\n", 
-            self.sys_name, self.at_num, self.bond_num, self.sys_type, self.at_charge)
+impl Molecule {
+    fn from(file: &str, lines: &[(usize, &str)]) -> Result<Molecule> {
+        // 前四行必需, 可能含空行
+        let mut lines = lines.iter().map(|(i, l)| (*i, l.trim()));
+        let mut next = |what: &str| -> Result<(usize, &str)> {
+            lines.next().ok_or_else(|| Error::Invalid(format!("{}: missing {} in @<TRIPOS>MOLECULE", file, what)))
+        };
+        let (_, name) = next("molecule name")?;
+        let (ln, counts) = next("atom and bond numbers")?;
+        let loc = Location { file, line: ln, section: "@<TRIPOS>MOLECULE" };
+        let fields: Vec<&str> = counts.split_whitespace().collect();
+        let num = |i: usize| loc.opt_field(&fields, i, "count").map(|n| n.unwrap_or(0));
+        let (num_atoms, num_bonds, num_subst, num_feat, num_sets) = (num(0)?, num(1)?, num(2)?, num(3)?, num(4)?);
+        if fields.is_empty() {
+            return Err(loc.error(counts, "missing atom number"));
+        }
+        let (_, mol_type) = next("molecule type")?;
+        let (_, charge_type) = next("charge type")?;
+        let mut rest: Vec<&str> = lines.map(|(_, l)| l).collect();
+        while rest.last().is_some_and(|l| l.is_empty()) {
+            rest.pop();
+        }
+        let opt = |s: Option<&&str>| s.filter(|s| !s.is_empty() && **s != "****").map(|s| s.to_string());
+        Ok(Molecule {
+            name: name.to_string(), num_atoms, num_bonds, num_subst, num_feat, num_sets,
+            mol_type: mol_type.to_string(), charge_type: charge_type.to_string(),
+            status_bits: opt(rest.first()), comment: opt(rest.get(1)),
+        })
     }
 }
 
-impl Molecule {
-    pub fn new(sys_name: &str, at_num: Option<&i32>, bond_num: Option<&i32>, sub_struct_num: Option<&i32>, prop_num: Option<&i32>, 
-               set_num: Option<&i32>, sys_type: Option<String>, at_charge: Option<String>) -> Molecule {
-        let sys_name = sys_name.to_string();
-        let at_num = *at_num.unwrap_or(&0);
-        let bond_num = *bond_num.unwrap_or(&0);
-        let sub_struct_num = *sub_struct_num.unwrap_or(&0);
-        let prop_num = *prop_num.unwrap_or(&0);
-        let set_num = *set_num.unwrap_or(&0);
-        let sys_type = sys_type.unwrap_or("SMALL".to_string());
-        let at_charge = at_charge.unwrap_or("USER_CHARGES".to_string());
-        Molecule {
-            sys_name, at_num, bond_num, sub_struct_num, prop_num, set_num, sys_type,at_charge
+impl Display for Molecule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "@<TRIPOS>MOLECULE\n{}", self.name)?;
+        writeln!(f, "{:5}{:6}{:6}{:2}{:2}", self.num_atoms, self.num_bonds, self.num_subst, self.num_feat, self.num_sets)?;
+        writeln!(f, "{}\n{}", self.mol_type, self.charge_type)?;
+        match (&self.status_bits, &self.comment) {
+            (status, Some(comment)) => writeln!(f, "{}\n{}", status.as_deref().unwrap_or("****"), comment)?,
+            (Some(status), None) => writeln!(f, "{}", status)?,
+            (None, None) => (),
         }
+        writeln!(f)
     }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-#[derive(Clone)]
+/// A line of `@<TRIPOS>ATOM`.
+#[derive(Clone, Debug)]
 pub struct Atom {
     pub atom_id: usize,
     pub atom_name: String,
-    x: f64,
-    y: f64,
-    z: f64,
-    at: String,
-    sub_struct_id: i32,
-    sub_struct_name: String,
-    atom_charge: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// SYBYL atom type, e.g. `C.ar`.
+    pub atom_type: String,
+    pub subst_id: Option<usize>,
+    pub subst_name: Option<String>,
+    pub charge: Option<f64>,
+    pub status_bit: Option<String>,
+    /// Element, taken from the atom type.
     pub element: String
 }
 
 impl Display for Atom {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:7} {:10}{:12.4}{:12.4}{:12.4} {:7}{:3} {:9}{:8.4}\n", 
-            self.atom_id, self.atom_name, self.x, self.y, self.z, self.at, 
-            self.sub_struct_id, self.sub_struct_name, self.atom_charge)
+        write!(f, "{:7} {:<8}{}{}{} {}", self.atom_id, self.atom_name,
+            fmt_num(self.x, 12), fmt_num(self.y, 12), fmt_num(self.z, 12), self.atom_type)?;
+        // 可选字段依次写出, 前一个缺失时后面的也不能写
+        if let Some(subst_id) = self.subst_id {
+            write!(f, "{:width$}{:3}", "", subst_id, width = 8usize.saturating_sub(self.atom_type.len()))?;
+            if let Some(subst_name) = &self.subst_name {
+                write!(f, " {:<8}", subst_name)?;
+                if let Some(charge) = self.charge {
+                    write!(f, "{}", fmt_num(charge, 10))?;
+                    if let Some(status_bit) = &self.status_bit {
+                        write!(f, " {}", status_bit)?;
+                    }
+                }
+            }
+        }
+        writeln!(f)
     }
 }
 
 impl Atom {
-    fn from(line: &str) -> Atom {
-        let line: Vec<&str> = line.trim().split_whitespace().collect();
-        let atom_id: usize = line[0].parse().unwrap();
-        let atom_name: String = line[1].to_string();
-        let x: f64 = line[2].parse().unwrap();
-        let y: f64 = line[3].parse().unwrap();
-        let z: f64 = line[4].parse().unwrap();
-        let at: String = line[5].to_string();
-        // 修改残基名为MOL, 残基编号为1
-        let sub_struct_id = 1;
-        let sub_struct_name = "MOL".to_string();
-        let atom_charge = line.get(8);
-        let atom_charge = atom_charge.and_then(|s| s.parse().ok()).unwrap_or(0.0);
-        let element: Vec<&str> = at.split(".").collect();
-        let element = element[0].to_string();
-        Atom {
-            atom_id, atom_name, x, y, z, at, sub_struct_id, sub_struct_name, atom_charge, element
-        }
+    fn from(line: &str, loc: &Location) -> Result<Atom> {
+        let line: Vec<&str> = line.split_whitespace().collect();
+        let atom_id: usize = loc.field(&line, 0, "atom id")?;
+        let atom_name: String = loc.field(&line, 1, "atom name")?;
+        let x: f64 = loc.field(&line, 2, "x coordinate")?;
+        let y: f64 = loc.field(&line, 3, "y coordinate")?;
+        let z: f64 = loc.field(&line, 4, "z coordinate")?;
+        let atom_type: String = loc.field(&line, 5, "atom type")?;
+        let subst_id = loc.opt_field(&line, 6, "substructure id")?;
+        let subst_name = loc.opt_field(&line, 7, "substructure name")?;
+        let charge = loc.opt_field(&line, 8, "charge")?;
+        let status_bit = line.get(9..).filter(|s| !s.is_empty()).map(|s| s.join(" "));
+        let element = atom_type.split('.').next().unwrap_or("").to_string();
+        Ok(Atom {
+            atom_id, atom_name, x, y, z, atom_type, subst_id, subst_name, charge, status_bit, element
+        })
     }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-#[derive(Clone)]
+/// A line of `@<TRIPOS>BOND`.
+#[derive(Clone, Debug)]
 pub struct Bond {
-    bond_id: usize,
+    pub bond_id: usize,
     pub a1: usize,
     pub a2: usize,
-    bt: String,
+    /// 1, 2, 3, am, ar, du, un, nc.
+    pub bond_type: String,
+    pub status_bits: Option<String>,
 }
 
 impl Display for Bond {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:6}{:5}{:5} {}\n", 
-            self.bond_id, self.a1, self.a2, self.bt)
+        write!(f, "{:6}{:5}{:5} {}", self.bond_id, self.a1, self.a2, self.bond_type)?;
+        if let Some(status_bits) = &self.status_bits {
+            write!(f, " {}", status_bits)?;
+        }
+        writeln!(f)
     }
 }
 
 impl Bond {
-    fn from(line: &str) -> Bond {
-        let line: Vec<&str> = line.trim().split_whitespace().collect();
-        let bond_id: usize = line[0].parse().unwrap();
-        let a1: usize = line[1].parse().unwrap();
-        let a2: usize = line[2].parse().unwrap();
-        let bt: String = line[3].to_string();
-        Bond {
-            bond_id, a1, a2, bt
+    fn from(line: &str, loc: &Location) -> Result<Bond> {
+        let line: Vec<&str> = line.split_whitespace().collect();
+        let bond_id: usize = loc.field(&line, 0, "bond id")?;
+        let a1: usize = loc.field(&line, 1, "origin atom id")?;
+        let a2: usize = loc.field(&line, 2, "target atom id")?;
+        let bond_type: String = loc.field(&line, 3, "bond type")?;
+        let status_bits = line.get(4..).filter(|s| !s.is_empty()).map(|s| s.join(" "));
+        Ok(Bond {
+            bond_id, a1, a2, bond_type, status_bits
+        })
+    }
+}
+
+/// A line of `@<TRIPOS>SUBSTRUCTURE`; the optional fields are kept as written.
+#[derive(Clone, Debug)]
+pub struct Substructure {
+    pub subst_id: usize,
+    pub subst_name: String,
+    pub root_atom: usize,
+    pub subst_type: Option<String>,
+    pub dict_type: Option<i32>,
+    pub chain: Option<String>,
+    pub sub_type: Option<String>,
+    pub inter_bonds: Option<usize>,
+    pub status: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl Substructure {
+    fn from(line: &str, loc: &Location) -> Result<Substructure> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        Ok(Substructure {
+            subst_id: loc.field(&fields, 0, "substructure id")?,
+            subst_name: loc.field(&fields, 1, "substructure name")?,
+            root_atom: loc.field(&fields, 2, "root atom")?,
+            subst_type: loc.opt_field(&fields, 3, "substructure type")?,
+            dict_type: loc.opt_field(&fields, 4, "dictionary type")?,
+            chain: loc.opt_field(&fields, 5, "chain")?,
+            sub_type: loc.opt_field(&fields, 6, "chain type")?,
+            inter_bonds: loc.opt_field(&fields, 7, "inter bonds")?,
+            status: loc.opt_field(&fields, 8, "status")?,
+            comment: fields.get(9..).filter(|s| !s.is_empty()).map(|s| s.join(" ")),
+        })
+    }
+}
+
+impl Display for Substructure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:6} {:<8}{:7}", self.subst_id, self.subst_name, self.root_atom)?;
+        let rest = [self.subst_type.clone(), self.dict_type.map(|d| d.to_string()), self.chain.clone(),
+            self.sub_type.clone(), self.inter_bonds.map(|b| b.to_string()), self.status.clone(), self.comment.clone()];
+        for field in rest.iter().map_while(|s| s.as_ref()) {
+            write!(f, " {}", field)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// `@<TRIPOS>CRYSIN`: cell constants, space group and setting.
+#[derive(Clone, Debug)]
+pub struct Crysin {
+    pub cell: [f64; 6],
+    pub space_grp: i32,
+    pub space_grp_setting: i32,
+}
+
+impl Crysin {
+    fn from(line: &str, loc: &Location) -> Result<Crysin> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let mut cell = [0.0; 6];
+        for (i, c) in cell.iter_mut().enumerate() {
+            *c = loc.field(&fields, i, "cell constant")?;
+        }
+        Ok(Crysin {
+            cell,
+            space_grp: loc.field(&fields, 6, "space group")?,
+            space_grp_setting: loc.field(&fields, 7, "space group setting")?,
+        })
+    }
+}
+
+impl Display for Crysin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in &self.cell {
+            write!(f, "{}", fmt_num(*c, 10))?;
         }
+        writeln!(f, "{:5}{:3}", self.space_grp, self.space_grp_setting)
+    }
+}
+
+/// An atom of `@<TRIPOS>UNITY_ATOM_ATTR` and its (name, value) attributes.
+#[derive(Clone, Debug)]
+pub struct AtomAttr {
+    pub atom_id: usize,
+    pub attrs: Vec<(String, String)>,
+}
+
+impl Display for AtomAttr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", self.atom_id, self.attrs.len())?;
+        for (name, value) in &self.attrs {
+            writeln!(f, "{} {}", name, value)?;
+        }
+        Ok(())
     }
 }
 
 impl Display for MOL2 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut out = format!("; Created by gen-rtp (https://github.com/supernova4869/gen-rtp)\n\n");
-        out.push_str(format!("{}", self.mol).as_str());
-        out.push_str("@<TRIPOS>ATOM\n");
+        let mut mol = self.mol.clone();
+        mol.num_atoms = self.atoms.len();
+        mol.num_bonds = self.bonds.len();
+        if !self.substructures.is_empty() {
+            mol.num_subst = self.substructures.len();
+        }
+        write!(f, "{}", mol)?;
+        if !self.comment.is_empty() {
+            writeln!(f, "@<TRIPOS>COMMENT\n{}\n", self.comment.join("\n"))?;
+        }
+        writeln!(f, "@<TRIPOS>ATOM")?;
         for a in &self.atoms {
-            out.push_str(format!("{}", a).as_str());
+            write!(f, "{}", a)?;
         }
-        out.push_str("@<TRIPOS>BOND\n");
+        writeln!(f, "@<TRIPOS>BOND")?;
         for b in &self.bonds {
-            out.push_str(format!("{}", b).as_str());
+            write!(f, "{}", b)?;
+        }
+        if !self.substructures.is_empty() {
+            writeln!(f, "@<TRIPOS>SUBSTRUCTURE")?;
+            for s in &self.substructures {
+                write!(f, "{}", s)?;
+            }
+        }
+        if let Some(crysin) = &self.crysin {
+            write!(f, "@<TRIPOS>CRYSIN\n{}", crysin)?;
         }
-        write!(f, "{}", out)
+        if !self.atom_attrs.is_empty() {
+            writeln!(f, "@<TRIPOS>UNITY_ATOM_ATTR")?;
+            for a in &self.atom_attrs {
+                write!(f, "{}", a)?;
+            }
+        }
+        for (name, lines) in &self.others {
+            writeln!(f, "@<TRIPOS>{}", name)?;
+            for l in lines {
+                writeln!(f, "{}", l)?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl MOL2 {
-    pub fn output(&self, outfile: &str) {
-        let mut file = fs::File::create(outfile).unwrap();
-        file.write_fmt(format_args!("{}", self)).unwrap();
-        println!("Written to {}", outfile);
-    }
-
-    pub fn to_hdb(&mut self, out: &str,
-        exclude_n: &Vec<usize>, exclude_c: &Vec<usize>,
-        atom_n: Option<usize>, atom_c: Option<usize>,
-        n_name: &Option<String>, c_name: &Option<String>,
-        atom_adjn: Option<usize>, atom_adjc: Option<usize>,
-        adjn_name: &Option<String>, adjc_name: &Option<String>) {
+    /// Builds the hdb entry of the residue. Atoms of the neighbouring residues get the `-`/`+`
+    /// prefix (or their connection names) so that the reference heavy atoms are named as in the rtp.
+    pub fn hdb_residue(&self, options: &ResidueOptions) -> HdbResidue {
+        let exclude_n = options.prev_atoms();
+        let exclude_c = options.next_atoms();
+        let mut mol2 = self.clone();
         // 前后残基中的原子名加前缀
-        for atom in &mut self.atoms {
-            if let Some(atom_n) = atom_n {
+        for atom in &mut mol2.atoms {
+            if let Some(prev) = &options.prev {
                 if exclude_n.contains(&atom.atom_id) {
-                    if atom.atom_id != atom_n {
+                    if atom.atom_id != prev.con_atom {
                         atom.atom_name = "-".to_string() + &atom.atom_name;
                     } else {
-                        atom.atom_name = n_name.as_ref().unwrap().to_string();
+                        atom.atom_name = prev.con_name.to_string();
                     }
                 }
+                if atom.atom_id == prev.adj_atom {
+                    atom.atom_name = prev.adj_name.to_string();
+                }
             }
-            if let Some(atom_c) = atom_c {
+            if let Some(next) = &options.next {
                 if exclude_c.contains(&atom.atom_id) {
-                    if atom.atom_id != atom_c {
+                    if atom.atom_id != next.con_atom {
                         atom.atom_name = "+".to_string() + &atom.atom_name;
                     } else {
-                        atom.atom_name = c_name.as_ref().unwrap().to_string();
+                        atom.atom_name = next.con_name.to_string();
                     }
                 }
-            }
-            if let Some(atom_adjn) = atom_adjn {
-                if atom.atom_id == atom_adjn {
-                    atom.atom_name = adjn_name.as_ref().unwrap().to_string();
-                }
-            }
-            if let Some(atom_adjc) = atom_adjc {
-                if atom.atom_id == atom_adjc {
-                    atom.atom_name = adjc_name.as_ref().unwrap().to_string();
+                if atom.atom_id == next.adj_atom {
+                    atom.atom_name = next.adj_name.to_string();
                 }
             }
         }
+        mol2.hdb_items(exclude_n, exclude_c, &options.link_atoms())
+    }
 
+    fn hdb_items(&self, exclude_n: &[usize], exclude_c: &[usize], exclude_l: &[usize]) -> HdbResidue {
         // atoms layout: H--i--j--k
         let mut items: Vec<HDBItem> = vec![];
         // 1. 找到所有的非排除重原子
@@ -268,69 +542,73 @@ impl MOL2 {
             .filter(|&h| h.element.ne("H"))
             .filter(|&h| !exclude_n.contains(&h.atom_id))
             .filter(|&h| !exclude_c.contains(&h.atom_id))
+            .filter(|&h| !exclude_l.contains(&h.atom_id))
             .collect();
         for atom_i in atoms_heavy {
             // 2. 找到和i相连的重原子j
-            let atom_j = get_adj_heavy_id(self, atom_i.atom_id);
+            // 连接残基的原子不在 rtp 中, 尽量不作参考原子
+            let mut atom_j = get_adj_heavy_id(self, atom_i.atom_id);
+            atom_j.sort_by_key(|a| exclude_l.contains(a));
             // 3. 找到和j相连的重原子k, 排除i
             let mut atom_k = get_adj_heavy_id(self, atom_j[0]);
             atom_k.retain(|&a| a != atom_i.atom_id);
+            atom_k.sort_by_key(|a| exclude_l.contains(a));
             // 4. 判断重原子连接的H类型
             let htype = get_htype_from_heavy_atom(self, atom_i.atom_id);
             let hs = get_adj_h_id(self, atom_i.atom_id);
-            if hs.len() == 0 {
+            if hs.is_empty() {
                 continue;
             } else {
-                let h = &self.atoms[hs[0] as usize - 1];
-                let h_basename = self.get_hbasename(h);
+                // hdb 中的H名取实际H名的公共前缀, 与 rtp 一致
+                let h_basename = common_prefix(hs.iter().map(|&h| self.atoms[h - 1].atom_name.as_str()));
                 let cur_h = match htype {
                     // type 1, 环H/肽H
                     1 => HDBItem::new(1, 1, h_basename, 
                         vec![
                             atom_i.atom_name.to_string(), 
-                            self.atoms[atom_j[0] as usize - 1].atom_name.to_string(), 
-                            self.atoms[atom_j[1] as usize - 1].atom_name.to_string()
+                            self.atoms[atom_j[0] - 1].atom_name.to_string(), 
+                            self.atoms[atom_j[1] - 1].atom_name.to_string()
                         ]
                     ),
                     // type 2, 羟H
                     2 => HDBItem::new(1, 2, h_basename, 
                         vec![
                             atom_i.atom_name.to_string(), 
-                            self.atoms[atom_j[0] as usize - 1].atom_name.to_string(), 
-                            self.atoms[atom_k[0] as usize - 1].atom_name.to_string()
+                            self.atoms[atom_j[0] - 1].atom_name.to_string(), 
+                            self.atoms[atom_k[0] - 1].atom_name.to_string()
                         ]
                     ),
                     // type 3, 烯H/酰胺H
                     3 => HDBItem::new(2, 3, h_basename, 
                         vec![
                             atom_i.atom_name.to_string(), 
-                            self.atoms[atom_j[0] as usize - 1].atom_name.to_string(), 
-                            self.atoms[atom_k[0] as usize - 1].atom_name.to_string()
+                            self.atoms[atom_j[0] - 1].atom_name.to_string(), 
+                            self.atoms[atom_k[0] - 1].atom_name.to_string()
                         ]
                     ),
                     // type 4, 甲基H
                     4 => HDBItem::new(3, 4, h_basename, 
                         vec![
                             atom_i.atom_name.to_string(), 
-                            self.atoms[atom_j[0] as usize - 1].atom_name.to_string(), 
-                            self.atoms[atom_k[0] as usize - 1].atom_name.to_string()
+                            self.atoms[atom_j[0] - 1].atom_name.to_string(), 
+                            self.atoms[atom_k[0] - 1].atom_name.to_string()
                         ]
                     ),
                     // type 5, 特丁基H
                     5 => HDBItem::new(1, 5, h_basename, 
                         vec![
                             atom_i.atom_name.to_string(), 
-                            self.atoms[atom_j[0] as usize - 1].atom_name.to_string(), 
-                            self.atoms[atom_j[1] as usize - 1].atom_name.to_string(),
-                            self.atoms[atom_j[2] as usize - 1].atom_name.to_string()
+                            self.atoms[atom_j[0] - 1].atom_name.to_string(), 
+                            self.atoms[atom_j[1] - 1].atom_name.to_string(),
+                            self.atoms[atom_j[2] - 1].atom_name.to_string()
                         ]
                     ),
                     // type 6, 亚甲基H
                     6 => HDBItem::new(2, 6, h_basename, 
                         vec![
                             atom_i.atom_name.to_string(), 
-                            self.atoms[atom_j[0] as usize - 1].atom_name.to_string(), 
-                            self.atoms[atom_j[1] as usize - 1].atom_name.to_string()
+                            self.atoms[atom_j[0] - 1].atom_name.to_string(), 
+                            self.atoms[atom_j[1] - 1].atom_name.to_string()
                         ]
                     ),
                     _ => HDBItem::new(1, 1, "".to_string(), vec![])
@@ -338,22 +616,24 @@ impl MOL2 {
                 items.push(cur_h);
             }
         }
-        // 5. 输出文件
-        let mut outfile = fs::File::create(out).unwrap();
-        outfile.write_all(format!("{:5}    {}\n", self.mol.sys_name, items.len()).as_bytes()).unwrap();
-        for item in &items {
-            outfile.write_all(format!("{:<7}{:<7}{:7}", item.h_num, item.h_type, item.h_atom).as_bytes()).unwrap();
-            for ha in &item.heavy_atoms {
-                outfile.write_all(format!("{:7}", ha).as_bytes()).unwrap();
-            }
-            outfile.write_all(b"\n").unwrap();
-        }
-
-        println!("Finished writing rtp file to {}", out);
+        HdbResidue { name: self.resname.to_string(), items }
     }
 
-    pub fn get_hbasename(&self, h: &Atom) -> String {
-        let heavy = &self.atoms[get_adj_heavy_id(self, h.atom_id)[0] as usize - 1];
-        format!("H{}", &heavy.atom_name[heavy.element.len()..])
+    /// Default base name of the hydrogens on `heavy`: `H` + its name without the element prefix
+    /// (`CB` → `HB`, `N` → `H`), or `H` + the whole name if it does not start with the element.
+    pub fn get_hbasename(&self, heavy: &Atom) -> String {
+        let name = &heavy.atom_name;
+        match name.get(..heavy.element.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(&heavy.element) => format!("H{}", &name[prefix.len()..]),
+            _ => format!("H{}", name),
+        }
     }
 }
+
+fn common_prefix<'a>(mut names: impl Iterator<Item = &'a str>) -> String {
+    let first = names.next().unwrap_or("");
+    let len = names.fold(first.len(), |len, n| {
+        first.chars().zip(n.chars()).take(len).take_while(|(a, b)| a == b).count()
+    });
+    first[..len].to_string()
+}
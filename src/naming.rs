@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::str::FromStr;
+
+use crate::error::{Error, Location, Result};
+use crate::hdb::{get_adj_h_id, get_adj_heavy_id};
+use crate::mol2::MOL2;
+use crate::residue::{Kind, ResidueOptions};
+
+/// Longest atom name accepted by PDB and pdb2gmx.
+pub const MAX_NAME_LEN: usize = 4;
+
+/// How heavy atoms are renamed; hydrogens are always named after their heavy atom.
+#[derive(Clone, Debug, Default)]
+pub enum Naming {
+    /// Element + one counter over all heavy atoms: C1, O2, C3, N4...
+    #[default]
+    Global,
+    /// Element + a counter per element: C1, O1, C2, N1...
+    Element,
+    /// Keep the names of the mol2.
+    Keep,
+    /// PDB amino-acid names from the bond graph: N, CA, C, O, then CB, CG, CD1, CD2... by the
+    /// distance from CA, going on past eta with theta, iota, kappa, lambda and mu (CT, CI, CK, CL,
+    /// CM); farther atoms and those of the neighbours are named as in [`Naming::Global`].
+    Amino,
+    /// Names from a user table; heavy atoms not in the table keep their names.
+    Map(NameTable),
+}
+
+impl FromStr for Naming {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Naming, String> {
+        match s {
+            "global" => Ok(Naming::Global),
+            "element" => Ok(Naming::Element),
+            "keep" => Ok(Naming::Keep),
+            "amino" => Ok(Naming::Amino),
+            _ => Err(format!("Invalid naming scheme `{}`, only support global, element, keep and amino (or --name-map).", s)),
+        }
+    }
+}
+
+impl Display for Naming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Naming::Global => write!(f, "global"),
+            Naming::Element => write!(f, "element"),
+            Naming::Keep => write!(f, "keep"),
+            Naming::Amino => write!(f, "amino"),
+            Naming::Map(_) => write!(f, "map"),
+        }
+    }
+}
+
+/// New atom names keyed by atom id or by old name.
+#[derive(Clone, Debug, Default)]
+pub struct NameTable {
+    pub by_id: HashMap<usize, String>,
+    pub by_name: HashMap<String, String>,
+}
+
+impl NameTable {
+    /// Reads `key new_name` lines (comma or space separated; key is an atom id or an old name).
+    /// A mapping file written by gen-rtp (`id,old_name,new_name,...`) is also accepted.
+    pub fn from(file: &str) -> Result<NameTable> {
+        let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let mut table = NameTable::default();
+        let mut new_col = 1;
+        for (ln, line) in content.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split([',', ' ', '\t']).filter(|s| !s.is_empty()).collect();
+            if fields.first() == Some(&"id") {
+                new_col = fields.iter().position(|&s| s == "new_name").unwrap_or(1);
+                continue;
+            }
+            let loc = Location { file, line: ln + 1, section: "name map" };
+            let new: String = loc.field(&fields, new_col, "new name")?;
+            match fields[0].parse() {
+                Ok(id) => table.by_id.insert(id, new),
+                Err(_) => table.by_name.insert(fields[0].to_string(), new),
+            };
+        }
+        Ok(table)
+    }
+
+    /// New name of atom `id` named `old`.
+    pub fn get(&self, id: usize, old: &str) -> Option<&str> {
+        self.by_id.get(&id).or_else(|| self.by_name.get(old)).map(|s| s.as_str())
+    }
+}
+
+/// New heavy atom names of `mol2` under `options.naming`, indexed by atom id - 1; `None` for hydrogens.
+pub fn heavy_names(mol2: &MOL2, options: &ResidueOptions) -> Result<Vec<Option<String>>> {
+    let mut global = 0;
+    let mut per_element: HashMap<&str, usize> = HashMap::new();
+    let mut names: Vec<Option<String>> = mol2.atoms.iter().map(|a| {
+        if a.element == "H" {
+            return None;
+        }
+        global += 1;
+        let count = per_element.entry(&a.element).or_insert(0);
+        *count += 1;
+        Some(match &options.naming {
+            Naming::Global | Naming::Amino => format!("{}{}", a.element, global),
+            Naming::Element => format!("{}{}", a.element, count),
+            Naming::Keep => a.atom_name.to_string(),
+            Naming::Map(table) => table.get(a.atom_id, &a.atom_name).unwrap_or(&a.atom_name).to_string(),
+        })
+    }).collect();
+    if let Naming::Amino = options.naming {
+        for (id, name) in amino_names(mol2, options)? {
+            names[id - 1] = Some(name);
+        }
+    }
+    Ok(names)
+}
+
+// 距 CA 的键数对应的希腊字母, eta 之后依次为 theta, iota, kappa, lambda, mu
+const GREEK: [&str; 12] = ["A", "B", "G", "D", "E", "Z", "H", "T", "I", "K", "L", "M"];
+
+fn amino_names(mol2: &MOL2, options: &ResidueOptions) -> Result<HashMap<usize, String>> {
+    let in_residue = |id: usize| !options.is_outside(id);
+    let element = |id: usize| mol2.atoms[id - 1].element.as_str();
+    let heavy = |id: usize| -> Vec<usize> {
+        get_adj_heavy_id(mol2, id).into_iter().filter(|&a| in_residue(a)).collect()
+    };
+    // 只连一个重原子的O, 即羰基或羧基O
+    let terminal_o = |c: usize| -> Vec<usize> {
+        heavy(c).into_iter().filter(|&o| element(o) == "O" && get_adj_heavy_id(mol2, o).len() == 1).collect()
+    };
+
+    // 主链 N-CA-C(=O), 有相邻残基时 N 和 C 即连接原子
+    let prev_adj = options.prev.as_ref().map(|n| n.adj_atom);
+    let next_adj = options.next.as_ref().map(|n| n.adj_atom);
+    let mut backbone = None;
+    for c in (1..=mol2.atoms.len()).filter(|&c| in_residue(c) && element(c) == "C" && !terminal_o(c).is_empty()) {
+        if next_adj.is_some_and(|a| a != c) {
+            continue;
+        }
+        for ca in heavy(c).into_iter().filter(|&ca| element(ca) == "C") {
+            let n = heavy(ca).into_iter().find(|&n| element(n) == "N" && prev_adj.is_none_or(|a| a == n));
+            if let Some(n) = n {
+                backbone = backbone.or(Some((n, ca, c)));
+            }
+        }
+    }
+    let (n, ca, c) = backbone.ok_or_else(|| Error::Invalid(
+        "no peptide backbone N-CA-C(=O) found in the current residue for the amino naming scheme".to_string()))?;
+
+    let mut names = HashMap::from([(n, "N".to_string()), (ca, "CA".to_string()), (c, "C".to_string())]);
+    for (i, o) in terminal_o(c).into_iter().enumerate() {
+        names.insert(o, match i { 0 => "O", _ => "OXT" }.to_string());
+    }
+
+    // 从 CA 逐层向侧链展开, 同层多个原子时编号, 优先沿用父原子的编号
+    let mut numbers: HashMap<usize, usize> = HashMap::new();
+    let mut level = vec![ca];
+    for greek in GREEK.iter().skip(1) {
+        let mut next: Vec<usize> = level.iter()
+            .flat_map(|&a| heavy(a))
+            .filter(|a| !names.contains_key(a) && !level.contains(a))
+            .collect();
+        next.sort_unstable();
+        next.dedup();
+        if next.is_empty() {
+            break;
+        }
+        let parents = |a: usize| -> Vec<usize> {
+            heavy(a).into_iter().filter(|p| level.contains(p)).filter_map(|p| numbers.get(&p).copied()).collect()
+        };
+        // 先按父原子编号, 再按元素 S > O > N > C
+        let rank = |a: usize| match element(a) { "S" => 0, "O" => 1, "N" => 2, "C" => 4, _ => 3 };
+        next.sort_by_key(|&a| (parents(a).into_iter().min().unwrap_or(0), rank(a), a));
+        let mut level_numbers: HashMap<usize, usize> = HashMap::new();
+        if next.len() > 1 {
+            let mut used = vec![];
+            for &a in &next {
+                let number = parents(a).into_iter().find(|p| !used.contains(p))
+                    .unwrap_or_else(|| (1..).find(|i| !used.contains(i)).unwrap());
+                used.push(number);
+                level_numbers.insert(a, number);
+            }
+        } else if let [p] = parents(next[0])[..] {
+            level_numbers.insert(next[0], p);
+        }
+        for &a in &next {
+            let number = level_numbers.get(&a).map_or(String::new(), |n| n.to_string());
+            names.insert(a, format!("{}{}{}", element(a).to_uppercase(), greek, number));
+        }
+        numbers.extend(level_numbers);
+        level = next;
+    }
+    let unnamed: Vec<String> = (1..=mol2.atoms.len())
+        .filter(|&a| in_residue(a) && element(a) != "H" && !names.contains_key(&a))
+        .map(|a| mol2.atoms[a - 1].atom_name.to_string())
+        .collect();
+    if !unnamed.is_empty() {
+        println!("Warning: atoms {} are further than mu from CA (or not bonded to it) and keep element+id names",
+            unnamed.join(", "));
+    }
+    Ok(names)
+}
+
+/// Names the hydrogens after their heavy atom (`H` + the heavy atom name without its element,
+/// plus an index from 1 if there are several, as pdb2gmx names them from the hdb), keeping
+/// them unique within each residue and at most [`MAX_NAME_LEN`] characters. Returns a note for every heavy atom whose hydrogens needed another name.
+pub fn hydrogen_names(mol2: &mut MOL2, options: &ResidueOptions) -> Vec<String> {
+    // 0 为当前残基, 1 前一残基, 2 后一残基, 3.. 各连接残基; 聚合物的首尾单元含封端, 与当前残基一起命名
+    let polymer = options.kind == Kind::Polymer;
+    let group = |id: usize| match (options.prev_atoms().contains(&id), options.next_atoms().contains(&id)) {
+        (true, _) if !polymer => 1,
+        (_, true) if !polymer => 2,
+        (true, _) | (_, true) => 0,
+        _ => options.links.iter().position(|l| l.atoms.contains(&id)).map_or(0, |i| i + 3),
+    };
+    // 各残基中已占用的名字, 先放入重原子
+    let mut taken: Vec<Vec<String>> = vec![vec![]; options.links.len() + 3];
+    for a in mol2.atoms.iter().filter(|a| a.element != "H") {
+        taken[group(a.atom_id)].push(a.atom_name.to_string());
+    }
+    let mut notes = vec![];
+    let heavy_atoms: Vec<usize> = mol2.atoms.iter().filter(|a| a.element != "H").map(|a| a.atom_id).collect();
+    for heavy in heavy_atoms {
+        let hs = get_adj_h_id(mol2, heavy);
+        if hs.is_empty() {
+            continue;
+        }
+        // 与 pdb2gmx 按 hdb 添加H时的命名一致, 从1编号
+        let names_of = |base: &str| -> Vec<String> {
+            match hs.len() {
+                1 => vec![base.to_string()],
+                n => (1..=n).map(|i| format!("{}{}", base, i)).collect(),
+            }
+        };
+        let taken = &mut taken[group(heavy)];
+        let usable = |names: &[String]| names.iter().all(|n| n.len() <= MAX_NAME_LEN && !taken.contains(n));
+        // 依次尝试: 默认名, H+完整重原子名, H+序号
+        let heavy_atom = &mol2.atoms[heavy - 1];
+        let default = mol2.get_hbasename(heavy_atom);
+        let candidates = [default.to_string(), format!("H{}", heavy_atom.atom_name.to_uppercase())].into_iter()
+            .chain((1..).map(|k| format!("H{}", k)));
+        let base = candidates.map(|b| names_of(&b)).find(|names| usable(names)).unwrap();
+        let default_names = names_of(&default);
+        if base != default_names {
+            let reason = match default_names.iter().find(|n| taken.contains(n)) {
+                Some(n) => format!("`{}` is already used", n),
+                None => format!("longer than {} characters", MAX_NAME_LEN),
+            };
+            notes.push(format!("hydrogens of {} named {} instead of {} ({})",
+                heavy_atom.atom_name, base.join("/"), default_names.join("/"), reason));
+        }
+        for (&h, name) in hs.iter().zip(&base) {
+            mol2.atoms[h - 1].atom_name = name.to_string();
+        }
+        taken.extend(base);
+    }
+    notes
+}
+
+/// Checks that the atom names are unique within the current residue and within each neighbour,
+/// and that names of the current residue fit in [`MAX_NAME_LEN`] characters.
+pub fn check_names(mol2: &MOL2, options: &ResidueOptions) -> Result<()> {
+    let mut problems = vec![];
+    let mut groups = match options.kind {
+        // 聚合物的首尾单元包含封端原子, 名字须与重复单元一起唯一
+        Kind::Polymer => vec![
+            ("polymer units".to_string(), mol2.atoms.iter().filter(|a| !options.link_atoms().contains(&a.atom_id)).collect::<Vec<_>>()),
+        ],
+        _ => vec![
+            ("current residue".to_string(), mol2.atoms.iter().filter(|a| !options.is_outside(a.atom_id)).collect::<Vec<_>>()),
+            ("previous residue".to_string(), mol2.atoms.iter().filter(|a| options.prev_atoms().contains(&a.atom_id)).collect()),
+            ("next residue".to_string(), mol2.atoms.iter().filter(|a| options.next_atoms().contains(&a.atom_id)).collect()),
+        ],
+    };
+    for (i, l) in options.links.iter().enumerate() {
+        groups.push((format!("linked residue {}", i + 1), mol2.atoms.iter().filter(|a| l.atoms.contains(&a.atom_id)).collect()));
+    }
+    for (i, (group, atoms)) in groups.iter().enumerate() {
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for a in atoms {
+            if let Some(first) = seen.insert(&a.atom_name, a.atom_id) {
+                problems.push(format!("atoms {} and {} of the {} are both named `{}`", first, a.atom_id, group, a.atom_name));
+            }
+            if i == 0 && (a.atom_name.is_empty() || a.atom_name.len() > MAX_NAME_LEN) {
+                problems.push(format!("name `{}` of atom {} is not 1-{} characters", a.atom_name, a.atom_id, MAX_NAME_LEN));
+            }
+        }
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Invalid(format!("invalid atom names:\n  {}", problems.join("\n  ")))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::residue::{fix_atom_names, Neighbour};
+
+    #[test]
+    fn amino_names_of_tys() {
+        let options = ResidueOptions {
+            prev: Some(Neighbour { atoms: (1..=6).collect(), con_atom: 1, con_name: "-C".to_string(), adj_atom: 7, adj_name: "N".to_string() }),
+            next: Some(Neighbour { atoms: (31..=36).collect(), con_atom: 31, con_name: "+N".to_string(), adj_atom: 9, adj_name: "C".to_string() }),
+            naming: Naming::Amino,
+            ..Default::default()
+        };
+        let mut mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        fix_atom_names(&mut mol2, &options).unwrap();
+        let names: Vec<&str> = mol2.atoms[6..30].iter().map(|a| a.atom_name.as_str()).collect();
+        assert_eq!(names, [
+            "N", "CA", "C", "O", "CB", "CG", "CD1", "CD2", "CE1", "CE2", "CZ", "OH", "ST", "OI1", "OI2", "OI3",
+            "HA", "HB1", "HB2", "HD1", "HD2", "HE1", "HE2", "H",
+        ]);
+    }
+}
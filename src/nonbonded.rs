@@ -0,0 +1,126 @@
+use std::fmt::{self, Display};
+
+use crate::error::{Error, Result};
+use crate::itp::{atomtypes_title, Defaults, TopolAtomtype};
+
+/// The `[ atomtypes ]` of the converted residues, to be added to `ffnonbonded.itp` of the force field.
+#[derive(Clone, Debug)]
+pub struct Nonbonded {
+    /// `[ defaults ]` the atomtypes follow, if known.
+    pub defaults: Option<Defaults>,
+    /// Sorted by name.
+    pub atomtypes: Vec<TopolAtomtype>,
+}
+
+/// The `atomtypes.atp` lines (name and mass) of the converted residues.
+#[derive(Clone, Debug)]
+pub struct Atp {
+    pub atomtypes: Vec<TopolAtomtype>,
+}
+
+impl Nonbonded {
+    /// The atomtypes sorted by name, without duplicates. Types named alike (ignoring case, as pdb2gmx
+    /// does) with other parameters are an error, since the force field can hold only one of them.
+    pub fn new(atomtypes: impl IntoIterator<Item = TopolAtomtype>, defaults: Option<Defaults>) -> Result<Nonbonded> {
+        let mut sorted: Vec<TopolAtomtype> = atomtypes.into_iter().collect();
+        sorted.sort_by(|a, b| a.name.cmp(&b.name).then(a.mass.total_cmp(&b.mass)));
+        let mut atomtypes: Vec<TopolAtomtype> = vec![];
+        let mut clashes = vec![];
+        for at in sorted {
+            match atomtypes.iter().find(|k| k.name.eq_ignore_ascii_case(&at.name)) {
+                Some(k) if k.same_params(&at) => {},
+                Some(k) => clashes.push(format!("{} (sigma {}, epsilon {}) and {} (sigma {}, epsilon {})",
+                    k.name, k.sigma, k.epsilon, at.name, at.sigma, at.epsilon)),
+                None => atomtypes.push(at),
+            }
+        }
+        match clashes.is_empty() {
+            true => Ok(Nonbonded { defaults, atomtypes }),
+            false => Err(Error::Invalid(format!("atomtypes defined twice with other parameters: {}", clashes.join(", ")))),
+        }
+    }
+
+    /// The matching `atomtypes.atp` lines.
+    pub fn atp(&self) -> Atp {
+        Atp { atomtypes: self.atomtypes.clone() }
+    }
+}
+
+/// The atomtypes of `atomtypes` named like one of `known` (ignoring case, as pdb2gmx does) but with
+/// other parameters, each with the known one it clashes with.
+pub fn conflicts<'a>(atomtypes: impl IntoIterator<Item = &'a TopolAtomtype>, known: impl IntoIterator<Item = &'a TopolAtomtype>)
+    -> Vec<(&'a TopolAtomtype, &'a TopolAtomtype)> {
+    let known: Vec<&TopolAtomtype> = known.into_iter().collect();
+    let mut clashes: Vec<(&TopolAtomtype, &TopolAtomtype)> = atomtypes.into_iter()
+        .filter_map(|at| known.iter().find(|k| k.name.eq_ignore_ascii_case(&at.name) && !k.same_params(at)).map(|k| (at, *k)))
+        .collect();
+    clashes.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    clashes
+}
+
+impl Display for Nonbonded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "; Created by gen-rtp (https://github.com/supernova4869/gen-rtp)")?;
+        // 记录原力场的 [ defaults ], 安装时需与目标力场一致
+        if let Some(d) = &self.defaults {
+            writeln!(f, "; [ defaults ] of the atomtypes:\n; nbfunc   comb-rule      gen-pairs       fudgeLJ    fudgeQQ\n;{}", d)?;
+        }
+        let bond_type = self.atomtypes.iter().any(|at| at.bond_type.is_some());
+        let c6_c12 = self.defaults.as_ref().is_some_and(|d| d.comb_rule == 1);
+        writeln!(f, "\n[ atomtypes ]")?;
+        writeln!(f, "{}", atomtypes_title(bond_type, c6_c12))?;
+        for at in &self.atomtypes {
+            writeln!(f, "{}", at.line(bond_type, c6_c12))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Atp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for at in &self.atomtypes {
+            writeln!(f, "{:<10}{:12.6}", at.name, at.mass)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mol2::MOL2;
+    use crate::residue::{convert, ResidueOptions};
+
+    #[test]
+    fn atomtypes_with_other_parameters_clash() {
+        let mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        let known = convert(&mol2, "examples/TYS.itp", &ResidueOptions::default()).unwrap().topol.atomtypes;
+        assert!(conflicts(&known, &known).is_empty());
+
+        let other: Vec<TopolAtomtype> = known.iter().cloned().map(|mut at| {
+            if at.name == "c3" {
+                at.sigma = 0.350000;
+            }
+            at
+        }).collect();
+        let clashes = conflicts(&other, &known);
+        assert_eq!(clashes.len(), 1);
+        assert_eq!((clashes[0].0.name.as_str(), clashes[0].0.sigma), ("c3", 0.350000));
+        assert!((clashes[0].1.sigma - 0.339967).abs() < 1e-6);
+    }
+
+    #[test]
+    fn atomtypes_are_merged_by_name() {
+        let mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        let own = convert(&mol2, "examples/TYS.itp", &ResidueOptions::default()).unwrap().topol.atomtypes;
+        let c3 = own.iter().find(|at| at.name == "c3").unwrap().clone();
+        let twice = Nonbonded::new(own.iter().cloned().chain([c3.clone()]), None).unwrap();
+        assert_eq!(twice.atomtypes.len(), own.len());
+
+        let other = TopolAtomtype { name: "C3".to_string(), sigma: 0.350000, ..c3 };
+        match Nonbonded::new(own.iter().cloned().chain([other]), None) {
+            Err(Error::Invalid(e)) => assert!(e.contains("C3") && e.contains("c3"), "{}", e),
+            _ => panic!("atomtypes C3 and c3 with other parameters are merged"),
+        }
+    }
+}
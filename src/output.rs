@@ -0,0 +1,116 @@
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+/// What to do when an output file already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Move the existing file to `<file>.bak` (or `.bak1`, `.bak2`, ... if taken) first.
+    #[default]
+    Backup,
+    /// Fail instead of touching the existing file.
+    Refuse,
+    /// Overwrite the existing file.
+    Force,
+}
+
+impl FromStr for Overwrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Overwrite, String> {
+        match s {
+            "backup" => Ok(Overwrite::Backup),
+            "refuse" => Ok(Overwrite::Refuse),
+            "force" => Ok(Overwrite::Force),
+            _ => Err(format!("Invalid overwrite mode `{}`, only support backup, refuse and force.", s)),
+        }
+    }
+}
+
+impl Display for Overwrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overwrite::Backup => write!(f, "backup"),
+            Overwrite::Refuse => write!(f, "refuse"),
+            Overwrite::Force => write!(f, "force"),
+        }
+    }
+}
+
+/// Writes output files, never touching the protected input files.
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    pub overwrite: Overwrite,
+    /// Only report what would be written.
+    pub dry_run: bool,
+    protected: Vec<PathBuf>,
+}
+
+impl Output {
+    pub fn new(overwrite: Overwrite, dry_run: bool) -> Output {
+        Output { overwrite, dry_run, protected: vec![] }
+    }
+
+    /// Refuses any later write to `file`, e.g. an input file.
+    pub fn protect(&mut self, file: &str) {
+        self.protected.push(normalize(Path::new(file)));
+    }
+
+    pub fn write(&self, file: &str, content: &str) -> Result<()> {
+        let path = Path::new(file);
+        if self.protected.contains(&normalize(path)) {
+            return Err(Error::Invalid(format!("refuse to overwrite input file {}, choose another output name", file)));
+        }
+        let backup = match (path.exists(), self.overwrite) {
+            (false, _) | (true, Overwrite::Force) => None,
+            (true, Overwrite::Refuse) => {
+                return Err(Error::Invalid(format!("{} already exists, refuse to overwrite it", file)));
+            },
+            (true, Overwrite::Backup) => Some(backup_path(path)),
+        };
+        if self.dry_run {
+            println!("[dry-run] Would write {} ({} lines)", file, content.lines().count());
+            if let Some(bak) = backup {
+                println!("[dry-run] Would back up existing {} to {}", file, bak.display());
+            }
+            return Ok(());
+        }
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| Error::io(&dir.display().to_string(), e))?;
+        }
+        if let Some(bak) = backup {
+            fs::rename(path, &bak).map_err(|e| Error::io(file, e))?;
+            println!("Backed up existing {} to {}", file, bak.display());
+        }
+        fs::write(path, content).map_err(|e| Error::io(file, e))
+    }
+}
+
+// 尚不存在的文件无法 canonicalize, 退而比较父目录
+fn normalize(path: &Path) -> PathBuf {
+    if let Ok(p) = path.canonicalize() {
+        return p;
+    }
+    let parent = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(p), Some(name)) => p.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+    (0..).map(|i| match i {
+        0 => path.with_file_name(format!("{}.bak", name)),
+        i => path.with_file_name(format!("{}.bak{}", name, i)),
+    }).find(|p| !p.exists()).unwrap()
+}
+
+/// Expands the placeholders `{mol2}`, `{itp}` (file stems of the inputs) and `{res}` (residue name)
+/// of an output file name template.
+pub fn expand_template(template: &str, mol2_stem: &str, itp_stem: &str, resname: &str) -> String {
+    template.replace("{mol2}", mol2_stem).replace("{itp}", itp_stem).replace("{res}", resname)
+}
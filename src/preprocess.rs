@@ -0,0 +1,169 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::error::{Error, Location, Result};
+
+/// The C preprocessor subset used by GROMACS topologies: `#include`, `#define`/`#undef`,
+/// `#ifdef`/`#ifndef`/`#else`/`#endif` and substitution of defined macros in the other lines.
+#[derive(Clone, Debug, Default)]
+pub struct Preprocessor {
+    /// Directories searched for `#include` files after the directory of the including file, like `-I`.
+    /// `$GMXLIB` is searched last.
+    pub include_dirs: Vec<String>,
+    /// Macros defined before reading, like `-D`, as (name, value).
+    pub defines: Vec<(String, String)>,
+}
+
+/// A line left after preprocessing, with the file and 1-based line number it comes from.
+#[derive(Clone, Debug)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+}
+
+// 条件编译的一层: 外层是否有效, 本分支是否选中, 是否已过 #else
+struct Cond {
+    parent: bool,
+    taking: bool,
+    seen_else: bool,
+}
+
+// #include 嵌套上限, 防止循环包含
+const MAX_DEPTH: usize = 32;
+
+impl Preprocessor {
+    /// Adds a macro from a `NAME` or `NAME=VALUE` definition of the command line.
+    pub fn define(&mut self, spec: &str) {
+        let (name, value) = spec.split_once('=').unwrap_or((spec, ""));
+        self.defines.retain(|(n, _)| n != name);
+        self.defines.push((name.to_string(), value.to_string()));
+    }
+
+    /// Reads `file` and everything it includes, returning the lines kept by the conditionals with
+    /// the macros substituted. Directives are consumed; comments are left in place.
+    pub fn process(&self, file: &str) -> Result<Vec<SourceLine>> {
+        let mut defines = self.defines.clone();
+        let mut lines = vec![];
+        self.read(file, &mut defines, &mut lines, 0)?;
+        Ok(lines)
+    }
+
+    fn read(&self, file: &str, defines: &mut Vec<(String, String)>, lines: &mut Vec<SourceLine>, depth: usize) -> Result<()> {
+        let content = fs::read_to_string(file).map_err(|e| Error::io(file, e))?;
+        let mut stack: Vec<Cond> = vec![];
+        let active = |stack: &[Cond]| stack.last().is_none_or(|c| c.parent && c.taking);
+        let mut last = 0;
+        // 以 \ 结尾的行与下一行相连, 行号取首行
+        let mut joined: Vec<(usize, String)> = vec![];
+        for (i, line) in content.lines().enumerate() {
+            match joined.last_mut() {
+                Some((_, text)) if text.ends_with('\\') => {
+                    text.pop();
+                    text.push_str(line);
+                },
+                _ => joined.push((i + 1, line.to_string())),
+            }
+        }
+        for (ln, text) in joined {
+            last = ln;
+            let loc = Location { file, line: ln, section: "preprocessor" };
+            let code = text.split(';').next().unwrap().trim();
+            let directive = match code.strip_prefix('#') {
+                Some(d) => d.trim_start(),
+                None => {
+                    if active(&stack) {
+                        lines.push(SourceLine { file: file.to_string(), line: ln, text: substitute(&text, defines) });
+                    }
+                    continue;
+                },
+            };
+            let (name, rest) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+            let rest = rest.trim();
+            match name {
+                "ifdef" | "ifndef" => {
+                    let defined = defines.iter().any(|(n, _)| n == rest);
+                    stack.push(Cond { parent: active(&stack), taking: defined == (name == "ifdef"), seen_else: false });
+                },
+                "else" => match stack.last_mut() {
+                    Some(c) if !c.seen_else => {
+                        c.taking = !c.taking;
+                        c.seen_else = true;
+                    },
+                    _ => return Err(loc.error(code, "#else without #ifdef")),
+                },
+                "endif" => {
+                    if stack.pop().is_none() {
+                        return Err(loc.error(code, "#endif without #ifdef"));
+                    }
+                },
+                _ if !active(&stack) => (),
+                "define" => {
+                    let (macro_name, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    if macro_name.is_empty() {
+                        return Err(loc.error(code, "missing macro name"));
+                    }
+                    defines.retain(|(n, _)| n != macro_name);
+                    defines.push((macro_name.to_string(), value.trim().to_string()));
+                },
+                "undef" => defines.retain(|(n, _)| n != rest),
+                "include" => {
+                    let target = rest.trim_matches(|c| c == '"' || c == '<' || c == '>');
+                    if depth >= MAX_DEPTH {
+                        return Err(loc.error(target, "too deeply nested #include"));
+                    }
+                    match self.find(file, target) {
+                        Some(path) => self.read(&path.to_string_lossy(), defines, lines, depth + 1)?,
+                        // 力场文件等找不到时跳过, 其中的参数生成 rtp 时用不到
+                        None => println!("Note: {}:{}: cannot find included file `{}`, skipped", file, ln, target),
+                    }
+                },
+                _ => return Err(loc.error(code, "unsupported preprocessor directive")),
+            }
+        }
+        if !stack.is_empty() {
+            return Err(Location { file, line: last, section: "preprocessor" }.error("#endif", "unterminated #ifdef"));
+        }
+        Ok(())
+    }
+
+    // 依次在包含者所在目录, -I 目录和 $GMXLIB 中查找
+    fn find(&self, from: &str, target: &str) -> Option<PathBuf> {
+        let parent = Path::new(from).parent().map(Path::to_path_buf).unwrap_or_default();
+        let gmxlib = env::var("GMXLIB").unwrap_or_default();
+        [parent].into_iter()
+            .chain(self.include_dirs.iter().map(PathBuf::from))
+            .chain(env::split_paths(&gmxlib))
+            .map(|dir| dir.join(target))
+            .find(|path| path.is_file())
+    }
+}
+
+// 替换注释前部分中已定义的宏, 宏的值中可再含宏
+fn substitute(text: &str, defines: &[(String, String)]) -> String {
+    let (code, comment) = match text.find(';') {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    if !defines.iter().any(|(_, v)| !v.is_empty()) {
+        return text.to_string();
+    }
+    let re = Regex::new(r"\b[A-Za-z_]\w*\b").unwrap();
+    let mut code = code.to_string();
+    for _ in 0..MAX_DEPTH {
+        let next = re.replace_all(&code, |caps: &regex::Captures| {
+            match defines.iter().find(|(n, v)| n == &caps[0] && !v.is_empty()) {
+                Some((_, v)) => v.to_string(),
+                None => caps[0].to_string(),
+            }
+        }).to_string();
+        if next == code {
+            break;
+        }
+        code = next;
+    }
+    code + comment
+}
@@ -0,0 +1,580 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::bonded::Ffbonded;
+use crate::boundary::neighbour_roles;
+use crate::charge::{charge_groups, fix_charge, ChargeFix, ChargeGroups};
+use crate::error::{Error, Result};
+use crate::hdb::{get_adj_atoms_id, get_adj_heavy_id, HDBItem, Hdb, HdbResidue};
+use crate::itp::{Defaults, Topol, TopolAtomtype};
+use crate::mol2::MOL2;
+use crate::naming::{check_names, heavy_names, hydrogen_names, Naming};
+use crate::nonbonded::Nonbonded;
+use crate::preprocess::Preprocessor;
+use crate::rtp::{Rtp, RtpAtom, RtpResidue};
+use crate::specbond::{SpecBond, Specbond};
+
+/// The kind of polymer the residue belongs to, deciding the backbone link.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Kind {
+    /// Amino acids, linked by `-C`-`N` peptide bonds.
+    #[default]
+    Protein,
+    /// Nucleotides, linked by `-O3'`-`P` phosphodiester bonds.
+    Nucleic,
+    /// Repeat units of a polymer, each linking its head atom to the tail atom of the previous unit.
+    Polymer,
+}
+
+impl Kind {
+    /// Default rtp names of the connection atoms: previous residue, current residue to previous,
+    /// next residue and current residue to next. Empty for polymers, whose link names follow the
+    /// names of the head and tail atoms.
+    pub fn link_names(&self) -> [&'static str; 4] {
+        match self {
+            Kind::Protein => ["-C", "N", "+N", "C"],
+            Kind::Nucleic => ["-O3'", "P", "+P", "O3'"],
+            Kind::Polymer => ["", "", "", ""],
+        }
+    }
+
+    /// Type of the residue in `residuetypes.dat`.
+    pub fn residue_type(&self) -> &'static str {
+        match self {
+            Kind::Protein => "Protein",
+            Kind::Nucleic => "DNA",
+            Kind::Polymer => "Other",
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Kind, String> {
+        match s {
+            "protein" => Ok(Kind::Protein),
+            "nucleic" => Ok(Kind::Nucleic),
+            "polymer" => Ok(Kind::Polymer),
+            _ => Err(format!("Invalid residue kind `{}`, only support protein, nucleic and polymer.", s)),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Protein => write!(f, "protein"),
+            Kind::Nucleic => write!(f, "nucleic"),
+            Kind::Polymer => write!(f, "polymer"),
+        }
+    }
+}
+
+/// A neighbouring residue kept in the capped molecule, and how it is bonded to the current residue.
+#[derive(Clone, Debug)]
+pub struct Neighbour {
+    /// Atom ids (1-based, as in the mol2 and itp) of the neighbouring residue.
+    pub atoms: Vec<usize>,
+    /// Atom of the neighbouring residue bonded to the current residue, e.g. the carbonyl C of the previous one.
+    pub con_atom: usize,
+    /// Name of `con_atom` in the rtp, including the `-`/`+` prefix, e.g. `-C` or `+N`.
+    pub con_name: String,
+    /// Atom of the current residue bonded to `con_atom`.
+    pub adj_atom: usize,
+    /// Name of `adj_atom` in the rtp, e.g. `N` or `C`.
+    pub adj_name: String,
+}
+
+/// An extra link from the current residue to another residue (crosslink, branch, covalent ligand).
+/// The linked residue is dropped from the rtp and pdb2gmx makes the bond from a `specbond.dat` entry.
+#[derive(Clone, Debug)]
+pub struct Link {
+    /// Atom ids of the linked residue.
+    pub atoms: Vec<usize>,
+    /// Atom of the linked residue bonded to the current residue.
+    pub con_atom: usize,
+    /// Name of the linked residue, e.g. `LYS`.
+    pub con_resname: String,
+    /// Name of `con_atom` in the linked residue, e.g. `NZ`.
+    pub con_name: String,
+    /// Atom of the current residue bonded to `con_atom`.
+    pub adj_atom: usize,
+}
+
+/// How a capped molecule is turned into a residue.
+#[derive(Clone, Debug)]
+pub struct ResidueOptions {
+    /// Naming scheme of the heavy atoms.
+    pub naming: Naming,
+    /// Protein or nucleic acid residue.
+    pub kind: Kind,
+    /// Force field family of the rtp, `amber` or `gromos`.
+    pub ff: String,
+    /// The previous residue, `None` for the first residue of a chain.
+    pub prev: Option<Neighbour>,
+    /// The next residue, `None` for the last residue of a chain.
+    pub next: Option<Neighbour>,
+    /// Extra links to other residues.
+    pub links: Vec<Link>,
+    /// How the charges are corrected once the atoms of the neighbours are excluded.
+    pub charge_fix: ChargeFix,
+    /// Net charge to correct to, the nearest integer if `None`.
+    pub net_charge: Option<i32>,
+    /// Charge groups of the itp or assigned from the bond graph.
+    pub charge_groups: ChargeGroups,
+    /// `[ defaults ]` of the force field the residue goes into; the atomtypes are checked and converted against it.
+    pub target_defaults: Option<Defaults>,
+    /// Atomtypes of the force field the residue goes into; clashing atomtypes are renamed.
+    pub target_atomtypes: Vec<TopolAtomtype>,
+    /// Prefix of the renamed atomtypes, e.g. `gaff_`.
+    pub type_prefix: String,
+    /// Move the bonded parameters into `ffbonded.itp` type tables, leaving the connectivity in the rtp.
+    pub bonded_types: bool,
+    /// Bonded types already known, of the force field the residue goes into or tabulated for other
+    /// residues; they are not tabulated again and terms with other parameters keep theirs.
+    pub target_bonded: Ffbonded,
+    /// Look up the parameters of the junction terms in `target_bonded`, see [`Topol::junction_params`].
+    pub junction_params: bool,
+    /// Atom names and atomtypes of the residue of the target force field standing for the neighbours
+    /// in the junction terms, e.g. those of ALA.
+    pub neighbour_types: Vec<(String, String)>,
+    /// Names in the reference residue of neighbour atoms, keyed by their rtp names (e.g. `-O2` to `O`),
+    /// taking precedence over those found by [`crate::boundary::neighbour_roles`].
+    pub neighbour_map: Vec<(String, String)>,
+    /// Include paths and defines for reading the itp.
+    pub preprocessor: Preprocessor,
+    /// Name (or number from 1) of the `[ moleculetype ]` to convert; by default the only one, or the one
+    /// with as many atoms as the mol2.
+    pub molecule: Option<String>,
+}
+
+impl Default for ResidueOptions {
+    fn default() -> Self {
+        ResidueOptions { naming: Naming::Global, kind: Kind::Protein, ff: "amber".to_string(), prev: None, next: None, links: vec![],
+            charge_fix: ChargeFix::None, net_charge: None, charge_groups: ChargeGroups::Keep,
+            target_defaults: None, target_atomtypes: vec![], type_prefix: "gaff_".to_string(),
+            bonded_types: false, target_bonded: Ffbonded::default(),
+            junction_params: false, neighbour_types: vec![], neighbour_map: vec![], preprocessor: Preprocessor::default(),
+            molecule: None }
+    }
+}
+
+impl ResidueOptions {
+    /// Atom ids of the previous residue, empty if there is none.
+    pub fn prev_atoms(&self) -> &[usize] {
+        self.prev.as_ref().map_or(&[], |n| &n.atoms)
+    }
+
+    /// Atom ids of the next residue, empty if there is none.
+    pub fn next_atoms(&self) -> &[usize] {
+        self.next.as_ref().map_or(&[], |n| &n.atoms)
+    }
+
+    /// Atom ids of all linked residues.
+    pub fn link_atoms(&self) -> Vec<usize> {
+        self.links.iter().flat_map(|l| l.atoms.iter().copied()).collect()
+    }
+
+    /// Whether atom `id` belongs to the previous, next or a linked residue rather than the current one.
+    pub fn is_outside(&self, id: usize) -> bool {
+        self.prev_atoms().contains(&id) || self.next_atoms().contains(&id) || self.links.iter().any(|l| l.atoms.contains(&id))
+    }
+
+    /// Checks that all atom ids of the neighbours and links exist in a molecule of `natoms` atoms.
+    pub fn check(&self, natoms: usize) -> Result<()> {
+        for (side, n) in [("previous", &self.prev), ("next", &self.next)] {
+            if let Some(n) = n {
+                let ids = n.atoms.iter().chain([&n.con_atom, &n.adj_atom]);
+                if let Some(id) = ids.into_iter().find(|&&id| id == 0 || id > natoms) {
+                    return Err(Error::Invalid(format!("atom {} of the {} residue is out of range, the molecule has {} atoms",
+                        id, side, natoms)));
+                }
+                if n.atoms.contains(&n.adj_atom) {
+                    return Err(Error::Invalid(format!("connection atom {} of the current residue belongs to the {} residue",
+                        n.adj_atom, side)));
+                }
+            }
+        }
+        for (i, l) in self.links.iter().enumerate() {
+            let ids = l.atoms.iter().chain([&l.con_atom, &l.adj_atom]);
+            if let Some(id) = ids.into_iter().find(|&&id| id == 0 || id > natoms) {
+                return Err(Error::Invalid(format!("atom {} of link {} is out of range, the molecule has {} atoms",
+                    id, i + 1, natoms)));
+            }
+            if self.is_outside(l.adj_atom) {
+                return Err(Error::Invalid(format!("connection atom {} of link {} does not belong to the current residue",
+                    l.adj_atom, i + 1)));
+            }
+            if !l.atoms.contains(&l.con_atom) {
+                return Err(Error::Invalid(format!("connection atom {} of link {} is not one of its atoms", l.con_atom, i + 1)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The converted residue: its topology and the generated rtp and hdb entries.
+pub struct Residue {
+    pub topol: Topol,
+    pub rtp: RtpResidue,
+    pub hdb: HdbResidue,
+    /// Terminal variants of the rtp entry, e.g. the 5'/3' ends of a nucleotide.
+    pub rtp_variants: Vec<RtpResidue>,
+    /// hdb entries of the terminal variants.
+    pub hdb_variants: Vec<HdbResidue>,
+    /// `specbond.dat` entries of the extra links.
+    pub specbonds: Vec<SpecBond>,
+    /// Bonded types moved out of the rtp entries, with [`ResidueOptions::bonded_types`].
+    pub bonded: Option<Ffbonded>,
+}
+
+impl Residue {
+    /// The rtp entry followed by its terminal variants.
+    pub fn rtps(&self) -> Vec<RtpResidue> {
+        [vec![self.rtp.clone()], self.rtp_variants.clone()].concat()
+    }
+
+    /// The hdb entry followed by those of the terminal variants.
+    pub fn hdbs(&self) -> Vec<HdbResidue> {
+        [vec![self.hdb.clone()], self.hdb_variants.clone()].concat()
+    }
+
+    /// A complete rtp file holding only this residue and its variants.
+    pub fn rtp_file(&self, ff: &str) -> Rtp {
+        Rtp::new(ff, self.rtps())
+    }
+
+    /// The `ffnonbonded.itp` atomtypes of this residue, without those of the target force field;
+    /// [`Nonbonded::atp`] gives the `atomtypes.atp` lines.
+    pub fn nonbonded_file(&self) -> Result<Nonbonded> {
+        Nonbonded::new(self.topol.new_atomtypes().cloned(), self.topol.defaults.clone())
+    }
+
+    /// A complete hdb file holding only this residue and its variants.
+    pub fn hdb_file(&self) -> Hdb {
+        Hdb { residues: self.hdbs() }
+    }
+
+    /// A `specbond.dat` holding only the links of this residue.
+    pub fn specbond_file(&self) -> Specbond {
+        Specbond { bonds: self.specbonds.clone() }
+    }
+}
+
+/// Fixes the atom names of `mol2` in place: heavy atoms are renamed by `options.naming`, the
+/// connection atoms of the current residue get their rtp names and hydrogens are named after
+/// their heavy atom. Fails if the resulting names are not unique or too long.
+pub fn fix_atom_names(mol2: &mut MOL2, options: &ResidueOptions) -> Result<()> {
+    options.check(mol2.atoms.len())?;
+    // 按命名方案修改重原子名
+    let names = heavy_names(mol2, options)?;
+    for (a, name) in mol2.atoms.iter_mut().zip(names) {
+        if let Some(name) = name {
+            a.atom_name = name;
+        }
+    }
+    for n in options.prev.iter().chain(options.next.iter()) {
+        mol2.atoms[n.adj_atom - 1].atom_name = n.adj_name.to_owned();
+    }
+    let mol2_bak = mol2.clone();
+    // 修改邻接H命名, 重名或过长时换名并报告
+    let notes = hydrogen_names(mol2, options);
+    if !notes.is_empty() {
+        println!("Renamed hydrogens of {} heavy atoms:", notes.len());
+        for note in notes {
+            println!("  {}", note);
+        }
+    }
+    // 用户指定的H名优先
+    if let Naming::Map(table) = &options.naming {
+        for (a, old) in mol2.atoms.iter_mut().zip(&mol2_bak.atoms) {
+            if a.element == "H" {
+                if let Some(name) = table.get(a.atom_id, &old.atom_name) {
+                    a.atom_name = name.to_string();
+                }
+            }
+        }
+    }
+    check_names(mol2, options)
+}
+
+/// Reads the itp of an already renamed `mol2` and generates the rtp and hdb entries.
+pub fn convert(mol2: &MOL2, itp_file: &str, options: &ResidueOptions) -> Result<Residue> {
+    options.check(mol2.atoms.len())?;
+    // 读取itp, 更新H原子名, 选择性删除连接原子成键信息
+    let mut topol = read_topol(itp_file, mol2, options)?;
+    // 同名的 atomtype 只能有一组参数, 在写出任何文件前检查
+    Nonbonded::new(topol.atomtypes.iter().cloned(), None)?;
+    // 额外连接的键长优先取 itp 中的参数, 须在 rtp_residue 删去连接原子的键之前读取
+    let link_lengths: Vec<Option<f64>> = options.links.iter().map(|l| topol.bond_length(l.con_atom, l.adj_atom)).collect();
+    // 生成rtp, 特殊处理2号规则
+    let mut rtp = topol.rtp_residue(options);
+    let mut bonded = options.bonded_types.then(|| topol.bonded_types(&mut rtp, options));
+    // 跨残基的键项按目标力场查找参数; 聚合物起始和末端单元内含有两单元间的键型, 在其后查找
+    if options.junction_params && options.kind != Kind::Polymer {
+        topol.junction_params(&mut rtp, options, bonded.as_ref(), &neighbour_roles(mol2, options));
+    }
+    // 去掉相邻残基原子后修正电荷
+    fix_charge(&mut rtp, &topol.atom_ids(), options);
+    if options.charge_groups == ChargeGroups::Auto {
+        charge_groups(&mut rtp, &topol.atom_ids(), mol2);
+    }
+    // 生成hdb, 根据H类型
+    let hdb = mol2.hdb_residue(options);
+    // 额外连接写入 specbond.dat
+    let specbonds = options.links.iter().zip(link_lengths).map(|(l, length)| {
+        let length = length.unwrap_or_else(|| {
+            let (a, b) = (&mol2.atoms[l.con_atom - 1], &mol2.atoms[l.adj_atom - 1]);
+            let d = ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt() / 10.0;
+            println!("No bond length of {}-{} in the itp, using {:.4} nm from the mol2 coordinates", l.con_atom, l.adj_atom, d);
+            d
+        });
+        SpecBond::new(&rtp.name, &mol2.atoms[l.adj_atom - 1].atom_name, &l.con_resname, &l.con_name, length)
+    }).collect();
+    let (rtp_variants, hdb_variants) = match options.kind {
+        Kind::Nucleic => nucleic_variants(mol2, &rtp, &hdb, options),
+        Kind::Polymer => polymer_units(mol2, itp_file, &rtp.name, options, &mut bonded)?,
+        Kind::Protein => (vec![], vec![]),
+    };
+    if options.junction_params && options.kind == Kind::Polymer {
+        topol.junction_params(&mut rtp, options, bonded.as_ref(), &[]);
+    }
+    Ok(Residue { topol, rtp, hdb, rtp_variants, hdb_variants, specbonds, bonded })
+}
+
+// 读取 itp, 并按目标力场转换和改名 atomtypes
+fn read_topol(itp_file: &str, mol2: &MOL2, options: &ResidueOptions) -> Result<Topol> {
+    let mut topol = Topol::from(itp_file, mol2, options)?;
+    if let Some(target) = &options.target_defaults {
+        topol.match_defaults(target);
+    }
+    if !options.target_atomtypes.is_empty() {
+        topol.match_atomtypes(&options.target_atomtypes, &options.type_prefix);
+    }
+    Ok(topol)
+}
+
+// 聚合物的起始单元 (含前一侧封端, 无 - 连接) 与末端单元 (含后一侧封端, 无 + 连接),
+// 与重复单元共用同一套原子名和电荷; 两端封端原子的键型参数并入 bonded
+fn polymer_units(mol2: &MOL2, itp_file: &str, name: &str, options: &ResidueOptions, bonded: &mut Option<Ffbonded>)
+    -> Result<(Vec<RtpResidue>, Vec<HdbResidue>)> {
+    if options.prev.is_none() || options.next.is_none() {
+        return Err(Error::Invalid("the polymer mode needs both the previous and the next unit of the repeat unit".to_string()));
+    }
+    let mut rtps = vec![];
+    let mut hdbs = vec![];
+    let mut topols = vec![];
+    // 起始和末端单元的电荷各自取整
+    for (suffix, unit) in [("S", ResidueOptions { prev: None, net_charge: None, ..options.clone() }),
+        ("E", ResidueOptions { next: None, net_charge: None, ..options.clone() })] {
+        let mut unit = unit;
+        if let Some(bonded) = bonded.as_ref() {
+            unit.target_bonded.merge(bonded.clone());
+        }
+        let mut topol = read_topol(itp_file, mol2, &unit)?;
+        let mut rtp = topol.rtp_residue(&unit);
+        let mut hdb = mol2.hdb_residue(&unit);
+        rtp.name = format!("{}{}", name, suffix);
+        if let Some(bonded) = bonded.as_mut() {
+            bonded.merge(topol.bonded_types(&mut rtp, &unit));
+        }
+        fix_charge(&mut rtp, &topol.atom_ids(), &unit);
+        if unit.charge_groups == ChargeGroups::Auto {
+            charge_groups(&mut rtp, &topol.atom_ids(), mol2);
+        }
+        hdb.name = rtp.name.to_string();
+        rtps.push(rtp);
+        hdbs.push(hdb);
+        topols.push((topol, unit));
+    }
+    for ((topol, unit), rtp) in topols.iter().zip(rtps.iter_mut()) {
+        if unit.junction_params {
+            topol.junction_params(rtp, unit, bonded.as_ref(), &[]);
+        }
+    }
+    println!("Added polymer start and end units: {}", [rtps[0].name.as_str(), rtps[1].name.as_str()].join(", "));
+    Ok((rtps, hdbs))
+}
+
+// 核酸末端的默认 H 电荷, 取自 AMBER 的 H5T/H3T
+const H5T_CHARGE: f64 = 0.4422;
+const H3T_CHARGE: f64 = 0.4396;
+
+// 核酸的 5' 端 (去掉磷酸基, O5' 加 H5T), 3' 端 (O3' 加 H3T) 及两端均为末端的变体.
+// 磷酸基按带一个负电荷处理: 中间残基电荷取整为 q, 则 5' 端和两端变体为 q + 1, 3' 端变体为 q
+fn nucleic_variants(mol2: &MOL2, rtp: &RtpResidue, hdb: &HdbResidue, options: &ResidueOptions) -> (Vec<RtpResidue>, Vec<HdbResidue>) {
+    let name = |id: usize| mol2.atoms[id - 1].atom_name.to_string();
+    let element = |id: usize| mol2.atoms[id - 1].element.as_str();
+    let inside = |ids: Vec<usize>| -> Vec<usize> { ids.into_iter().filter(|&a| !options.is_outside(a)).collect() };
+    let charge: f64 = rtp.atoms.iter().filter(|a| a.excluded.is_none()).map(|a| a.charge).sum();
+    let charge = charge.round();
+    // 末端 H 的原子类型沿用分子中羟基 H 的类型
+    let h_type = mol2.atoms.iter()
+        .filter(|a| a.element == "H" && !options.is_outside(a.atom_id))
+        .filter(|a| get_adj_atoms_id(mol2, a.atom_id).iter().any(|&o| element(o) == "O"))
+        .find_map(|a| rtp.atoms.iter().find(|r| r.name == a.atom_name))
+        .map_or_else(|| {
+            println!("Note: no hydroxyl H in the molecule, H5T/H3T of the terminal variants use atom type HO");
+            "HO".to_string()
+        }, |r| r.atomtype.to_string());
+    let h_atom = |h: &str, charge: f64| RtpAtom { name: h.to_string(), atomtype: h_type.to_string(), charge, cgnr: 0, excluded: None };
+    // 参考原子: O 上的 H 以 O-C-C 定位
+    let refs = |o: usize, skip: usize| -> Option<Vec<String>> {
+        let c = inside(get_adj_heavy_id(mol2, o)).into_iter().find(|&c| c != skip)?;
+        let cc = inside(get_adj_heavy_id(mol2, c)).into_iter().find(|&x| x != o)?;
+        Some(vec![name(o), name(c), name(cc)])
+    };
+
+    // 5' 端: P 及只连 P 的 O
+    let five = options.prev.as_ref().and_then(|prev| {
+        let p = prev.adj_atom;
+        let mut removed = vec![name(p)];
+        let mut o5 = None;
+        for o in inside(get_adj_heavy_id(mol2, p)).into_iter().filter(|&o| element(o) == "O") {
+            match get_adj_atoms_id(mol2, o).len() {
+                1 => removed.push(name(o)),
+                _ => o5 = Some(o),
+            }
+        }
+        let o5 = o5?;
+        Some((removed, name(o5), refs(o5, p)?))
+    });
+    let three = options.next.as_ref().and_then(|next| {
+        let o3 = next.adj_atom;
+        Some((name(o3), refs(o3, next.con_atom)?))
+    });
+    if options.prev.is_some() && five.is_none() {
+        println!("Note: no 5'-phosphate P-O5' found, skipping the 5' terminal variant");
+    }
+    if options.next.is_some() && three.is_none() {
+        println!("Note: no O3' found, skipping the 3' terminal variant");
+    }
+
+    let mut rtps = vec![];
+    let mut hdbs = vec![];
+    let five_ter = |r: &RtpResidue, h: &HdbResidue, name: &str| {
+        let (removed, o5, refs) = five.as_ref().unwrap();
+        let removed = |n: &str| n.starts_with('-') || removed.iter().any(|r| r == n);
+        (r.terminal_variant(name, removed, o5, h_atom("H5T", H5T_CHARGE), charge + 1.0),
+         h.terminal_variant(name, removed, HDBItem::new(1, 2, "H5T".to_string(), refs.clone())))
+    };
+    let three_ter = |r: &RtpResidue, h: &HdbResidue, name: &str, target: f64| {
+        let (o3, refs) = three.as_ref().unwrap();
+        let removed = |n: &str| n.starts_with('+');
+        (r.terminal_variant(name, removed, o3, h_atom("H3T", H3T_CHARGE), target),
+         h.terminal_variant(name, removed, HDBItem::new(1, 2, "H3T".to_string(), refs.clone())))
+    };
+    if five.is_some() {
+        let (r, h) = five_ter(rtp, hdb, &format!("{}5", rtp.name));
+        rtps.push(r);
+        hdbs.push(h);
+    }
+    if three.is_some() {
+        let (r, h) = three_ter(rtp, hdb, &format!("{}3", rtp.name), charge);
+        rtps.push(r);
+        hdbs.push(h);
+    }
+    if five.is_some() && three.is_some() {
+        let name = format!("{}N", rtp.name);
+        let (r, h) = five_ter(rtp, hdb, &name);
+        let (r, h) = three_ter(&r, &h, &name, charge + 1.0);
+        rtps.push(r);
+        hdbs.push(h);
+    }
+    if !rtps.is_empty() {
+        let names: Vec<&str> = rtps.iter().map(|r| r.name.as_str()).collect();
+        println!("Added nucleic acid terminal variants: {}", names.join(", "));
+    }
+    (rtps, hdbs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_length_is_taken_from_the_itp() {
+        let options = ResidueOptions {
+            prev: Some(Neighbour { atoms: (1..=6).collect(), con_atom: 1, con_name: "-C".to_string(), adj_atom: 7, adj_name: "N".to_string() }),
+            links: vec![Link { atoms: (31..=36).collect(), con_atom: 31, con_resname: "NME".to_string(), con_name: "N".to_string(), adj_atom: 9 }],
+            ..Default::default()
+        };
+        let mut mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        fix_atom_names(&mut mol2, &options).unwrap();
+        let residue = convert(&mol2, "examples/TYS.itp", &options).unwrap();
+        assert_eq!(residue.specbonds.len(), 1);
+        assert_eq!(residue.specbonds[0].length, 0.137890);
+    }
+
+    #[test]
+    fn junction_terms_find_the_atomtypes_of_the_cap_atoms() {
+        // 目标力场的键型取整个封端分子的键型, 参考残基取 GAFF 类型
+        let whole = ResidueOptions { bonded_types: true, ..Default::default() };
+        let target = convert(&MOL2::from("examples/TYS.mol2").unwrap(), "examples/TYS.itp", &whole).unwrap().bonded.unwrap();
+        let neighbour_types = [("N", "n"), ("H", "hn"), ("CA", "c3"), ("HA", "h1"), ("C", "c"), ("O", "o")];
+        let options = ResidueOptions {
+            prev: Some(Neighbour { atoms: (1..=6).collect(), con_atom: 1, con_name: "-C".to_string(), adj_atom: 7, adj_name: "N".to_string() }),
+            next: Some(Neighbour { atoms: (31..=36).collect(), con_atom: 31, con_name: "+N".to_string(), adj_atom: 9, adj_name: "C".to_string() }),
+            target_bonded: target,
+            neighbour_types: neighbour_types.iter().map(|(n, t)| (n.to_string(), t.to_string())).collect(),
+            ..Default::default()
+        };
+        let mut mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        fix_atom_names(&mut mol2, &options).unwrap();
+        let roles = neighbour_roles(&mol2, &options);
+        let role = |r: &str| roles.iter().filter(|(_, n)| n == r).count();
+        assert_eq!((role("O"), role("CA"), role("HA"), role("H")), (1, 2, 6, 1));
+
+        let mut topol = read_topol("examples/TYS.itp", &mol2, &options).unwrap();
+        let mut rtp = topol.rtp_residue(&options);
+        let missing = topol.junction_params(&mut rtp.clone(), &options, None, &[]);
+        assert!(missing.iter().any(|m| m.contains("no atomtype")));
+        let missing = topol.junction_params(&mut rtp, &options, None, &roles);
+        assert!(missing.is_empty(), "{:?}", missing);
+        assert!(rtp.angles.iter().any(|a| a.atoms[0] == "-C" && !a.params.is_empty()));
+        assert!(rtp.dihedrals.iter().any(|d| d.atoms[0].starts_with("-O") && !d.params.is_empty()));
+    }
+
+    #[test]
+    fn atomtypes_of_the_target_are_not_written() {
+        let mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+        let own = convert(&mol2, "examples/TYS.itp", &ResidueOptions::default()).unwrap().topol.atomtypes;
+        let target = |name: &str, new: &str, sigma: f64| {
+            let at = own.iter().find(|at| at.name == name).unwrap();
+            TopolAtomtype { name: new.to_string(), sigma: at.sigma * sigma, ..at.clone() }
+        };
+        let options = ResidueOptions {
+            target_atomtypes: vec![target("c", "c", 1.0), target("o", "O", 1.0), target("c3", "c3", 1.03)],
+            ..Default::default()
+        };
+        let residue = convert(&mol2, "examples/TYS.itp", &options).unwrap();
+        let names: Vec<&str> = residue.topol.atomtypes.iter().map(|at| at.name.as_str()).collect();
+        assert!(names.contains(&"O") && names.contains(&"c") && names.contains(&"gaff_c3"));
+        let written: Vec<String> = residue.nonbonded_file().unwrap().atomtypes.into_iter().map(|at| at.name).collect();
+        assert!(!written.iter().any(|n| n == "c" || n == "O"), "{:?}", written);
+        assert!(written.iter().any(|n| n == "gaff_c3") && written.iter().any(|n| n == "ca"));
+        assert_eq!(written.len(), names.len() - 2);
+    }
+
+    #[test]
+    fn hydrogens_added_from_the_hdb_are_in_the_rtp() {
+        for naming in [Naming::Global, Naming::Amino] {
+            let options = ResidueOptions {
+                prev: Some(Neighbour { atoms: (1..=6).collect(), con_atom: 1, con_name: "-C".to_string(), adj_atom: 7, adj_name: "N".to_string() }),
+                next: Some(Neighbour { atoms: (31..=36).collect(), con_atom: 31, con_name: "+N".to_string(), adj_atom: 9, adj_name: "C".to_string() }),
+                naming,
+                ..Default::default()
+            };
+            let mut mol2 = MOL2::from("examples/TYS.mol2").unwrap();
+            fix_atom_names(&mut mol2, &options).unwrap();
+            let residue = convert(&mol2, "examples/TYS.itp", &options).unwrap();
+            let atoms: Vec<&str> = residue.rtp.atoms.iter().filter(|a| a.excluded.is_none()).map(|a| a.name.as_str()).collect();
+            let added: Vec<String> = residue.hdb.items.iter().flat_map(|it| it.h_names()).collect();
+            assert_eq!(added.len(), atoms.iter().filter(|a| a.starts_with('H')).count());
+            for h in &added {
+                assert!(atoms.contains(&h.as_str()), "{} ({}) not in the rtp", h, options.naming);
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt::{self, Display};
+use std::fs;
+
+use crate::error::{Error, Result};
+
+/// An `rtp` file: the `[ bondedtypes ]` header and one or more residue entries.
+#[derive(Clone, Debug)]
+pub struct Rtp {
+    /// Force field family, `amber` or `gromos`; decides `[ bondedtypes ]`.
+    pub ff: String,
+    pub residues: Vec<RtpResidue>,
+}
+
+/// One `[ RES ]` entry of an `rtp` file.
+#[derive(Clone, Debug)]
+pub struct RtpResidue {
+    pub name: String,
+    pub atoms: Vec<RtpAtom>,
+    pub bonds: Vec<RtpInteraction>,
+    pub angles: Vec<RtpInteraction>,
+    pub dihedrals: Vec<RtpInteraction>,
+    pub impropers: Vec<RtpInteraction>,
+    /// Integer net charge the charges were corrected to, if any (see [`crate::charge::fix_charge`]).
+    pub net_charge: Option<i32>,
+}
+
+/// A line of `[ atoms ]`.
+#[derive(Clone, Debug)]
+pub struct RtpAtom {
+    pub name: String,
+    pub atomtype: String,
+    pub charge: f64,
+    pub cgnr: i32,
+    /// Set for atoms of a neighbouring residue; they are written commented out with this note.
+    pub excluded: Option<String>,
+}
+
+/// A line of `[ bonds ]`, `[ angles ]`, `[ dihedrals ]` or `[ impropers ]`: atom names
+/// followed by the already formatted parameters (empty if taken from the force field).
+#[derive(Clone, Debug)]
+pub struct RtpInteraction {
+    pub atoms: Vec<String>,
+    pub params: String,
+}
+
+impl Rtp {
+    pub fn new(ff: &str, residues: Vec<RtpResidue>) -> Rtp {
+        Rtp { ff: ff.to_string(), residues }
+    }
+}
+
+/// The bonds, angles, dihedrals and impropers function types of the `[ bondedtypes ]` written for `ff`.
+pub fn bondedtypes(ff: &str) -> [i32; 4] {
+    match ff {
+        "gromos" => [2, 2, 1, 2],
+        _ => [1, 1, 9, 4],
+    }
+}
+
+/// The atom names and types of residue `name` in the rtp files of a force field directory (all its
+/// `*.rtp`, as pdb2gmx reads them), or `None` if none of them has it.
+pub fn read_atom_types(dir: &str, name: &str) -> Result<Option<Vec<(String, String)>>> {
+    let entries = fs::read_dir(dir).map_err(|e| Error::io(dir, e))?;
+    let mut files: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "rtp"))
+        .collect();
+    files.sort();
+    for file in files {
+        let content = fs::read_to_string(&file).map_err(|e| Error::io(&file.to_string_lossy(), e))?;
+        if let Some(atoms) = entry_atoms(&content, name) {
+            return Ok(Some(atoms));
+        }
+    }
+    Ok(None)
+}
+
+// 条目内的小节, 其余 [ XXX ] 为新的残基
+const ENTRY_SECTIONS: [&str; 7] = ["atoms", "bonds", "angles", "dihedrals", "impropers", "exclusions", "cmap"];
+
+fn entry_atoms(content: &str, name: &str) -> Option<Vec<(String, String)>> {
+    let mut atoms: Option<Vec<(String, String)>> = None;
+    let mut section = String::new();
+    for line in content.lines() {
+        let line = line.split(';').next().unwrap().trim();
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')).map(|h| h.trim()) {
+            if ENTRY_SECTIONS.contains(&header) {
+                section = header.to_string();
+            } else if atoms.is_some() {
+                break;
+            } else if header == name {
+                atoms = Some(vec![]);
+                section.clear();
+            }
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let (Some(atoms), "atoms", [atom, atomtype, ..]) = (atoms.as_mut(), section.as_str(), fields.as_slice()) {
+            atoms.push((atom.to_string(), atomtype.to_string()));
+        }
+    }
+    atoms
+}
+
+impl RtpResidue {
+    /// A terminal variant named `name`: atoms whose name satisfies `removed`, and every term involving
+    /// them, are dropped, and the hydrogen `h` is bonded to `anchor`. The charge of `anchor` takes up
+    /// the difference so that the variant sums to `target`.
+    pub fn terminal_variant(&self, name: &str, removed: impl Fn(&str) -> bool, anchor: &str, mut h: RtpAtom, target: f64) -> RtpResidue {
+        let mut atoms: Vec<RtpAtom> = self.atoms.iter().filter(|a| !removed(&a.name)).cloned().collect();
+        let pos = atoms.iter().position(|a| a.name == anchor).unwrap();
+        h.cgnr = atoms[pos].cgnr;
+        let h_name = h.name.to_string();
+        atoms.insert(pos + 1, h);
+        let total: f64 = atoms.iter().filter(|a| a.excluded.is_none()).map(|a| a.charge).sum();
+        atoms[pos].charge += target - total;
+        let keep = |items: &[RtpInteraction]| -> Vec<RtpInteraction> {
+            items.iter().filter(|i| !i.atoms.iter().any(|a| removed(a))).cloned().collect()
+        };
+        let mut bonds = keep(&self.bonds);
+        bonds.push(RtpInteraction { atoms: vec![anchor.to_string(), h_name], params: String::new() });
+        RtpResidue {
+            name: name.to_string(), atoms, bonds, angles: keep(&self.angles), dihedrals: keep(&self.dihedrals),
+            impropers: keep(&self.impropers), net_charge: Some(target.round() as i32)
+        }
+    }
+}
+
+impl Display for Rtp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.residues.iter().map(|r| r.name.as_str()).collect();
+        writeln!(f, "; Created by gen-rtp (https://github.com/supernova4869/gen-rtp)")?;
+        writeln!(f, "; converted from top of {}", names.join(", "))?;
+        for r in self.residues.iter().filter(|r| r.net_charge.is_some()) {
+            writeln!(f, "; net charge of {}: {}", r.name, r.net_charge.unwrap())?;
+        }
+        writeln!(f)?;
+
+        // 以下正式rtp
+        match self.ff.as_str() {
+            "gromos" => {
+                writeln!(f, "[ bondedtypes ]\n; bonds  angles  dihedrals  impropers")?;
+                writeln!(f, "    2       2          1          2\n")?;
+            },
+            _ => {
+                writeln!(f, "[ bondedtypes ]")?;
+                writeln!(f, "; bonds  angles  dihedrals  impropers all_dihedrals nrexcl HH14 RemoveDih")?;
+                writeln!(f, "     1       1          9          4        1         3      1     0\n")?;
+            }
+        }
+        for r in &self.residues {
+            write!(f, "{}", r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for RtpResidue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 残基名
+        writeln!(f, "[ {} ]", self.name)?;
+        // [ atoms ]字段：记录残基中每个原子的名称、类型和电荷、电荷组
+        writeln!(f, " [ atoms ]")?;
+        for a in &self.atoms {
+            writeln!(f, "{}", a)?;
+        }
+        // [ bonds ], [ angles ], [ dihedrals ], [ impropers ]
+        for (title, items) in [(" [ bonds ]", &self.bonds), (" [ angles ]", &self.angles),
+                               (" [ dihedrals ]", &self.dihedrals), (" [ impropers ]", &self.impropers)] {
+            writeln!(f, "{}", title)?;
+            for i in items {
+                writeln!(f, "{}", i)?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+impl Display for RtpAtom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = format!("{:>8} {:>5}{:12.6}{:5}", self.name, self.atomtype, self.charge, self.cgnr);
+        match &self.excluded {
+            Some(note) => write!(f, "; {}\t; {}", line, note),
+            None => write!(f, "{}", line),
+        }
+    }
+}
+
+impl Display for RtpInteraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for a in &self.atoms {
+            write!(f, "{:>7}", a)?;
+        }
+        write!(f, "{}", self.params)
+    }
+}
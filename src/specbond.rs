@@ -0,0 +1,51 @@
+use std::fmt::{self, Display};
+
+/// A `specbond.dat` file: special bonds that pdb2gmx makes between residues.
+#[derive(Clone, Debug, Default)]
+pub struct Specbond {
+    pub bonds: Vec<SpecBond>,
+}
+
+/// One line of `specbond.dat`: the two residues and atoms, the number of bonds each atom makes,
+/// the reference bond length (nm) and the residue names after linking.
+#[derive(Clone, Debug)]
+pub struct SpecBond {
+    pub res1: String,
+    pub atom1: String,
+    pub nbonds1: i32,
+    pub res2: String,
+    pub atom2: String,
+    pub nbonds2: i32,
+    pub length: f64,
+    pub newres1: String,
+    pub newres2: String,
+}
+
+impl SpecBond {
+    /// A single bond between `atom1` of `res1` and `atom2` of `res2`, keeping the residue names.
+    pub fn new(res1: &str, atom1: &str, res2: &str, atom2: &str, length: f64) -> SpecBond {
+        SpecBond {
+            res1: res1.to_string(), atom1: atom1.to_string(), nbonds1: 1,
+            res2: res2.to_string(), atom2: atom2.to_string(), nbonds2: 1,
+            length, newres1: res1.to_string(), newres2: res2.to_string(),
+        }
+    }
+}
+
+impl Display for Specbond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 首行为条目数, 追加到已有 specbond.dat 时需同时修改
+        writeln!(f, "{}", self.bonds.len())?;
+        for b in &self.bonds {
+            writeln!(f, "{}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for SpecBond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<6}{:<6}{:<3}{:<6}{:<6}{:<3}{:<8.4}{:<6}{}",
+            self.res1, self.atom1, self.nbonds1, self.res2, self.atom2, self.nbonds2, self.length, self.newres1, self.newres2)
+    }
+}
@@ -4,6 +4,8 @@ use regex::Regex;
 use std::str::FromStr;
 use std::fmt::Debug;
 
+use crate::error::Error;
+
 pub fn read_file() -> String {
     let inp = get_input("".to_string());
     let inp: String = match inp.starts_with("\"") {
@@ -24,87 +26,86 @@ pub fn get_parent_path(fname: &str) -> PathBuf {
     Path::new(file.parent().unwrap()).to_owned()
 }
 
-pub fn atrange2atlist(atom_selection_str: &str) -> Vec<usize> {
+pub fn atrange2atlist(atom_selection_str: &str) -> Result<Vec<usize>, Error> {
     let mut selection_range: Vec<usize> = vec![];
     if atom_selection_str.trim().is_empty() {
-        return selection_range;
+        return Ok(selection_range);
     }
+    let invalid = |s: &str| Error::Invalid(format!("invalid atom selection `{}` in `{}`", s, atom_selection_str));
     let atom_selection_str = atom_selection_str.replace(" ", "");
     let sub_selections: Vec<&str> = atom_selection_str.split(',').collect();
     for s in sub_selections {
         if s.contains('-') {
-            let r: Vec<&str> = s.split('-').collect();
-            let l: usize = r[0].parse().expect("Range lower bound not int");
-            let u: usize = r[1].parse().expect("Range upper bound not int");
+            let (l, u) = s.split_once('-').unwrap();
+            let l: usize = l.parse().map_err(|_| invalid(s))?;
+            let u: usize = u.parse().map_err(|_| invalid(s))?;
+            if l > u {
+                return Err(invalid(s));
+            }
             selection_range.append(&mut (l..=u).collect());
         } else {
-            let s: usize = s.parse().expect("Index not int");
-            selection_range.push(s);
+            selection_range.push(s.parse().map_err(|_| invalid(s))?);
         }
     }
-    return selection_range
+    Ok(selection_range)
 }
 
+// 输入无法解析时重新输入, 输入结束时使用默认值
 pub fn get_input<T: FromStr>(default: T) -> T where <T as FromStr>::Err: Debug {
-    let mut inp: String = String::new();
-    io::stdin().read_line(&mut inp).expect("Failed to read line");
-    let inp = inp.trim();
-    match inp.is_empty() {
-        true => default,
-        false => inp.parse().expect("Failed to parse input")
+    loop {
+        let mut inp: String = String::new();
+        if io::stdin().read_line(&mut inp).unwrap_or(0) == 0 {
+            return default;
+        }
+        let inp = inp.trim();
+        if inp.is_empty() {
+            return default;
+        }
+        match inp.parse() {
+            Ok(v) => return v,
+            Err(_) => println!("Invalid input `{}`, please input again:", inp),
+        }
     }
 }
 
-pub fn get_exclude_atoms() -> (Vec<usize>, Vec<usize>, 
-                               Option<usize>, Option<usize>, 
-                               Option<String>, Option<String>,
-                               Option<usize>, Option<usize>, 
-                               Option<String>, Option<String>) {
-    println!("Input atoms id of the previous residue, e.g., 1-3, 5 (leave blank if it is the first residue): ");
-    let prev_atoms = get_input("".to_string());
-    let prev_atoms = atrange2atlist(prev_atoms.as_str());
-    let (prev_con_atom, prev_con_atom_name, prev_adj_atom, prev_adj_atom_name) = match prev_atoms.is_empty() {
-        false => {
-            println!("Connection atom id of the previous residue (default: {}): ", prev_atoms[0]);
-            let prev_con_atom = get_input(prev_atoms[0]);
-            println!("Rename connection atom name to (default: -C): ");
-            println!("(IMPORTANT: please ensure the new atom name is correct)");
-            let prev_atom_name = get_input("-C".to_string());
-            println!("Connection atom id of the current residue to previous (default: {}): ", prev_atoms[prev_atoms.len() - 1] + 1);
-            let prev_adj_atom = get_input(prev_atoms[prev_atoms.len() - 1] + 1);
-            println!("Rename connection atom name to (default: N): ");
-            println!("(IMPORTANT: please ensure the new atom name is correct)");
-            let prev_adj_atom_name = get_input("N".to_string());
-            (Some(prev_con_atom), Some(prev_atom_name), Some(prev_adj_atom), Some(prev_adj_atom_name))
-        },
-        true => {
-            (None, None, None, None)
+// 命令行已给出则直接使用, 非交互模式使用默认值, 否则提示输入
+pub fn ask<T: FromStr>(prompt: &str, preset: Option<T>, default: T, interactive: bool) -> T where <T as FromStr>::Err: Debug {
+    match (preset, interactive) {
+        (Some(v), _) => v,
+        (None, false) => default,
+        (None, true) => {
+            println!("{}", prompt);
+            get_input(default)
         }
-    };
-    println!("Input atoms id of the next residue, e.g., 1-3, 5 (leave blank if it is the last residue): ");
-    let next_atoms = get_input("".to_string());
-    let next_atoms = atrange2atlist(next_atoms.as_str());
-    let (next_con_atom, next_atom_name, next_adj_atom, next_adj_atom_name) = match next_atoms.is_empty() {
-        false => {
-            println!("Connection atom id of the next residue (default: {}): ", next_atoms[0]);
-            let next_con_atom = get_input(next_atoms[0]);
-            println!("Rename connection atom to (default: +N): ");
-            println!("(IMPORTANT: please ensure the new atom name is correct)");
-            let next_con_atom_name = get_input("+N".to_string());
-            println!("Connection atom id of the current residue to next (default: {}): ", next_atoms[next_atoms.len() - 1] + 1);
-            let next_adj_atom = get_input(next_atoms[0] - 1);
-            println!("Rename connection atom to (default: C): ");
-            println!("(IMPORTANT: please ensure the new atom name is correct)");
-            let next_adj_atom_name = get_input("C".to_string());
-            (Some(next_con_atom), Some(next_con_atom_name), Some(next_adj_atom), Some(next_adj_atom_name))
-        },
-        true => {
-            (None, None, None, None)
+    }
+}
+
+// atrange2atlist 的逆操作, 如 [1, 2, 3, 5] -> "1-3,5"
+pub fn atlist2atrange(atoms: &[usize]) -> String {
+    let mut ranges: Vec<String> = vec![];
+    let mut i = 0;
+    while i < atoms.len() {
+        let mut j = i;
+        while j + 1 < atoms.len() && atoms[j + 1] == atoms[j] + 1 {
+            j += 1;
         }
-    };
-    (prev_atoms, next_atoms, 
-        prev_con_atom, next_con_atom, 
-        prev_con_atom_name, next_atom_name, 
-        prev_adj_atom, next_adj_atom,
-        prev_adj_atom_name, next_adj_atom_name)
-}
\ No newline at end of file
+        ranges.push(match i == j {
+            true => atoms[i].to_string(),
+            false => format!("{}-{}", atoms[i], atoms[j]),
+        });
+        i = j + 1;
+    }
+    ranges.join(",")
+}
+
+// 元素符号, 按原子序数排列
+const ELEMENTS: [&str; 54] = [
+    "H", "He", "Li", "Be", "B", "C", "N", "O", "F", "Ne", "Na", "Mg", "Al", "Si", "P", "S", "Cl", "Ar",
+    "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn", "Fe", "Co", "Ni", "Cu", "Zn", "Ga", "Ge", "As", "Se", "Br", "Kr",
+    "Rb", "Sr", "Y", "Zr", "Nb", "Mo", "Tc", "Ru", "Rh", "Pd", "Ag", "Cd", "In", "Sn", "Sb", "Te", "I", "Xe",
+];
+
+/// Atomic number of an element symbol (case-insensitive), up to Xe.
+pub fn atomic_number(element: &str) -> Option<i32> {
+    ELEMENTS.iter().position(|e| e.eq_ignore_ascii_case(element)).map(|i| i as i32 + 1)
+}